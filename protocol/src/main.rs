@@ -17,9 +17,15 @@
 */
 
 use spifyrfb_protocol::info;
-use spifyrfb_protocol::server::{RFBAuthentication, VNCAuth, ipc_client, CreateOptions};
+use spifyrfb_protocol::server::{RFBAuthentication, VNCAuth, PlainAuth, ipc_client, CreateOptions};
+use spifyrfb_protocol::server::sessions::{ExclusivePolicy, SessionPolicy, SharedMode};
+use spifyrfb_protocol::server::credentials::HtpasswdBackend;
 use std::env;
 use std::error::Error;
+use std::path::PathBuf;
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
@@ -30,6 +36,10 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let mut websocket_proxy: Option<(String, bool)> = Option::None;
     let mut daemon_ip: Option<String> = Option::None;
     let mut authentication: Option<RFBAuthentication> = Option::None;
+    let mut bell_interval: Option<Duration> = Option::None;
+    let mut vencrypt = false;
+    let mut rsa_aes = false;
+    let mut session_policy: SessionPolicy = Default::default();
 
     for arg in env::args_os() {
         if arg.to_string_lossy().starts_with("--ip=") {
@@ -43,6 +53,61 @@ async fn main() -> Result<(), Box<dyn Error>> {
             authentication = Option::Some(RFBAuthentication::Vnc(VNCAuth {
                 security_key: security_key.as_bytes()[0..8].try_into().unwrap()
             }));
+        } else if arg.to_string_lossy().starts_with("--plain-auth=") {
+            /* htpasswd (next to config.json) or htpasswd:PATH, offered through VeNCrypt or RSA-AES */
+            let backend = String::from(arg.to_string_lossy().replace("--plain-auth=", "").trim());
+            let htpasswd_path = match backend.split_once(':') {
+                Some(("htpasswd", path)) => Option::Some(PathBuf::from(path)),
+                None if backend == "htpasswd" => Option::Some(HtpasswdBackend::default_path()),
+                _ => Option::None
+            };
+
+            match htpasswd_path {
+                Some(htpasswd_path) => {
+                    authentication = Option::Some(RFBAuthentication::Plain(PlainAuth {
+                        backend: Arc::new(HtpasswdBackend::new(htpasswd_path))
+                    }));
+                },
+                None => {
+                    /* Never start without the Authentication that was asked for */
+                    eprintln!("Unknown Plain Authentication Backend: '{}', use htpasswd or htpasswd:PATH", backend);
+                    process::exit(1);
+                }
+            }
+        } else if arg.to_string_lossy() == "--vencrypt" {
+            /* X509None, X509Vnc and X509Plain only, rustls can't do the anonymous TLS* sub-types */
+            vencrypt = true;
+        } else if arg.to_string_lossy() == "--rsa-aes" {
+            rsa_aes = true;
+        } else if arg.to_string_lossy().starts_with("--bell-interval=") {
+            let interval_ms = String::from(arg.to_string_lossy().replace("--bell-interval=", "").trim());
+            match interval_ms.parse::<u64>() {
+                Ok(interval_ms) => bell_interval = Option::Some(Duration::from_millis(interval_ms)),
+                Err(_) => {
+                    eprintln!("Invalid Bell Interval: '{}', use a number of milliseconds", interval_ms);
+                    process::exit(1);
+                }
+            }
+        } else if arg.to_string_lossy().starts_with("--shared=") {
+            /* client (honor the shared-flag), always or never */
+            let shared_mode = String::from(arg.to_string_lossy().replace("--shared=", "").trim());
+            match SharedMode::from_name(&shared_mode) {
+                Some(shared_mode) => session_policy.shared_mode = shared_mode,
+                None => {
+                    eprintln!("Unknown Shared Mode: '{}', use client, always or never", shared_mode);
+                    process::exit(1);
+                }
+            }
+        } else if arg.to_string_lossy().starts_with("--exclusive=") {
+            /* disconnect (other Viewers) or refuse (the exclusive Client) */
+            let exclusive_policy = String::from(arg.to_string_lossy().replace("--exclusive=", "").trim());
+            match ExclusivePolicy::from_name(&exclusive_policy) {
+                Some(exclusive_policy) => session_policy.exclusive_policy = exclusive_policy,
+                None => {
+                    eprintln!("Unknown Exclusive Policy: '{}', use disconnect or refuse", exclusive_policy);
+                    process::exit(1);
+                }
+            }
         } else if arg.to_string_lossy().starts_with("--spify-daemon=") {
             let ip = String::from(arg.to_string_lossy().replace("--spify-daemon=", ""));
             daemon_ip = Option::Some(ip.clone());
@@ -57,7 +122,11 @@ async fn main() -> Result<(), Box<dyn Error>> {
         ip_address: launch_ip.unwrap(),
         ws_proxy: websocket_proxy,
         auth: authentication,
-        spify_daemon: daemon_ip.is_some()
+        spify_daemon: daemon_ip.is_some(),
+        vencrypt,
+        rsa_aes,
+        bell_interval,
+        session_policy
     };
 
     /* CREATE PROTOCOL SERVER WITH LAUNCH IP */
@@ -0,0 +1,386 @@
+/*
+    SpifyRFB - Modern RFB Server implementation using Rust
+    Copyright (C) 2023  Atheesh Thirumalairajan
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::collections::HashMap;
+use tokio::io::{AsyncWriteExt, WriteHalf};
+use super::{ServerToClientMessage, encoding_zlib, stream::RFBStream};
+
+/* Characters outside Latin-1 can't be sent as Cut Text */
+const LATIN1_REPLACEMENT: u8 = b'?';
+
+/* Largest Clipboard accepted, and announced as Provide-able without a Request */
+pub(crate) const MAX_CLIPBOARD_SIZE: u32 = 20 * 1024 * 1024;
+
+/* Extended Clipboard flags: Formats in the low bits, Actions in the high byte */
+pub struct ClipboardFormat;
+impl ClipboardFormat {
+    pub const TEXT: u32 = 1;
+    pub const RTF: u32 = 1 << 1;
+    pub const HTML: u32 = 1 << 2;
+
+    pub const SUPPORTED: u32 = ClipboardFormat::TEXT | ClipboardFormat::RTF | ClipboardFormat::HTML;
+    const ALL: [u32; 3] = [ClipboardFormat::TEXT, ClipboardFormat::RTF, ClipboardFormat::HTML];
+}
+
+pub struct ClipboardAction;
+impl ClipboardAction {
+    pub const CAPS: u32 = 1 << 24;
+    pub const REQUEST: u32 = 1 << 25;
+    pub const PEEK: u32 = 1 << 26;
+    pub const NOTIFY: u32 = 1 << 27;
+    pub const PROVIDE: u32 = 1 << 28;
+
+    const SUPPORTED: u32 = ClipboardAction::REQUEST | ClipboardAction::PEEK | ClipboardAction::NOTIFY | ClipboardAction::PROVIDE;
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClipboardData {
+    pub(crate) text: Option<String>,
+    pub(crate) rtf: Option<String>,
+    pub(crate) html: Option<String>,
+}
+
+impl ClipboardData {
+    pub fn from_text(text: String) -> ClipboardData {
+        ClipboardData { text: Option::Some(text), ..Default::default() }
+    }
+
+    fn format(&self, clipboard_format: u32) -> Option<&String> {
+        match clipboard_format {
+            ClipboardFormat::TEXT => self.text.as_ref(),
+            ClipboardFormat::RTF => self.rtf.as_ref(),
+            ClipboardFormat::HTML => self.html.as_ref(),
+            _ => Option::None
+        }
+    }
+
+    fn set_format(&mut self, clipboard_format: u32, value: String) {
+        match clipboard_format {
+            ClipboardFormat::TEXT => self.text = Option::Some(value),
+            ClipboardFormat::RTF => self.rtf = Option::Some(value),
+            ClipboardFormat::HTML => self.html = Option::Some(value),
+            _ => {}
+        }
+    }
+
+    pub fn formats(&self) -> u32 {
+        ClipboardFormat::ALL
+            .into_iter()
+            .filter(|clipboard_format| self.format(*clipboard_format).is_some())
+            .fold(0, |formats, clipboard_format| formats | clipboard_format)
+    }
+}
+
+/* What the other side told us in its Caps: Actions, Formats and their largest unrequested Size */
+#[derive(Debug, Clone)]
+pub struct ClipboardCaps {
+    pub(crate) flags: u32,
+    pub(crate) max_sizes: HashMap<u32, u32>,
+}
+
+impl Default for ClipboardCaps {
+    fn default() -> Self {
+        /* Assumed until the Client sends its own Caps */
+        ClipboardCaps {
+            flags: ClipboardFormat::TEXT | ClipboardAction::SUPPORTED,
+            max_sizes: HashMap::from([(ClipboardFormat::TEXT, MAX_CLIPBOARD_SIZE)]),
+        }
+    }
+}
+
+pub enum ExtendedClipboardMessage {
+    Caps(ClipboardCaps),
+    Request(u32),
+    Peek,
+    Notify(u32),
+    Provide(ClipboardData),
+}
+
+pub fn latin1_to_string(latin1_text: &[u8]) -> String {
+    /* Latin-1 bytes are the first 256 Unicode code points */
+    latin1_text.iter().map(|byte| *byte as char).collect()
+}
+
+pub fn string_to_latin1(text: &str) -> Vec<u8> {
+    text.chars()
+        .map(|character| u8::try_from(character as u32).unwrap_or(LATIN1_REPLACEMENT))
+        .collect()
+}
+
+pub fn accepts_cut_text_length(text_length: i32, extended_clipboard: bool) -> bool {
+    /* Negative Lengths carry Extended Clipboard Messages, only valid once it was negotiated */
+    (text_length >= 0 || extended_clipboard) && text_length.unsigned_abs() <= MAX_CLIPBOARD_SIZE
+}
+
+pub fn parse_client_cut_text(buffer: &[u8]) -> Option<String> {
+    /* padding (U8 x 3), length (U32), text */
+    let text_length = u32::from_be_bytes(buffer.get(3..7)?.try_into().unwrap()) as usize;
+    let cut_text = buffer.get(7..)?.get(..text_length)?;
+    Option::Some(latin1_to_string(cut_text))
+}
+
+pub fn parse_extended_cut_text(payload: &[u8]) -> Option<ExtendedClipboardMessage> {
+    /* flags (U32), then data depending on the Action */
+    let flags = u32::from_be_bytes(payload.get(0..4)?.try_into().unwrap());
+    let formats = flags & 0xFFFF;
+    let action_data = &payload[4..];
+
+    if flags & ClipboardAction::CAPS != 0 {
+        /* One Size per Format bit, lowest bit first */
+        let mut max_sizes: HashMap<u32, u32> = HashMap::new();
+        let format_bits = (0..16).map(|bit| 1_u32 << bit).filter(|format_bit| formats & format_bit != 0);
+        for (format_bit, size) in format_bits.zip(action_data.chunks_exact(4)) {
+            max_sizes.insert(format_bit, u32::from_be_bytes(size.try_into().unwrap()));
+        }
+
+        Option::Some(ExtendedClipboardMessage::Caps(ClipboardCaps { flags, max_sizes }))
+    } else if flags & ClipboardAction::REQUEST != 0 {
+        Option::Some(ExtendedClipboardMessage::Request(formats))
+    } else if flags & ClipboardAction::PEEK != 0 {
+        Option::Some(ExtendedClipboardMessage::Peek)
+    } else if flags & ClipboardAction::NOTIFY != 0 {
+        Option::Some(ExtendedClipboardMessage::Notify(formats))
+    } else if flags & ClipboardAction::PROVIDE != 0 {
+        /* zlib Stream of size (U32) and data for each Format, lowest bit first */
+        let provided_data = encoding_zlib::decompress_once(action_data, MAX_CLIPBOARD_SIZE as usize * ClipboardFormat::ALL.len())?;
+        let mut clipboard_data: ClipboardData = Default::default();
+        let mut offset = 0;
+        for format_bit in (0..16).map(|bit| 1_u32 << bit).filter(|format_bit| formats & format_bit != 0) {
+            let size = u32::from_be_bytes(provided_data.get(offset..(offset + 4))?.try_into().unwrap()) as usize;
+            let value = provided_data.get((offset + 4)..(offset + 4 + size))?;
+            offset += 4 + size;
+
+            /* Text Formats are NUL terminated, with CRLF line endings */
+            let value = String::from_utf8_lossy(value).trim_end_matches('\0').to_string();
+            if format_bit == ClipboardFormat::TEXT {
+                clipboard_data.set_format(format_bit, value.replace("\r\n", "\n"));
+            } else {
+                clipboard_data.set_format(format_bit, value);
+            }
+        }
+
+        Option::Some(ExtendedClipboardMessage::Provide(clipboard_data))
+    } else {
+        Option::None
+    }
+}
+
+fn provided_value(clipboard_format: u32, value: &str) -> Vec<u8> {
+    let mut provided_value = if clipboard_format == ClipboardFormat::TEXT {
+        value.replace("\r\n", "\n").replace('\n', "\r\n").into_bytes()
+    } else {
+        value.as_bytes().to_vec()
+    };
+
+    provided_value.push(0);
+    provided_value
+}
+
+pub fn fits_unsolicited(clipboard_data: &ClipboardData, client_caps: &ClipboardCaps) -> bool {
+    /* Anything larger than the Client allows is only Notified */
+    ClipboardFormat::ALL.into_iter().all(|clipboard_format| {
+        match (clipboard_data.format(clipboard_format), client_caps.flags & clipboard_format != 0) {
+            (Some(value), true) => value.len() < *client_caps.max_sizes.get(&clipboard_format).unwrap_or(&0) as usize,
+            _ => true
+        }
+    })
+}
+
+async fn write_extended_cut_text(client_tx: &mut WriteHalf<RFBStream>, flags: u32, action_data: &[u8]) {
+    /* message-type, padding (U8 x 3), negative length (S32), flags (U32), data */
+    let payload_length = (4 + action_data.len()) as i32;
+    let mut cut_text_message: Vec<u8> = vec![ServerToClientMessage::SERVER_CUT_TEXT, 0, 0, 0];
+    cut_text_message.extend_from_slice(&(-payload_length).to_be_bytes());
+    cut_text_message.extend_from_slice(&flags.to_be_bytes());
+    cut_text_message.extend_from_slice(action_data);
+    client_tx.write_all(&cut_text_message).await.unwrap_or(());
+}
+
+pub async fn write_caps(client_tx: &mut WriteHalf<RFBStream>) {
+    let mut max_sizes: Vec<u8> = vec![];
+    for _clipboard_format in ClipboardFormat::ALL {
+        max_sizes.extend_from_slice(&MAX_CLIPBOARD_SIZE.to_be_bytes());
+    }
+
+    write_extended_cut_text(client_tx, ClipboardAction::CAPS | ClipboardAction::SUPPORTED | ClipboardFormat::SUPPORTED, &max_sizes).await;
+}
+
+pub async fn write_request(client_tx: &mut WriteHalf<RFBStream>, formats: u32) {
+    write_extended_cut_text(client_tx, ClipboardAction::REQUEST | formats, &[]).await;
+}
+
+pub async fn write_notify(client_tx: &mut WriteHalf<RFBStream>, formats: u32) {
+    write_extended_cut_text(client_tx, ClipboardAction::NOTIFY | formats, &[]).await;
+}
+
+pub async fn write_provide(client_tx: &mut WriteHalf<RFBStream>, clipboard_data: &ClipboardData, formats: u32) {
+    let mut provided_formats: u32 = 0;
+    let mut provided_data: Vec<u8> = vec![];
+    for clipboard_format in ClipboardFormat::ALL {
+        if let Some(value) = clipboard_data.format(clipboard_format).filter(|_| formats & clipboard_format != 0) {
+            let value = provided_value(clipboard_format, value);
+            provided_data.extend_from_slice(&(value.len() as u32).to_be_bytes());
+            provided_data.extend(value);
+            provided_formats |= clipboard_format;
+        }
+    }
+
+    if let Some(compressed_data) = encoding_zlib::compress_once(&provided_data, encoding_zlib::ZLIB_COMPRESS_LEVEL) {
+        write_extended_cut_text(client_tx, ClipboardAction::PROVIDE | provided_formats, &compressed_data).await;
+    }
+}
+
+pub async fn write_server_cut_text(client_tx: &mut WriteHalf<RFBStream>, text: &str) {
+    /* message-type, padding (U8 x 3), length (U32), text */
+    let latin1_text = string_to_latin1(text);
+    let mut cut_text_message: Vec<u8> = vec![ServerToClientMessage::SERVER_CUT_TEXT, 0, 0, 0];
+    cut_text_message.extend_from_slice(&(latin1_text.len() as u32).to_be_bytes());
+    cut_text_message.extend(latin1_text);
+    client_tx.write_all(&cut_text_message).await.unwrap_or(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cut_text_buffer(text_length: u32, text: &[u8]) -> Vec<u8> {
+        let mut buffer: Vec<u8> = vec![0, 0, 0];
+        buffer.extend_from_slice(&text_length.to_be_bytes());
+        buffer.extend_from_slice(text);
+        buffer
+    }
+
+    fn extended_payload(flags: u32, action_data: &[u8]) -> Vec<u8> {
+        let mut payload = flags.to_be_bytes().to_vec();
+        payload.extend_from_slice(action_data);
+        payload
+    }
+
+    #[test]
+    fn parses_latin1_cut_text() {
+        let buffer = cut_text_buffer(5, &[b'c', b'a', b'f', 0xE9, b'!']);
+        assert_eq!(parse_client_cut_text(&buffer), Option::Some("café!".to_string()));
+    }
+
+    #[test]
+    fn cut_text_shorter_than_its_length_is_refused() {
+        assert_eq!(parse_client_cut_text(&cut_text_buffer(10, b"short")), Option::None);
+        assert_eq!(parse_client_cut_text(&cut_text_buffer(u32::MAX, b"")), Option::None);
+        assert_eq!(parse_client_cut_text(&[0, 0, 0, 0]), Option::None);
+    }
+
+    #[test]
+    fn cut_text_length_limits() {
+        assert!(accepts_cut_text_length(0, false));
+        assert!(accepts_cut_text_length(MAX_CLIPBOARD_SIZE as i32, false));
+        assert!(!accepts_cut_text_length(MAX_CLIPBOARD_SIZE as i32 + 1, false));
+
+        /* Negative only with Extended Clipboard */
+        assert!(!accepts_cut_text_length(-8, false));
+        assert!(accepts_cut_text_length(-8, true));
+        assert!(!accepts_cut_text_length(-(MAX_CLIPBOARD_SIZE as i32) - 1, true));
+        assert!(!accepts_cut_text_length(i32::MIN, true));
+    }
+
+    #[test]
+    fn latin1_round_trip() {
+        let text = "Grüße, naïve café";
+        assert_eq!(latin1_to_string(&string_to_latin1(text)), text);
+        assert_eq!(string_to_latin1("€1"), b"?1".to_vec());
+    }
+
+    #[test]
+    fn parses_extended_caps() {
+        let mut sizes: Vec<u8> = vec![];
+        sizes.extend_from_slice(&1024_u32.to_be_bytes());
+        sizes.extend_from_slice(&2048_u32.to_be_bytes());
+        let flags = ClipboardAction::CAPS | ClipboardAction::PROVIDE | ClipboardFormat::TEXT | ClipboardFormat::HTML;
+
+        match parse_extended_cut_text(&extended_payload(flags, &sizes)) {
+            Some(ExtendedClipboardMessage::Caps(clipboard_caps)) => {
+                assert_eq!(clipboard_caps.flags, flags);
+                assert_eq!(clipboard_caps.max_sizes.get(&ClipboardFormat::TEXT), Option::Some(&1024));
+                assert_eq!(clipboard_caps.max_sizes.get(&ClipboardFormat::HTML), Option::Some(&2048));
+                assert_eq!(clipboard_caps.max_sizes.get(&ClipboardFormat::RTF), Option::None);
+            },
+            _ => panic!("expected Caps"),
+        }
+    }
+
+    #[test]
+    fn parses_extended_actions() {
+        let payload = extended_payload(ClipboardAction::REQUEST | ClipboardFormat::TEXT, &[]);
+        assert!(matches!(parse_extended_cut_text(&payload), Some(ExtendedClipboardMessage::Request(ClipboardFormat::TEXT))));
+
+        let payload = extended_payload(ClipboardAction::PEEK, &[]);
+        assert!(matches!(parse_extended_cut_text(&payload), Some(ExtendedClipboardMessage::Peek)));
+
+        let payload = extended_payload(ClipboardAction::NOTIFY | ClipboardFormat::RTF, &[]);
+        assert!(matches!(parse_extended_cut_text(&payload), Some(ExtendedClipboardMessage::Notify(ClipboardFormat::RTF))));
+
+        assert!(parse_extended_cut_text(&extended_payload(ClipboardFormat::TEXT, &[])).is_none());
+        assert!(parse_extended_cut_text(&[0, 0]).is_none());
+    }
+
+    #[test]
+    fn provide_round_trip() {
+        let mut provided_data: Vec<u8> = vec![];
+        for (clipboard_format, value) in [(ClipboardFormat::TEXT, "line one\nline two"), (ClipboardFormat::HTML, "<b>bold</b>")] {
+            let value = provided_value(clipboard_format, value);
+            provided_data.extend_from_slice(&(value.len() as u32).to_be_bytes());
+            provided_data.extend(value);
+        }
+
+        let compressed_data = encoding_zlib::compress_once(&provided_data, encoding_zlib::ZLIB_COMPRESS_LEVEL).unwrap();
+        let flags = ClipboardAction::PROVIDE | ClipboardFormat::TEXT | ClipboardFormat::HTML;
+        match parse_extended_cut_text(&extended_payload(flags, &compressed_data)) {
+            Some(ExtendedClipboardMessage::Provide(clipboard_data)) => {
+                assert_eq!(clipboard_data.text.as_deref(), Option::Some("line one\nline two"));
+                assert_eq!(clipboard_data.html.as_deref(), Option::Some("<b>bold</b>"));
+                assert_eq!(clipboard_data.rtf, Option::None);
+                assert_eq!(clipboard_data.formats(), ClipboardFormat::TEXT | ClipboardFormat::HTML);
+            },
+            _ => panic!("expected Provide"),
+        }
+    }
+
+    #[test]
+    fn provide_with_bad_data_is_refused() {
+        /* Not zlib */
+        let payload = extended_payload(ClipboardAction::PROVIDE | ClipboardFormat::TEXT, &[1, 2, 3, 4]);
+        assert!(parse_extended_cut_text(&payload).is_none());
+
+        /* Size past the end of the data */
+        let mut provided_data = 100_u32.to_be_bytes().to_vec();
+        provided_data.extend_from_slice(b"text\0");
+        let compressed_data = encoding_zlib::compress_once(&provided_data, encoding_zlib::ZLIB_COMPRESS_LEVEL).unwrap();
+        let payload = extended_payload(ClipboardAction::PROVIDE | ClipboardFormat::TEXT, &compressed_data);
+        assert!(parse_extended_cut_text(&payload).is_none());
+    }
+
+    #[test]
+    fn unsolicited_size_follows_client_caps() {
+        let client_caps = ClipboardCaps {
+            flags: ClipboardFormat::TEXT,
+            max_sizes: HashMap::from([(ClipboardFormat::TEXT, 8)]),
+        };
+
+        assert!(fits_unsolicited(&ClipboardData::from_text("short".to_string()), &client_caps));
+        assert!(!fits_unsolicited(&ClipboardData::from_text("much too long".to_string()), &client_caps));
+    }
+}
@@ -0,0 +1,179 @@
+/*
+    SpifyRFB - Modern RFB Server implementation using Rust
+    Copyright (C) 2023  Atheesh Thirumalairajan
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::{env, fs, path::PathBuf};
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use super::sessions::AccessLevel;
+
+/* Checks Plain (Username and Password) Credentials, None if they are wrong */
+pub trait CredentialBackend: Send + Sync {
+    fn verify(&self, username: &str, password: &str) -> Option<AccessLevel>;
+}
+
+/*
+    One User per line, as USERNAME:HASH[:ACCESS]
+    HASH is bcrypt ($2a$, $2b$, $2y$) or argon2 ($argon2id$, ...)
+    ACCESS is 'full' (default) or 'view'
+*/
+pub struct HtpasswdBackend {
+    path: PathBuf
+}
+
+impl HtpasswdBackend {
+    pub fn new(path: PathBuf) -> HtpasswdBackend {
+        HtpasswdBackend { path }
+    }
+
+    pub fn default_path() -> PathBuf {
+        /* Next to config.json */
+        let mut spify_installpath = env::current_exe().unwrap();
+        spify_installpath.set_file_name("htpasswd");
+        spify_installpath
+    }
+}
+
+fn verify_hash(password: &str, hash: &str) -> bool {
+    if hash.starts_with("$2") {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    } else if hash.starts_with("$argon2") {
+        PasswordHash::new(hash)
+            .map(|password_hash| Argon2::default().verify_password(password.as_bytes(), &password_hash).is_ok())
+            .unwrap_or(false)
+    } else {
+        /* Plaintext, MD5 and SHA-1 entries are refused */
+        false
+    }
+}
+
+impl CredentialBackend for HtpasswdBackend {
+    fn verify(&self, username: &str, password: &str) -> Option<AccessLevel> {
+        /* Read on every attempt, edits apply without a restart */
+        let htpasswd = fs::read_to_string(&self.path).ok()?;
+        let mut user_entry = htpasswd
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| line.splitn(3, ':'))
+            .find(|fields| fields.clone().next() == Option::Some(username))?;
+
+        user_entry.next();
+        let hash = user_entry.next()?;
+        let access_level = match user_entry.next() {
+            Option::None | Option::Some("full") => AccessLevel::FullControl,
+            Option::Some("view") => AccessLevel::ViewOnly,
+            Option::Some(_) => return Option::None,
+        };
+
+        verify_hash(password, hash).then_some(access_level)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use argon2::{PasswordHasher, password_hash::SaltString};
+
+    struct TestHtpasswd {
+        backend: HtpasswdBackend
+    }
+
+    impl TestHtpasswd {
+        fn new(name: &str, contents: &str) -> TestHtpasswd {
+            let path = env::temp_dir().join(format!("spifyrfb-htpasswd-{}-{}", std::process::id(), name));
+            fs::write(&path, contents).unwrap();
+            TestHtpasswd { backend: HtpasswdBackend::new(path) }
+        }
+    }
+
+    impl Drop for TestHtpasswd {
+        fn drop(&mut self) {
+            fs::remove_file(&self.backend.path).unwrap_or(());
+        }
+    }
+
+    fn bcrypt_hash(password: &str) -> String {
+        /* Lowest Cost, the Tests only check the Format */
+        bcrypt::hash(password, 4).unwrap()
+    }
+
+    fn argon2_hash(password: &str) -> String {
+        let salt = SaltString::encode_b64(b"spifyrfb-salt").unwrap();
+        Argon2::default().hash_password(password.as_bytes(), &salt).unwrap().to_string()
+    }
+
+    #[test]
+    fn verifies_bcrypt_and_argon2() {
+        let htpasswd = TestHtpasswd::new("hashes", &format!(
+            "alice:{}\nbob:{}:full\n",
+            bcrypt_hash("alice-password"),
+            argon2_hash("bob-password")
+        ));
+
+        assert_eq!(htpasswd.backend.verify("alice", "alice-password"), Option::Some(AccessLevel::FullControl));
+        assert_eq!(htpasswd.backend.verify("bob", "bob-password"), Option::Some(AccessLevel::FullControl));
+        assert_eq!(htpasswd.backend.verify("alice", "bob-password"), Option::None);
+        assert_eq!(htpasswd.backend.verify("bob", ""), Option::None);
+    }
+
+    #[test]
+    fn reads_access_level() {
+        let htpasswd = TestHtpasswd::new("access", &format!(
+            "viewer:{}:view\nadmin:{}:root\n",
+            bcrypt_hash("password"),
+            bcrypt_hash("password")
+        ));
+
+        assert_eq!(htpasswd.backend.verify("viewer", "password"), Option::Some(AccessLevel::ViewOnly));
+
+        /* Unknown Access is refused, not widened */
+        assert_eq!(htpasswd.backend.verify("admin", "password"), Option::None);
+    }
+
+    #[test]
+    fn skips_comments_and_unknown_users() {
+        let htpasswd = TestHtpasswd::new("comments", &format!(
+            "# alice:{0}\n\n  carol:{0}  \n",
+            bcrypt_hash("password")
+        ));
+
+        assert_eq!(htpasswd.backend.verify("# alice", "password"), Option::None);
+        assert_eq!(htpasswd.backend.verify("alice", "password"), Option::None);
+        assert_eq!(htpasswd.backend.verify("carol", "password"), Option::Some(AccessLevel::FullControl));
+        assert_eq!(htpasswd.backend.verify("dave", "password"), Option::None);
+    }
+
+    #[test]
+    fn refuses_weak_hashes() {
+        let htpasswd = TestHtpasswd::new("weak", concat!(
+            "plain:password\n",
+            "md5:$apr1$salt$2Hp7bpyOCFBN2B8Q9HuNA1\n",
+            "sha1:{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=\n",
+            "empty:\n"
+        ));
+
+        for username in ["plain", "md5", "sha1", "empty"] {
+            assert_eq!(htpasswd.backend.verify(username, "password"), Option::None);
+        }
+    }
+
+    #[test]
+    fn missing_file_refuses_everyone() {
+        let backend = HtpasswdBackend::new(env::temp_dir().join("spifyrfb-htpasswd-does-not-exist"));
+        assert_eq!(backend.verify("alice", "password"), Option::None);
+    }
+}
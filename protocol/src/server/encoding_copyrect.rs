@@ -0,0 +1,397 @@
+/*
+    SpifyRFB - Modern RFB Server implementation using Rust
+    Copyright (C) 2023  Atheesh Thirumalairajan
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::{cmp::Reverse, collections::{HashMap, HashSet}};
+use super::{
+    FrameBuffer, FrameBufferRectangle, RFBEncodingType,
+    incremental::{self, DamagedRectangle, DAMAGE_TILE_SIZE}
+};
+
+/* Width (in pixels) of the row segments used to find moved content */
+const SEGMENT_WIDTH: usize = 16;
+const SAMPLE_SPACING: usize = 8;
+const MAX_SAMPLES: usize = 128;
+
+/* An offset needs this many matching samples before it is verified */
+const MIN_VOTES: usize = 4;
+const MAX_CANDIDATES: usize = 4;
+const HASH_BASE: u64 = 0x100000001b3;
+
+pub fn get_pixel_data(damaged_rectangle: DamagedRectangle) -> FrameBufferRectangle {
+    let (source_x, source_y) = damaged_rectangle.copy_source.unwrap_or((
+        damaged_rectangle.x_position,
+        damaged_rectangle.y_position
+    ));
+
+    /* CopyRect is just the Source Position */
+    let mut encoded_pixels: Vec<u8> = Vec::with_capacity(4);
+    encoded_pixels.extend_from_slice(&source_x.to_be_bytes());
+    encoded_pixels.extend_from_slice(&source_y.to_be_bytes());
+
+    FrameBufferRectangle {
+        x_position: damaged_rectangle.x_position,
+        y_position: damaged_rectangle.y_position,
+        width: damaged_rectangle.width,
+        height: damaged_rectangle.height,
+        encoding_type: RFBEncodingType::COPY_RECT,
+        encoded_pixels,
+        encoded_pixels_length: 0,
+    }
+}
+
+fn segment_hash(segment: &[u8]) -> u64 {
+    segment.iter().fold(0_u64, |hash, byte| hash.wrapping_mul(HASH_BASE).wrapping_add(*byte as u64))
+}
+
+fn is_uniform(segment: &[u8], bytes_per_pixel: usize) -> bool {
+    let first_pixel = &segment[..bytes_per_pixel];
+    segment.chunks_exact(bytes_per_pixel).all(|pixel| pixel == first_pixel)
+}
+
+fn intersects(a: (usize, usize, usize, usize), b: (usize, usize, usize, usize)) -> bool {
+    /* Rectangles are (x, y, width, height) */
+    a.0 < b.0 + b.2 && b.0 < a.0 + a.2 && a.1 < b.1 + b.3 && b.1 < a.1 + a.3
+}
+
+fn tile_matches(
+    previous: &FrameBuffer,
+    current: &FrameBuffer,
+    tile: (usize, usize),
+    offset: (isize, isize)
+) -> bool {
+    let bytes_per_pixel = (current.bits_per_pixel / 8) as usize;
+    let stride = current.width as usize * bytes_per_pixel;
+    let x_start = tile.0 * DAMAGE_TILE_SIZE;
+    let y_start = tile.1 * DAMAGE_TILE_SIZE;
+    let x_end = (x_start + DAMAGE_TILE_SIZE).min(current.width as usize);
+    let y_end = (y_start + DAMAGE_TILE_SIZE).min(current.height as usize);
+
+    /* The Source must lie completely inside the Frame */
+    let source_x = x_start as isize - offset.0;
+    let source_y = y_start as isize - offset.1;
+    if source_x < 0
+        || source_y < 0
+        || source_x as usize + (x_end - x_start) > current.width as usize
+        || source_y as usize + (y_end - y_start) > current.height as usize {
+        return false;
+    }
+
+    let row_length = (x_end - x_start) * bytes_per_pixel;
+    for line in 0..(y_end - y_start) {
+        let current_start = (y_start + line) * stride + x_start * bytes_per_pixel;
+        let previous_start = (source_y as usize + line) * stride + source_x as usize * bytes_per_pixel;
+        if current.raw_pixels[current_start..(current_start + row_length)]
+            != previous.raw_pixels[previous_start..(previous_start + row_length)] {
+            return false;
+        }
+    }
+
+    true
+}
+
+fn collect_samples(
+    previous: &FrameBuffer,
+    current: &FrameBuffer,
+    damaged_rectangles: &[DamagedRectangle]
+) -> HashMap<u64, Vec<(usize, usize)>> {
+    let bytes_per_pixel = (current.bits_per_pixel / 8) as usize;
+    let stride = current.width as usize * bytes_per_pixel;
+    let segment_length = SEGMENT_WIDTH * bytes_per_pixel;
+    let mut positions: Vec<(usize, usize)> = vec![];
+
+    for damaged_rectangle in damaged_rectangles {
+        let x_start = (damaged_rectangle.x_position - current.x_position) as usize;
+        let y_start = (damaged_rectangle.y_position - current.y_position) as usize;
+        let x_end = x_start + damaged_rectangle.width as usize;
+        let y_end = y_start + damaged_rectangle.height as usize;
+        if x_end - x_start < SEGMENT_WIDTH {
+            continue;
+        }
+
+        for y in (y_start..y_end).step_by(SAMPLE_SPACING) {
+            for x in (x_start..=(x_end - SEGMENT_WIDTH)).step_by(SEGMENT_WIDTH) {
+                let start = y * stride + x * bytes_per_pixel;
+                let segment = &current.raw_pixels[start..(start + segment_length)];
+
+                /* Flat or Unchanged segments match everywhere, skip them */
+                if !is_uniform(segment, bytes_per_pixel)
+                    && segment != &previous.raw_pixels[start..(start + segment_length)] {
+                    positions.push((x, y));
+                }
+            }
+        }
+    }
+
+    /* Spread the Samples over the whole Damage */
+    let mut samples: HashMap<u64, Vec<(usize, usize)>> = HashMap::new();
+    let sample_step = positions.len().div_ceil(MAX_SAMPLES).max(1);
+    for (x, y) in positions.into_iter().step_by(sample_step) {
+        let start = y * stride + x * bytes_per_pixel;
+        let hash = segment_hash(&current.raw_pixels[start..(start + segment_length)]);
+        samples.entry(hash).or_default().push((x, y));
+    }
+
+    samples
+}
+
+fn vote_offsets(
+    previous: &FrameBuffer,
+    damaged_rectangles: &[DamagedRectangle],
+    samples: &HashMap<u64, Vec<(usize, usize)>>
+) -> Vec<(isize, isize)> {
+    let bytes_per_pixel = (previous.bits_per_pixel / 8) as usize;
+    let stride = previous.width as usize * bytes_per_pixel;
+    let segment_length = SEGMENT_WIDTH * bytes_per_pixel;
+    let base_power = HASH_BASE.wrapping_pow(segment_length as u32);
+
+    /* Moved content comes from somewhere inside the Damage */
+    let x_start = damaged_rectangles.iter().map(|rectangle| rectangle.x_position).min().unwrap();
+    let y_start = damaged_rectangles.iter().map(|rectangle| rectangle.y_position).min().unwrap();
+    let x_end = damaged_rectangles.iter().map(|rectangle| rectangle.x_position + rectangle.width).max().unwrap();
+    let y_end = damaged_rectangles.iter().map(|rectangle| rectangle.y_position + rectangle.height).max().unwrap();
+    let x_start = (x_start - previous.x_position) as usize;
+    let x_end = (x_end - previous.x_position) as usize;
+    let y_start = (y_start - previous.y_position) as usize;
+    let y_end = (y_end - previous.y_position) as usize;
+
+    /* Cheap filter so most positions skip the HashMap lookup */
+    let mut sample_filter: Vec<bool> = vec![false; 1 << 16];
+    for hash in samples.keys() {
+        sample_filter[(hash >> 48) as usize] = true;
+    }
+
+    let mut votes: HashMap<(isize, isize), usize> = HashMap::new();
+    for y in y_start..y_end {
+        let row = &previous.raw_pixels[(y * stride + x_start * bytes_per_pixel)..(y * stride + x_end * bytes_per_pixel)];
+        let mut hash: u64 = 0;
+
+        /* Rolling Hash over every Segment of the Row */
+        for (index, byte) in row.iter().enumerate() {
+            hash = hash.wrapping_mul(HASH_BASE).wrapping_add(*byte as u64);
+            if index >= segment_length {
+                hash = hash.wrapping_sub((row[index - segment_length] as u64).wrapping_mul(base_power));
+            }
+
+            let segment_start = (index + 1) as isize - segment_length as isize;
+            if segment_start < 0
+                || !(segment_start as usize).is_multiple_of(bytes_per_pixel)
+                || !sample_filter[(hash >> 48) as usize] {
+                continue;
+            }
+
+            if let Some(positions) = samples.get(&hash) {
+                let x = x_start + segment_start as usize / bytes_per_pixel;
+                for (sample_x, sample_y) in positions {
+                    let offset = (*sample_x as isize - x as isize, *sample_y as isize - y as isize);
+                    if offset != (0, 0) {
+                        *votes.entry(offset).or_insert(0) += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    let mut candidates: Vec<((isize, isize), usize)> = votes
+        .into_iter()
+        .filter(|(_, count)| *count >= MIN_VOTES)
+        .collect();
+
+    candidates.sort_by_key(|candidate| Reverse(candidate.1));
+    candidates.into_iter().take(MAX_CANDIDATES).map(|(offset, _)| offset).collect()
+}
+
+pub fn find_copies(
+    previous: &FrameBuffer,
+    current: &FrameBuffer,
+    damaged_rectangles: &[DamagedRectangle]
+) -> Vec<DamagedRectangle> {
+    let samples = collect_samples(previous, current, damaged_rectangles);
+    if samples.is_empty() {
+        return vec![];
+    }
+
+    /* Every tile inside a Damaged Rectangle has changed */
+    let mut changed_tiles: HashSet<(usize, usize)> = HashSet::new();
+    for damaged_rectangle in damaged_rectangles {
+        let x_start = (damaged_rectangle.x_position - current.x_position) as usize;
+        let y_start = (damaged_rectangle.y_position - current.y_position) as usize;
+        for tile_y in (y_start / DAMAGE_TILE_SIZE)..(y_start + damaged_rectangle.height as usize).div_ceil(DAMAGE_TILE_SIZE) {
+            for tile_x in (x_start / DAMAGE_TILE_SIZE)..(x_start + damaged_rectangle.width as usize).div_ceil(DAMAGE_TILE_SIZE) {
+                changed_tiles.insert((tile_x, tile_y));
+            }
+        }
+    }
+
+    let mut copy_rectangles: Vec<DamagedRectangle> = vec![];
+    for offset in vote_offsets(previous, damaged_rectangles, &samples) {
+        let matching_tiles: HashSet<(usize, usize)> = changed_tiles
+            .iter()
+            .filter(|tile| tile_matches(previous, current, **tile, offset))
+            .copied()
+            .collect();
+
+        for mut copy_rectangle in incremental::merge_tiles(current, |tile_x, tile_y| matching_tiles.contains(&(tile_x, tile_y))) {
+            let destination = (
+                (copy_rectangle.x_position - current.x_position) as usize,
+                (copy_rectangle.y_position - current.y_position) as usize,
+                copy_rectangle.width as usize,
+                copy_rectangle.height as usize
+            );
+
+            let source = (
+                (destination.0 as isize - offset.0) as usize,
+                (destination.1 as isize - offset.1) as usize,
+                destination.2,
+                destination.3
+            );
+
+            /* The Client applies Copies in order, don't read what an earlier Copy wrote */
+            let source_overwritten = copy_rectangles.iter().any(|earlier| intersects(source, (
+                (earlier.x_position - current.x_position) as usize,
+                (earlier.y_position - current.y_position) as usize,
+                earlier.width as usize,
+                earlier.height as usize
+            )));
+
+            if source_overwritten {
+                continue;
+            }
+
+            for tile_y in (destination.1 / DAMAGE_TILE_SIZE)..(destination.1 + destination.3).div_ceil(DAMAGE_TILE_SIZE) {
+                for tile_x in (destination.0 / DAMAGE_TILE_SIZE)..(destination.0 + destination.2).div_ceil(DAMAGE_TILE_SIZE) {
+                    changed_tiles.remove(&(tile_x, tile_y));
+                }
+            }
+
+            copy_rectangle.copy_source = Option::Some((
+                current.x_position + source.0 as u16,
+                current.y_position + source.1 as u16
+            ));
+
+            copy_rectangles.push(copy_rectangle);
+        }
+    }
+
+    copy_rectangles
+}
+
+pub fn apply_copies(previous: &FrameBuffer, copy_rectangles: &[DamagedRectangle]) -> FrameBuffer {
+    let bytes_per_pixel = (previous.bits_per_pixel / 8) as usize;
+    let stride = previous.width as usize * bytes_per_pixel;
+    let mut predicted = previous.clone();
+
+    /* Replay the Copies the way the Client will */
+    for copy_rectangle in copy_rectangles {
+        let (source_x, source_y) = copy_rectangle.copy_source.unwrap();
+        let source = incremental::crop(&predicted, DamagedRectangle {
+            x_position: source_x,
+            y_position: source_y,
+            ..*copy_rectangle
+        });
+
+        let x_offset = (copy_rectangle.x_position - predicted.x_position) as usize * bytes_per_pixel;
+        let y_offset = (copy_rectangle.y_position - predicted.y_position) as usize;
+        let row_length = copy_rectangle.width as usize * bytes_per_pixel;
+
+        for (line, source_row) in source.raw_pixels.chunks_exact(row_length).enumerate() {
+            let start = (y_offset + line) * stride + x_offset;
+            predicted.raw_pixels[start..(start + row_length)].copy_from_slice(source_row);
+        }
+    }
+
+    predicted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::test_support::{framebuffer, noise_pixels};
+
+    const BYTES_PER_PIXEL: usize = 4;
+
+    fn whole_frame(framebuffer: &FrameBuffer) -> DamagedRectangle {
+        DamagedRectangle {
+            x_position: framebuffer.x_position,
+            y_position: framebuffer.y_position,
+            width: framebuffer.width,
+            height: framebuffer.height,
+            copy_source: Option::None,
+        }
+    }
+
+    fn scrolled(previous: &FrameBuffer, lines: usize) -> FrameBuffer {
+        /* Content moves up, new lines appear at the bottom */
+        let stride = previous.width as usize * BYTES_PER_PIXEL;
+        let mut raw_pixels = previous.raw_pixels[(lines * stride)..].to_vec();
+        raw_pixels.extend(noise_pixels(lines * stride, 0xC0FFEE));
+        framebuffer(previous.width, previous.height, raw_pixels)
+    }
+
+    #[test]
+    fn encodes_the_source_position() {
+        let rectangle = get_pixel_data(DamagedRectangle {
+            x_position: 10,
+            y_position: 20,
+            width: 30,
+            height: 40,
+            copy_source: Option::Some((300, 2)),
+        });
+
+        assert_eq!(rectangle.encoding_type, RFBEncodingType::COPY_RECT);
+        assert_eq!((rectangle.x_position, rectangle.y_position, rectangle.width, rectangle.height), (10, 20, 30, 40));
+        assert_eq!(rectangle.encoded_pixels, vec![1, 44, 0, 2]);
+    }
+
+    #[test]
+    fn finds_scrolled_content() {
+        let previous = framebuffer(128, 128, noise_pixels(128 * 128 * BYTES_PER_PIXEL, 0x1234));
+        let current = scrolled(&previous, DAMAGE_TILE_SIZE);
+        let copy_rectangles = find_copies(&previous, &current, &[whole_frame(&current)]);
+
+        assert!(!copy_rectangles.is_empty());
+        let copied_lines: usize = copy_rectangles
+            .iter()
+            .map(|copy_rectangle| copy_rectangle.width as usize * copy_rectangle.height as usize)
+            .sum::<usize>() / 128;
+        assert_eq!(copied_lines, 128 - DAMAGE_TILE_SIZE);
+
+        /* Replaying the Copies gives the current pixels inside every Copy */
+        let predicted = apply_copies(&previous, &copy_rectangles);
+        for copy_rectangle in &copy_rectangles {
+            let (source_x, source_y) = copy_rectangle.copy_source.unwrap();
+            assert_eq!((source_x, source_y), (copy_rectangle.x_position, copy_rectangle.y_position + DAMAGE_TILE_SIZE as u16));
+            let destination = DamagedRectangle { copy_source: Option::None, ..*copy_rectangle };
+            assert_eq!(incremental::crop(&predicted, destination).raw_pixels, incremental::crop(&current, destination).raw_pixels);
+        }
+    }
+
+    #[test]
+    fn new_content_has_no_copies() {
+        let previous = framebuffer(64, 64, noise_pixels(64 * 64 * BYTES_PER_PIXEL, 1));
+        let current = framebuffer(64, 64, noise_pixels(64 * 64 * BYTES_PER_PIXEL, 2));
+        assert!(find_copies(&previous, &current, &[whole_frame(&current)]).is_empty());
+    }
+
+    #[test]
+    fn flat_content_has_no_copies() {
+        /* Uniform Segments match everywhere, they can't tell where content came from */
+        let previous = framebuffer(64, 64, [0, 0, 0, 0].repeat(64 * 64));
+        let current = framebuffer(64, 64, [255, 255, 255, 0].repeat(64 * 64));
+        assert!(find_copies(&previous, &current, &[whole_frame(&current)]).is_empty());
+    }
+}
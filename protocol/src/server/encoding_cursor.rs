@@ -0,0 +1,230 @@
+/*
+    SpifyRFB - Modern RFB Server implementation using Rust
+    Copyright (C) 2023  Atheesh Thirumalairajan
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use super::{FrameBuffer, FrameBufferRectangle, PixelFormat, RFBEncodingType, events::CursorImage, translate};
+
+/* Pixels at least half opaque are part of the Cursor */
+const MASK_ALPHA_THRESHOLD: u32 = 128;
+
+/* Warn once, not on every Update */
+static COMPOSITE_WARNED: AtomicBool = AtomicBool::new(false);
+
+fn argb_components(argb_pixel: u32) -> (u32, u32, u32, u32) {
+    let alpha = argb_pixel >> 24;
+    let (mut red, mut green, mut blue) = ((argb_pixel >> 16) & 0xFF, (argb_pixel >> 8) & 0xFF, argb_pixel & 0xFF);
+
+    /* XFixes Cursor Images are premultiplied */
+    if alpha > 0 && alpha < 255 {
+        red = (red * 255 / alpha).min(255);
+        green = (green * 255 / alpha).min(255);
+        blue = (blue * 255 / alpha).min(255);
+    }
+
+    (alpha, red, green, blue)
+}
+
+fn write_bitmap(cursor_data: &mut Vec<u8>, cursor: &CursorImage, bit_set: impl Fn(u32) -> bool) {
+    /* One bit per pixel, most significant first. Each Row starts on a Byte boundary */
+    for row in cursor.pixels.chunks_exact(cursor.width as usize) {
+        let mut row_bytes: Vec<u8> = vec![0; (cursor.width as usize).div_ceil(8)];
+        for (column, argb_pixel) in row.iter().enumerate() {
+            if bit_set(*argb_pixel) {
+                row_bytes[column / 8] |= 128 >> (column % 8);
+            }
+        }
+
+        cursor_data.extend(row_bytes);
+    }
+}
+
+fn is_visible(argb_pixel: u32) -> bool {
+    argb_components(argb_pixel).0 >= MASK_ALPHA_THRESHOLD
+}
+
+fn encode_rich_cursor(cursor: &CursorImage, pixelformat: PixelFormat) -> Vec<u8> {
+    let bytes_per_pixel = (pixelformat.bits_per_pixel / 8) as usize;
+
+    /* cursor-pixels in the Client's Pixel Format, followed by the bitmask */
+    let mut cursor_data: Vec<u8> = Vec::with_capacity(cursor.pixels.len() * bytes_per_pixel);
+    for argb_pixel in &cursor.pixels {
+        let (_alpha, red, green, blue) = argb_components(*argb_pixel);
+        cursor_data.extend(translate::rgb_to_pixel(red as u8, green as u8, blue as u8, &pixelformat));
+    }
+
+    write_bitmap(&mut cursor_data, cursor, is_visible);
+    cursor_data
+}
+
+fn encode_x_cursor(cursor: &CursorImage) -> Vec<u8> {
+    /* Two Colours only: Black (primary) for dark pixels, White for the rest */
+    let mut cursor_data: Vec<u8> = vec![0, 0, 0, 255, 255, 255];
+    write_bitmap(&mut cursor_data, cursor, |argb_pixel| {
+        let (_alpha, red, green, blue) = argb_components(argb_pixel);
+        (red * 299 + green * 587 + blue * 114) / 1000 < 128
+    });
+
+    write_bitmap(&mut cursor_data, cursor, is_visible);
+    cursor_data
+}
+
+pub fn get_cursor_rectangle(
+    cursor: &CursorImage,
+    encoding_type: i32,
+    pixelformat: PixelFormat
+) -> FrameBufferRectangle {
+    /* An empty Cursor hides it, and carries no data */
+    let cursor_data = if cursor.width == 0 || cursor.height == 0 {
+        vec![]
+    } else if encoding_type == RFBEncodingType::CURSOR {
+        encode_rich_cursor(cursor, pixelformat)
+    } else {
+        encode_x_cursor(cursor)
+    };
+
+    FrameBufferRectangle {
+        x_position: cursor.x_hotspot,
+        y_position: cursor.y_hotspot,
+        width: cursor.width,
+        height: cursor.height,
+        encoding_type,
+        encoded_pixels: cursor_data,
+        encoded_pixels_length: 0,
+    }
+}
+
+pub fn get_pointer_position_rectangle(x_position: u16, y_position: u16) -> FrameBufferRectangle {
+    FrameBufferRectangle {
+        x_position,
+        y_position,
+        width: 0,
+        height: 0,
+        encoding_type: RFBEncodingType::POINTER_POS,
+        encoded_pixels: vec![],
+        encoded_pixels_length: 0,
+    }
+}
+
+pub fn composite(
+    framebuffer: &mut FrameBuffer,
+    server_pixelformat: &PixelFormat,
+    cursor_pixels: &[u32],
+    cursor_width: u16,
+    cursor_height: u16,
+    cursor_x: i32,
+    cursor_y: i32
+) {
+    /* Colour-mapped Captures have no Channels to blend into */
+    if server_pixelformat.true_color_flag == 0 || framebuffer.bits_per_pixel != server_pixelformat.bits_per_pixel {
+        if !COMPOSITE_WARNED.swap(true, Ordering::Relaxed) {
+            println!("Cursor: {} bpp Capture is not True-Colour, the Pointer is left out for Clients without a Cursor Encoding", framebuffer.bits_per_pixel);
+        }
+
+        return;
+    }
+
+    let bytes_per_pixel = (framebuffer.bits_per_pixel / 8) as usize;
+    let stride = framebuffer.width as usize * bytes_per_pixel;
+    for cursor_row in 0..cursor_height as i32 {
+        let line = cursor_y + cursor_row - framebuffer.y_position as i32;
+        if line < 0 || line >= framebuffer.height as i32 {
+            continue;
+        }
+
+        for cursor_column in 0..cursor_width as i32 {
+            let column = cursor_x + cursor_column - framebuffer.x_position as i32;
+            if column < 0 || column >= framebuffer.width as i32 {
+                continue;
+            }
+
+            let argb_pixel = cursor_pixels[(cursor_row * cursor_width as i32 + cursor_column) as usize];
+            let alpha = argb_pixel >> 24;
+            if alpha == 0 {
+                continue;
+            }
+
+            /* Premultiplied: destination = cursor + destination x (1 - alpha), in 8 bit channels */
+            let start = line as usize * stride + column as usize * bytes_per_pixel;
+            let pixel = &mut framebuffer.raw_pixels[start..(start + bytes_per_pixel)];
+            let destination = translate::get_rgb_pixels(pixel, server_pixelformat);
+            let blended: Vec<u8> = [(argb_pixel >> 16) & 0xFF, (argb_pixel >> 8) & 0xFF, argb_pixel & 0xFF]
+                .into_iter()
+                .zip(destination)
+                .map(|(cursor_value, destination_value)| (cursor_value + destination_value as u32 * (255 - alpha) / 255).min(255) as u8)
+                .collect();
+
+            pixel.copy_from_slice(&translate::rgb_to_pixel(blended[0], blended[1], blended[2], server_pixelformat));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::test_support::framebuffer;
+
+    fn true_colour(bits_per_pixel: u8, max: (u16, u16, u16), shift: (u8, u8, u8)) -> PixelFormat {
+        PixelFormat {
+            bits_per_pixel,
+            depth: if bits_per_pixel == 32 { 24 } else { bits_per_pixel },
+            true_color_flag: 1,
+            red_max: max.0,
+            green_max: max.1,
+            blue_max: max.2,
+            red_shift: shift.0,
+            green_shift: shift.1,
+            blue_shift: shift.2,
+            ..Default::default()
+        }
+    }
+
+    /* Opaque Red, half transparent (premultiplied) White, and a transparent Pixel */
+    const CURSOR_PIXELS: [u32; 3] = [0xFFFF0000, 0x80808080, 0x00000000];
+
+    #[test]
+    fn composites_into_32bpp() {
+        let rgb888 = true_colour(32, (255, 255, 255), (16, 8, 0));
+        let mut capture = framebuffer(4, 1, [0, 0, 0, 0].repeat(4));
+        composite(&mut capture, &rgb888, &CURSOR_PIXELS, 3, 1, 1, 0);
+
+        assert_eq!(capture.raw_pixels, vec![
+            0, 0, 0, 0,
+            0, 0, 255, 0,
+            128, 128, 128, 0,
+            0, 0, 0, 0,
+        ]);
+    }
+
+    #[test]
+    fn composites_into_16bpp() {
+        let rgb565 = true_colour(16, (31, 63, 31), (11, 5, 0));
+        let mut capture = FrameBuffer { bits_per_pixel: 16, ..framebuffer(3, 1, [0xFF, 0xFF].repeat(3)) };
+        composite(&mut capture, &rgb565, &CURSOR_PIXELS, 3, 1, 0, 0);
+
+        /* Red stays Red, half White over White is White, transparent leaves the Pixel */
+        assert_eq!(capture.raw_pixels, vec![0x00, 0xF8, 0xFF, 0xFF, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn colour_mapped_capture_is_left_alone() {
+        let colour_map = PixelFormat { bits_per_pixel: 8, depth: 8, ..Default::default() };
+        let mut capture = FrameBuffer { bits_per_pixel: 8, ..framebuffer(3, 1, vec![1, 2, 3]) };
+        composite(&mut capture, &colour_map, &CURSOR_PIXELS, 3, 1, 0, 0);
+        assert_eq!(capture.raw_pixels, vec![1, 2, 3]);
+    }
+}
@@ -0,0 +1,100 @@
+/*
+    SpifyRFB - Modern RFB Server implementation using Rust
+    Copyright (C) 2023  Atheesh Thirumalairajan
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use super::{FrameBufferRectangle, RFBEncodingType};
+
+/* ExtendedDesktopSize: Why the Size changed (sent as x-position) */
+pub struct ResizeReason;
+impl ResizeReason {
+    pub const SERVER: u16 = 0;
+    pub const CLIENT: u16 = 1;
+}
+
+/* ExtendedDesktopSize: Result of a SetDesktopSize request (sent as y-position) */
+pub struct ResizeStatus;
+impl ResizeStatus {
+    pub const NO_ERROR: u16 = 0;
+    pub const PROHIBITED: u16 = 1;
+}
+
+pub fn get_desktop_size_rectangle(width: u16, height: u16) -> FrameBufferRectangle {
+    FrameBufferRectangle {
+        x_position: 0,
+        y_position: 0,
+        width,
+        height,
+        encoding_type: RFBEncodingType::DESKTOP_SIZE,
+        encoded_pixels: vec![],
+        encoded_pixels_length: 0,
+    }
+}
+
+pub fn get_extended_desktop_size_rectangle(
+    reason: u16,
+    status: u16,
+    width: u16,
+    height: u16
+) -> FrameBufferRectangle {
+    /* number-of-screens (U8), padding (U8 x 3), then a single Screen covering everything */
+    let mut screen_data: Vec<u8> = vec![1, 0, 0, 0];
+    screen_data.extend_from_slice(&0_u32.to_be_bytes()); /* SCREEN ID */
+    screen_data.extend_from_slice(&0_u16.to_be_bytes());
+    screen_data.extend_from_slice(&0_u16.to_be_bytes());
+    screen_data.extend_from_slice(&width.to_be_bytes());
+    screen_data.extend_from_slice(&height.to_be_bytes());
+    screen_data.extend_from_slice(&0_u32.to_be_bytes()); /* FLAGS */
+
+    FrameBufferRectangle {
+        x_position: reason,
+        y_position: status,
+        width,
+        height,
+        encoding_type: RFBEncodingType::EXTENDED_DESKTOP_SIZE,
+        encoded_pixels: screen_data,
+        encoded_pixels_length: 0,
+    }
+}
+
+pub fn get_desktop_name_rectangle(name_string: &str) -> FrameBufferRectangle {
+    let mut name_data: Vec<u8> = vec![];
+    name_data.extend_from_slice(&(name_string.len() as u32).to_be_bytes());
+    name_data.extend_from_slice(name_string.as_bytes());
+
+    FrameBufferRectangle {
+        x_position: 0,
+        y_position: 0,
+        width: 0,
+        height: 0,
+        encoding_type: RFBEncodingType::DESKTOP_NAME,
+        encoded_pixels: name_data,
+        encoded_pixels_length: 0,
+    }
+}
+
+pub fn get_capability_rectangle(encoding_type: i32) -> FrameBufferRectangle {
+    /* Empty pseudo-rectangle, tells the Client an extension is available */
+    FrameBufferRectangle {
+        x_position: 0,
+        y_position: 0,
+        width: 0,
+        height: 0,
+        encoding_type,
+        encoded_pixels: vec![],
+        encoded_pixels_length: 0,
+    }
+}
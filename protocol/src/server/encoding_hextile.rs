@@ -16,7 +16,19 @@
     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-use super::{FrameBuffer, FrameBufferRectangle, RFBEncodingType};
+use super::{
+    FrameBuffer, FrameBufferRectangle, RFBEncodingType,
+    encoding_rre::{background_pixel, find_subrectangles}
+};
+
+struct HextileSubEncoding;
+impl HextileSubEncoding {
+    const RAW: u8 = 1;
+    const BACKGROUND_SPECIFIED: u8 = 2;
+    const FOREGROUND_SPECIFIED: u8 = 4;
+    const ANY_SUBRECTS: u8 = 8;
+    const SUBRECTS_COLOURED: u8 = 16;
+}
 
 pub fn get_pixel_data(framebuffer: FrameBuffer) -> FrameBufferRectangle {
     let mut framebuffer_rectangle = FrameBufferRectangle {
@@ -40,82 +52,226 @@ pub fn get_pixel_data(framebuffer: FrameBuffer) -> FrameBufferRectangle {
     }
 }
 
-fn encode(framebuffer: FrameBuffer) -> Vec<u8> {
-    let bytes_per_pixel: u16 = (framebuffer.bits_per_pixel / 8) as u16;
-    const HEXTILE_WIDTH: f32 = 16_f32;
-    const HEXTILE_HEIGHT: f32 = 16_f32;
+fn encode_tile(
+    hextiles: &mut Vec<u8>,
+    tile_pixels: &[u8],
+    tile_width: usize,
+    tile_height: usize,
+    bytes_per_pixel: usize,
+    tile_colours: &mut (Option<Vec<u8>>, Option<Vec<u8>>)
+) {
+    let pixel_at = |index: usize| &tile_pixels[(index * bytes_per_pixel)..((index + 1) * bytes_per_pixel)];
+    let background_index = background_pixel(tile_pixels, bytes_per_pixel);
+    let background = pixel_at(background_index).to_vec();
+    let subrectangles = find_subrectangles(tile_pixels, tile_width, tile_height, bytes_per_pixel, background_index);
 
-    /* Divide FrameBuffer into Tiles of 16x16 pixels */
-    let h_tiles = (framebuffer.width as f32 / HEXTILE_WIDTH).ceil() as usize;
-    let v_tiles = (framebuffer.height as f32 / HEXTILE_HEIGHT).ceil() as usize;
+    let mut subencoding_mask: u8 = 0;
+    let mut tile_data: Vec<u8> = vec![];
 
-    let mut hextiles: Vec<u8> = vec![];
-    let hscan_lines: Vec<&[u8]>;
-    hscan_lines = framebuffer
-        .raw_pixels
-        .chunks_exact((framebuffer.width * bytes_per_pixel) as usize)
-        .collect();
-
-    /* Solid Tile Identification */
-    let mut solid_previous_tile: Vec<u8> = vec![];
-    let mut previous_scan_line: Vec<u8> = vec![];
-    let mut solid_tile = true;
-
-    for hextile_ctr in 0..(v_tiles * h_tiles) {
-        let mut tile_pixels: Vec<u8> = Vec::with_capacity(
-            (HEXTILE_WIDTH * HEXTILE_HEIGHT) as usize 
-            * bytes_per_pixel as usize,
-        );
-
-        let vertical_progress = ((hextile_ctr as f32 / h_tiles as f32).floor()) as usize;
-        let horizontal_progress = hextile_ctr % h_tiles;
-        let start = vertical_progress * HEXTILE_HEIGHT as usize;
-        let end = 
-            if (start + HEXTILE_HEIGHT as usize) > hscan_lines.len() { hscan_lines.len() } 
-            else { start + HEXTILE_HEIGHT as usize };
-
-        for hscan_line_ctr in start..end {
-            let h_start = horizontal_progress * bytes_per_pixel as usize * (HEXTILE_WIDTH as usize);
-            let h_end = 
-                if (h_start + (HEXTILE_HEIGHT as usize * bytes_per_pixel as usize)) > hscan_lines[hscan_line_ctr].len() { hscan_lines[hscan_line_ctr].len() } 
-                else { h_start + (HEXTILE_HEIGHT as usize * bytes_per_pixel as usize) };
-
-            let scan_line = &hscan_lines[hscan_line_ctr][h_start..h_end];
-            tile_pixels.extend_from_slice(scan_line);
-
-            if previous_scan_line.len() == 0 {
-                /* This is the first Line */
-                previous_scan_line = scan_line.to_vec();
-            } else {
-                if scan_line != previous_scan_line {
-                    /* This is not a Solid Tile */
-                    solid_tile = false;
-                }
+    /* Background and Foreground carry over from the previous Tile */
+    if tile_colours.0.as_ref() != Option::Some(&background) {
+        subencoding_mask |= HextileSubEncoding::BACKGROUND_SPECIFIED;
+        tile_data.extend_from_slice(&background);
+    }
+
+    let mut foreground: Option<Vec<u8>> = tile_colours.1.clone();
+    if !subrectangles.is_empty() {
+        let first_colour = pixel_at(subrectangles[0].pixel);
+        let single_colour = subrectangles
+            .iter()
+            .all(|subrectangle| pixel_at(subrectangle.pixel) == first_colour);
+
+        subencoding_mask |= HextileSubEncoding::ANY_SUBRECTS;
+        if single_colour {
+            if foreground.as_deref() != Option::Some(first_colour) {
+                subencoding_mask |= HextileSubEncoding::FOREGROUND_SPECIFIED;
+                tile_data.extend_from_slice(first_colour);
+                foreground = Option::Some(first_colour.to_vec());
             }
+        } else {
+            subencoding_mask |= HextileSubEncoding::SUBRECTS_COLOURED;
+            foreground = Option::None;
         }
 
-        if solid_tile == true {
-            let mut solid_color: Vec<u8> = vec![];
-            for subpixel in 0..(bytes_per_pixel as usize) {
-                solid_color.push(tile_pixels[subpixel]);
+        tile_data.push(subrectangles.len() as u8);
+        for subrectangle in &subrectangles {
+            if !single_colour {
+                tile_data.extend_from_slice(pixel_at(subrectangle.pixel));
             }
 
-            if solid_color == solid_previous_tile {
-                /* Set No bits, color same as previous tile */
-                hextiles.push(0_u8);
-            } else {
-                hextiles.push(2_u8);
-                hextiles.extend_from_slice(&solid_color);
+            /* Position and Size (minus one) packed into Nibbles */
+            tile_data.push(((subrectangle.x_position as u8) << 4) | subrectangle.y_position as u8);
+            tile_data.push((((subrectangle.width - 1) as u8) << 4) | (subrectangle.height - 1) as u8);
+        }
+    }
+
+    if subrectangles.len() > 255 || tile_data.len() >= tile_pixels.len() {
+        /* Busy Tile, RAW is smaller. Colours are undefined afterwards */
+        hextiles.push(HextileSubEncoding::RAW);
+        hextiles.extend_from_slice(tile_pixels);
+        *tile_colours = (Option::None, Option::None);
+    } else {
+        hextiles.push(subencoding_mask);
+        hextiles.extend(tile_data);
+        *tile_colours = (Option::Some(background), foreground);
+    }
+}
+
+fn encode(framebuffer: FrameBuffer) -> Vec<u8> {
+    let bytes_per_pixel = (framebuffer.bits_per_pixel / 8) as usize;
+    let width = framebuffer.width as usize;
+    let height = framebuffer.height as usize;
+    let stride = width * bytes_per_pixel;
+    const HEXTILE_SIZE: usize = 16;
+
+    /* (Background, Foreground) of the previous Tile */
+    let mut tile_colours: (Option<Vec<u8>>, Option<Vec<u8>>) = (Option::None, Option::None);
+    let mut hextiles: Vec<u8> = vec![];
+
+    /* Divide FrameBuffer into Tiles of 16x16 pixels */
+    for tile_y in (0..height).step_by(HEXTILE_SIZE) {
+        let tile_height = HEXTILE_SIZE.min(height - tile_y);
+        for tile_x in (0..width).step_by(HEXTILE_SIZE) {
+            let tile_width = HEXTILE_SIZE.min(width - tile_x);
+            let mut tile_pixels: Vec<u8> = Vec::with_capacity(tile_width * tile_height * bytes_per_pixel);
 
-                /* Update Previous Hextile (for Solid Color) */
-                solid_previous_tile = solid_color;
+            for line in tile_y..(tile_y + tile_height) {
+                let start = line * stride + tile_x * bytes_per_pixel;
+                tile_pixels.extend_from_slice(&framebuffer.raw_pixels[start..(start + tile_width * bytes_per_pixel)]);
             }
-        } else {
-            hextiles.push(1_u8);
-            hextiles.extend_from_slice(tile_pixels.as_slice());
+
+            encode_tile(&mut hextiles, &tile_pixels, tile_width, tile_height, bytes_per_pixel, &mut tile_colours);
         }
     }
 
     /* Send Hextiles */
     hextiles
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::test_support::{framebuffer, noise_pixels};
+
+    const BYTES_PER_PIXEL: usize = 4;
+
+    fn test_pixels(width: usize, height: usize) -> Vec<u8> {
+        /* Flat Tiles, Tiles with one or more Foreground Colours, and a noisy corner */
+        let noise = noise_pixels(width * height * BYTES_PER_PIXEL, 0x9E3779B9);
+        let mut pixels: Vec<u8> = vec![];
+        for y in 0..height {
+            for x in 0..width {
+                let noise_start = (y * width + x) * BYTES_PER_PIXEL;
+                let pixel: [u8; 4] = match (x, y) {
+                    (0..=15, 0..=15) => [noise[noise_start], noise[noise_start + 1], noise[noise_start + 2], 0],
+                    (20..=27, 4..=9) => [0, 0, 255, 0],
+                    (30..=33, 20..=40) => [0, 255, 0, 0],
+                    (36..=37, 22..=25) => [255, 0, 0, 0],
+                    _ if (x / 4 + y / 4) % 2 == 0 && y >= 48 => [0, 0, 0, 0],
+                    _ => [255, 255, 255, 0],
+                };
+                pixels.extend_from_slice(&pixel);
+            }
+        }
+
+        pixels
+    }
+
+    fn decode(hextiles: &[u8], width: usize, height: usize) -> Vec<u8> {
+        let mut pixels: Vec<u8> = vec![0; width * height * BYTES_PER_PIXEL];
+        let mut background: Vec<u8> = vec![];
+        let mut foreground: Vec<u8> = vec![];
+        let mut offset = 0;
+        let take = |length: usize, offset: &mut usize| {
+            *offset += length;
+            hextiles[(*offset - length)..*offset].to_vec()
+        };
+
+        for tile_y in (0..height).step_by(16) {
+            let tile_height = 16.min(height - tile_y);
+            for tile_x in (0..width).step_by(16) {
+                let tile_width = 16.min(width - tile_x);
+                let mut fill = |x: usize, y: usize, fill_width: usize, fill_height: usize, pixel: &[u8]| {
+                    for line in (tile_y + y)..(tile_y + y + fill_height) {
+                        for column in (tile_x + x)..(tile_x + x + fill_width) {
+                            let start = (line * width + column) * BYTES_PER_PIXEL;
+                            pixels[start..(start + BYTES_PER_PIXEL)].copy_from_slice(pixel);
+                        }
+                    }
+                };
+
+                let subencoding_mask = take(1, &mut offset)[0];
+                if subencoding_mask & HextileSubEncoding::RAW != 0 {
+                    let raw_pixels = take(tile_width * tile_height * BYTES_PER_PIXEL, &mut offset);
+                    for (index, pixel) in raw_pixels.chunks_exact(BYTES_PER_PIXEL).enumerate() {
+                        fill(index % tile_width, index / tile_width, 1, 1, pixel);
+                    }
+                    continue;
+                }
+
+                if subencoding_mask & HextileSubEncoding::BACKGROUND_SPECIFIED != 0 {
+                    background = take(BYTES_PER_PIXEL, &mut offset);
+                }
+                fill(0, 0, tile_width, tile_height, &background);
+
+                if subencoding_mask & HextileSubEncoding::FOREGROUND_SPECIFIED != 0 {
+                    foreground = take(BYTES_PER_PIXEL, &mut offset);
+                }
+
+                if subencoding_mask & HextileSubEncoding::ANY_SUBRECTS != 0 {
+                    let subrectangle_count = take(1, &mut offset)[0];
+                    for _ in 0..subrectangle_count {
+                        let pixel = if subencoding_mask & HextileSubEncoding::SUBRECTS_COLOURED != 0 {
+                            take(BYTES_PER_PIXEL, &mut offset)
+                        } else {
+                            foreground.clone()
+                        };
+
+                        let position_size = take(2, &mut offset);
+                        fill(
+                            (position_size[0] >> 4) as usize,
+                            (position_size[0] & 15) as usize,
+                            (position_size[1] >> 4) as usize + 1,
+                            (position_size[1] & 15) as usize + 1,
+                            &pixel
+                        );
+                    }
+                }
+            }
+        }
+
+        assert_eq!(offset, hextiles.len());
+        pixels
+    }
+
+    #[test]
+    fn hextile_round_trip() {
+        /* Not a multiple of 16, so the Edge Tiles are smaller */
+        let pixels = test_pixels(70, 61);
+        let rectangle = get_pixel_data(framebuffer(70, 61, pixels.clone()));
+
+        assert_eq!(rectangle.encoding_type, RFBEncodingType::HEX_TILE);
+        assert!(rectangle.encoded_pixels.len() < pixels.len());
+        assert_eq!(decode(&rectangle.encoded_pixels, 70, 61), pixels);
+    }
+
+    #[test]
+    fn repeated_background_is_not_resent() {
+        let pixels = [255, 255, 255, 0].repeat(48 * 16);
+        let rectangle = get_pixel_data(framebuffer(48, 16, pixels));
+
+        /* Only the first Tile specifies its Background */
+        assert_eq!(rectangle.encoded_pixels, vec![
+            HextileSubEncoding::BACKGROUND_SPECIFIED, 255, 255, 255, 0,
+            0,
+            0
+        ]);
+    }
+
+    #[test]
+    fn empty_rectangle_is_raw() {
+        let rectangle = get_pixel_data(framebuffer(0, 10, vec![]));
+        assert_eq!(rectangle.encoding_type, RFBEncodingType::RAW);
+        assert!(rectangle.encoded_pixels.is_empty());
+    }
+}
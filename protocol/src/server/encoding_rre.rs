@@ -0,0 +1,289 @@
+/*
+    SpifyRFB - Modern RFB Server implementation using Rust
+    Copyright (C) 2023  Atheesh Thirumalairajan
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::collections::HashMap;
+use super::{
+    FrameBuffer, FrameBufferRectangle, RFBEncodingType, encoding_raw,
+    incremental::{self, DamagedRectangle}
+};
+
+/* CoRRE positions and sizes are single bytes */
+const CORRE_MAX_SIZE: u16 = 255;
+
+pub struct SubRectangle {
+    pub(crate) pixel: usize, /* INDEX OF THE PIXEL IN THE RECTANGLE */
+    pub(crate) x_position: u16,
+    pub(crate) y_position: u16,
+    pub(crate) width: u16,
+    pub(crate) height: u16,
+}
+
+pub fn background_pixel(pixels: &[u8], bytes_per_pixel: usize) -> usize {
+    /* The most frequent Pixel makes the best Background */
+    let mut pixel_count: HashMap<&[u8], (usize, usize)> = HashMap::new();
+    for (index, pixel) in pixels.chunks_exact(bytes_per_pixel).enumerate() {
+        pixel_count.entry(pixel).or_insert((index, 0)).1 += 1;
+    }
+
+    pixel_count
+        .values()
+        .max_by_key(|count| count.1)
+        .map(|count| count.0)
+        .unwrap_or(0)
+}
+
+pub fn find_subrectangles(
+    pixels: &[u8],
+    width: usize,
+    height: usize,
+    bytes_per_pixel: usize,
+    background: usize
+) -> Vec<SubRectangle> {
+    let pixel_at = |x: usize, y: usize| &pixels[((y * width + x) * bytes_per_pixel)..((y * width + x + 1) * bytes_per_pixel)];
+    let background = &pixels[(background * bytes_per_pixel)..((background + 1) * bytes_per_pixel)];
+    let mut covered: Vec<bool> = vec![false; width * height];
+    let mut subrectangles: Vec<SubRectangle> = vec![];
+
+    for y in 0..height {
+        for x in 0..width {
+            if covered[y * width + x] || pixel_at(x, y) == background {
+                continue;
+            }
+
+            /* Grow Right, then Down while the whole row matches */
+            let color = pixel_at(x, y);
+            let mut x_end = x + 1;
+            while x_end < width && !covered[y * width + x_end] && pixel_at(x_end, y) == color {
+                x_end += 1;
+            }
+
+            let mut y_end = y + 1;
+            while y_end < height && (x..x_end).all(|column| {
+                !covered[y_end * width + column] && pixel_at(column, y_end) == color
+            }) {
+                y_end += 1;
+            }
+
+            for line in y..y_end {
+                for column in x..x_end {
+                    covered[line * width + column] = true;
+                }
+            }
+
+            subrectangles.push(SubRectangle {
+                pixel: y * width + x,
+                x_position: x as u16,
+                y_position: y as u16,
+                width: (x_end - x) as u16,
+                height: (y_end - y) as u16,
+            });
+        }
+    }
+
+    subrectangles
+}
+
+fn encode(framebuffer: &FrameBuffer, compact: bool) -> Vec<u8> {
+    let bytes_per_pixel = (framebuffer.bits_per_pixel / 8) as usize;
+    let pixels = &framebuffer.raw_pixels;
+    let background = background_pixel(pixels, bytes_per_pixel);
+    let subrectangles = find_subrectangles(
+        pixels,
+        framebuffer.width as usize,
+        framebuffer.height as usize,
+        bytes_per_pixel,
+        background
+    );
+
+    /* Header: number-of-subrectangles (U32), background-pixel-value */
+    let mut rre_data: Vec<u8> = vec![];
+    rre_data.extend_from_slice(&(subrectangles.len() as u32).to_be_bytes());
+    rre_data.extend_from_slice(&pixels[(background * bytes_per_pixel)..((background + 1) * bytes_per_pixel)]);
+
+    for subrectangle in subrectangles {
+        rre_data.extend_from_slice(&pixels[(subrectangle.pixel * bytes_per_pixel)..((subrectangle.pixel + 1) * bytes_per_pixel)]);
+        if compact {
+            /* CoRRE: U8 Positions and Sizes */
+            rre_data.extend_from_slice(&[
+                subrectangle.x_position as u8,
+                subrectangle.y_position as u8,
+                subrectangle.width as u8,
+                subrectangle.height as u8
+            ]);
+        } else {
+            rre_data.extend_from_slice(&subrectangle.x_position.to_be_bytes());
+            rre_data.extend_from_slice(&subrectangle.y_position.to_be_bytes());
+            rre_data.extend_from_slice(&subrectangle.width.to_be_bytes());
+            rre_data.extend_from_slice(&subrectangle.height.to_be_bytes());
+        }
+    }
+
+    rre_data
+}
+
+fn get_rectangle(framebuffer: FrameBuffer, encoding_type: i32) -> FrameBufferRectangle {
+    if framebuffer.width == 0 || framebuffer.height == 0 {
+        return encoding_raw::get_pixel_data(framebuffer);
+    }
+
+    let rre_data = encode(&framebuffer, encoding_type == RFBEncodingType::CORRE);
+    if rre_data.len() >= framebuffer.raw_pixels.len() {
+        /* Noisy content, RAW is smaller */
+        return encoding_raw::get_pixel_data(framebuffer);
+    }
+
+    FrameBufferRectangle {
+        x_position: framebuffer.x_position,
+        y_position: framebuffer.y_position,
+        width: framebuffer.width,
+        height: framebuffer.height,
+        encoding_type,
+        encoded_pixels: rre_data,
+        encoded_pixels_length: 0,
+    }
+}
+
+pub fn get_pixel_data(framebuffer: FrameBuffer) -> FrameBufferRectangle {
+    get_rectangle(framebuffer, RFBEncodingType::RRE)
+}
+
+pub fn get_corre_pixel_data(framebuffer: FrameBuffer) -> Vec<FrameBufferRectangle> {
+    let mut framebuffer_rectangles: Vec<FrameBufferRectangle> = vec![];
+
+    /* Split into Rectangles of at most 255x255 pixels */
+    for y_offset in (0..framebuffer.height).step_by(CORRE_MAX_SIZE as usize) {
+        for x_offset in (0..framebuffer.width).step_by(CORRE_MAX_SIZE as usize) {
+            let corre_rectangle = incremental::crop(&framebuffer, DamagedRectangle {
+                x_position: framebuffer.x_position + x_offset,
+                y_position: framebuffer.y_position + y_offset,
+                width: CORRE_MAX_SIZE.min(framebuffer.width - x_offset),
+                height: CORRE_MAX_SIZE.min(framebuffer.height - y_offset),
+                copy_source: Option::None,
+            });
+
+            framebuffer_rectangles.push(get_rectangle(corre_rectangle, RFBEncodingType::CORRE));
+        }
+    }
+
+    framebuffer_rectangles
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::test_support::{framebuffer, noise_pixels};
+
+    const BYTES_PER_PIXEL: usize = 4;
+
+    fn window_pixels(width: usize, height: usize) -> Vec<u8> {
+        /* Grey Desktop with a Window, a Title Bar and a Button */
+        let mut pixels: Vec<u8> = vec![];
+        for y in 0..height {
+            for x in 0..width {
+                let pixel: [u8; 4] = match (x, y) {
+                    (40..=49, 12..=15) => [0, 0, 255, 0],
+                    (10..=69, 10..=17) => [255, 0, 0, 0],
+                    (10..=69, 18..=39) => [255, 255, 255, 0],
+                    _ => [128, 128, 128, 0],
+                };
+                pixels.extend_from_slice(&pixel);
+            }
+        }
+
+        pixels
+    }
+
+    fn decode(rre_data: &[u8], width: usize, height: usize, compact: bool) -> Vec<u8> {
+        let read_u16 = |offset: usize| u16::from_be_bytes([rre_data[offset], rre_data[offset + 1]]) as usize;
+        let subrectangle_count = u32::from_be_bytes(rre_data[0..4].try_into().unwrap()) as usize;
+        let background = &rre_data[4..(4 + BYTES_PER_PIXEL)];
+        let mut pixels = background.repeat(width * height);
+
+        let mut offset = 4 + BYTES_PER_PIXEL;
+        for _ in 0..subrectangle_count {
+            let pixel = &rre_data[offset..(offset + BYTES_PER_PIXEL)];
+            offset += BYTES_PER_PIXEL;
+            let (x, y, subrectangle_width, subrectangle_height) = if compact {
+                offset += 4;
+                (rre_data[offset - 4] as usize, rre_data[offset - 3] as usize, rre_data[offset - 2] as usize, rre_data[offset - 1] as usize)
+            } else {
+                offset += 8;
+                (read_u16(offset - 8), read_u16(offset - 6), read_u16(offset - 4), read_u16(offset - 2))
+            };
+
+            for line in y..(y + subrectangle_height) {
+                for column in x..(x + subrectangle_width) {
+                    let start = (line * width + column) * BYTES_PER_PIXEL;
+                    pixels[start..(start + BYTES_PER_PIXEL)].copy_from_slice(pixel);
+                }
+            }
+        }
+
+        assert_eq!(offset, rre_data.len());
+        pixels
+    }
+
+    #[test]
+    fn rre_round_trip() {
+        let pixels = window_pixels(80, 50);
+        let rectangle = get_pixel_data(framebuffer(80, 50, pixels.clone()));
+
+        assert_eq!(rectangle.encoding_type, RFBEncodingType::RRE);
+        assert!(rectangle.encoded_pixels.len() < pixels.len());
+        assert_eq!(decode(&rectangle.encoded_pixels, 80, 50, false), pixels);
+    }
+
+    #[test]
+    fn corre_splits_and_round_trips() {
+        let pixels = window_pixels(300, 40);
+        let rectangles = get_corre_pixel_data(framebuffer(300, 40, pixels.clone()));
+
+        assert_eq!(rectangles.len(), 2);
+        assert_eq!((rectangles[1].x_position, rectangles[1].width), (255, 45));
+        for rectangle in rectangles {
+            assert_eq!(rectangle.encoding_type, RFBEncodingType::CORRE);
+            let decoded = decode(&rectangle.encoded_pixels, rectangle.width as usize, rectangle.height as usize, true);
+            for (line, row) in decoded.chunks_exact(rectangle.width as usize * BYTES_PER_PIXEL).enumerate() {
+                let start = (line * 300 + rectangle.x_position as usize) * BYTES_PER_PIXEL;
+                assert_eq!(row, &pixels[start..(start + row.len())]);
+            }
+        }
+    }
+
+    #[test]
+    fn noise_falls_back_to_raw() {
+        let pixels = noise_pixels(32 * 32 * BYTES_PER_PIXEL, 0x12345678);
+        let rectangle = get_pixel_data(framebuffer(32, 32, pixels.clone()));
+
+        assert_eq!(rectangle.encoding_type, RFBEncodingType::RAW);
+        assert_eq!(rectangle.encoded_pixels, pixels);
+    }
+
+    #[test]
+    fn subrectangles_cover_every_foreground_pixel() {
+        let pixels = window_pixels(80, 50);
+        let background = background_pixel(&pixels, BYTES_PER_PIXEL);
+        assert_eq!(pixels[(background * BYTES_PER_PIXEL)..((background + 1) * BYTES_PER_PIXEL)], [128, 128, 128, 0]);
+
+        /* Title Bar is split into four around the Button, plus the Button and the Window body */
+        let subrectangles = find_subrectangles(&pixels, 80, 50, BYTES_PER_PIXEL, background);
+        assert_eq!(subrectangles.len(), 6);
+        let covered: usize = subrectangles.iter().map(|subrectangle| subrectangle.width as usize * subrectangle.height as usize).sum();
+        assert_eq!(covered, 60 * 30);
+    }
+}
@@ -0,0 +1,466 @@
+/*
+    SpifyRFB - Modern RFB Server implementation using Rust
+    Copyright (C) 2023  Atheesh Thirumalairajan
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::collections::HashMap;
+use jpeg_encoder::{ColorType, Encoder};
+use super::{FrameBuffer, FrameBufferRectangle, RFBEncodingType, encoding_raw, encoding_zlib, incremental::{self, DamagedRectangle}};
+
+/* Decoders only accept Rectangles up to this size */
+const TIGHT_MAX_WIDTH: usize = 2048;
+const TIGHT_MAX_AREA: usize = 65536;
+
+/* Data shorter than this is sent without zlib */
+const TIGHT_MIN_TO_COMPRESS: usize = 12;
+
+/* Smaller Rectangles don't win anything from JPEG */
+const TIGHT_MIN_JPEG_AREA: usize = 1024;
+
+/* Mean Gradient error (per component) below which an Image counts as smooth */
+const TIGHT_SMOOTH_THRESHOLD: usize = 16;
+
+/* JPEG Quality for each QualityLevel (0-9) */
+const TIGHT_JPEG_QUALITY: [u8; 10] = [15, 29, 41, 42, 62, 77, 79, 86, 92, 100];
+
+struct TightCompression;
+impl TightCompression {
+    const FILL: u8 = 0x80;
+    const JPEG: u8 = 0x90;
+    const PNG: u8 = 0xA0; /* TIGHTPNG ONLY */
+    const EXPLICIT_FILTER: u8 = 0x40;
+}
+
+struct TightFilter;
+impl TightFilter {
+    const PALETTE: u8 = 1;
+    const GRADIENT: u8 = 2;
+}
+
+/* Each Client has four zlib Streams, one per kind of data */
+struct TightStream;
+impl TightStream {
+    const FULL_COLOUR: u8 = 0;
+    const MONO: u8 = 1;
+    const INDEXED: u8 = 2;
+    const GRADIENT: u8 = 3;
+}
+
+pub fn bytes_per_tpixel(framebuffer: &FrameBuffer) -> usize {
+    /* TPIXEL is R, G, B for 24 bit depth, otherwise the whole Pixel */
+    let pixel_count = framebuffer.width as usize * framebuffer.height as usize;
+    framebuffer.encoded_pixels.len().checked_div(pixel_count).unwrap_or(3)
+}
+
+fn stream_name(stream_id: &str, tight_stream: u8) -> String {
+    format!("{}-tight{}", stream_id, tight_stream)
+}
+
+pub fn flush_streams(stream_id: String) {
+    for tight_stream in 0..4 {
+        encoding_zlib::flush_stream(stream_name(&stream_id, tight_stream));
+    }
+}
+
+fn write_compact_length(tight_data: &mut Vec<u8>, length: usize) {
+    /* 7 bits per byte, high bit set if another byte follows */
+    if length < 0x80 {
+        tight_data.push(length as u8);
+    } else if length < 0x4000 {
+        tight_data.extend_from_slice(&[(length as u8 & 0x7f) | 0x80, (length >> 7) as u8]);
+    } else {
+        tight_data.extend_from_slice(&[
+            (length as u8 & 0x7f) | 0x80,
+            ((length >> 7) as u8 & 0x7f) | 0x80,
+            (length >> 14) as u8
+        ]);
+    }
+}
+
+fn write_compressed(
+    tight_data: &mut Vec<u8>,
+    pixel_data: &[u8],
+    stream_id: &str,
+    tight_stream: u8,
+    compress_level: i32
+) {
+    if pixel_data.len() < TIGHT_MIN_TO_COMPRESS {
+        tight_data.extend_from_slice(pixel_data);
+        return;
+    }
+
+    let compressed_data = encoding_zlib::compress(
+        stream_name(stream_id, tight_stream),
+        pixel_data,
+        compress_level
+    ).unwrap_or_default();
+
+    write_compact_length(tight_data, compressed_data.len());
+    tight_data.extend(compressed_data);
+}
+
+/* Colours in order of appearance, and the index of each Colour */
+type TightPalette<'a> = (Vec<&'a [u8]>, HashMap<&'a [u8], u8>);
+
+fn find_palette(tpixels: &[u8], bytes_per_tpixel: usize, max_colours: usize) -> Option<TightPalette<'_>> {
+    let mut palette: Vec<&[u8]> = vec![];
+    let mut palette_index: HashMap<&[u8], u8> = HashMap::new();
+
+    for tpixel in tpixels.chunks_exact(bytes_per_tpixel) {
+        if !palette_index.contains_key(tpixel) {
+            if palette.len() == max_colours {
+                return Option::None;
+            }
+
+            palette_index.insert(tpixel, palette.len() as u8);
+            palette.push(tpixel);
+        }
+    }
+
+    Option::Some((palette, palette_index))
+}
+
+fn gradient_filter(tpixels: &[u8], width: usize) -> Vec<u8> {
+    /* Send each component as the error of V[x-1] + V[y-1] - V[x-1, y-1] */
+    let stride = width * 3;
+    let mut filtered: Vec<u8> = Vec::with_capacity(tpixels.len());
+
+    for (index, component) in tpixels.iter().enumerate() {
+        let left = if index % stride >= 3 { tpixels[index - 3] as i32 } else { 0 };
+        let above = if index >= stride { tpixels[index - stride] as i32 } else { 0 };
+        let above_left = if index % stride >= 3 && index >= stride { tpixels[index - stride - 3] as i32 } else { 0 };
+        let predicted = (left + above - above_left).clamp(0, 255);
+        filtered.push((*component as i32 - predicted) as u8);
+    }
+
+    filtered
+}
+
+fn is_smooth(filtered: &[u8]) -> bool {
+    let error: usize = filtered
+        .iter()
+        .map(|residual| (*residual as i8).unsigned_abs() as usize)
+        .sum();
+
+    error / filtered.len().max(1) < TIGHT_SMOOTH_THRESHOLD
+}
+
+fn encode_jpeg(tpixels: &[u8], width: usize, height: usize, quality_level: i32) -> Option<Vec<u8>> {
+    let mut jpeg_data: Vec<u8> = vec![];
+    let jpeg_encoder = Encoder::new(&mut jpeg_data, TIGHT_JPEG_QUALITY[quality_level as usize]);
+    jpeg_encoder.encode(tpixels, width as u16, height as u16, ColorType::Rgb).ok()?;
+    Option::Some(jpeg_data)
+}
+
+fn encode(
+    tpixels: &[u8],
+    width: usize,
+    height: usize,
+    bytes_per_tpixel: usize,
+    stream_id: &str,
+    compress_level: i32,
+    quality_level: Option<i32>
+) -> Vec<u8> {
+    let area = width * height;
+    let max_colours = (area / 16).clamp(2, 256);
+    let mut tight_data: Vec<u8> = vec![];
+
+    if let Some((palette, palette_index)) = find_palette(tpixels, bytes_per_tpixel, max_colours) {
+        if palette.len() == 1 {
+            tight_data.push(TightCompression::FILL);
+            tight_data.extend_from_slice(palette[0]);
+            return tight_data;
+        }
+
+        let tight_stream = if palette.len() == 2 { TightStream::MONO } else { TightStream::INDEXED };
+        tight_data.push((tight_stream << 4) | TightCompression::EXPLICIT_FILTER);
+        tight_data.push(TightFilter::PALETTE);
+        tight_data.push((palette.len() - 1) as u8);
+        for colour in &palette {
+            tight_data.extend_from_slice(colour);
+        }
+
+        let mut pixel_data: Vec<u8> = vec![];
+        if palette.len() == 2 {
+            /* One bit per Pixel, each Row starts on a Byte boundary */
+            for row in tpixels.chunks_exact(width * bytes_per_tpixel) {
+                for byte_pixels in row.chunks(8 * bytes_per_tpixel) {
+                    let mut packed_byte: u8 = 0;
+                    for (bit, tpixel) in byte_pixels.chunks_exact(bytes_per_tpixel).enumerate() {
+                        packed_byte |= palette_index[tpixel] << (7 - bit);
+                    }
+
+                    pixel_data.push(packed_byte);
+                }
+            }
+        } else {
+            pixel_data = tpixels
+                .chunks_exact(bytes_per_tpixel)
+                .map(|tpixel| palette_index[tpixel])
+                .collect();
+        }
+
+        write_compressed(&mut tight_data, &pixel_data, stream_id, tight_stream, compress_level);
+        return tight_data;
+    }
+
+    /* Photographic content, JPEG and the Gradient Filter need 24 bit colour */
+    if bytes_per_tpixel == 3 {
+        if let Some(quality_level) = quality_level {
+            if area >= TIGHT_MIN_JPEG_AREA {
+                if let Some(jpeg_data) = encode_jpeg(tpixels, width, height, quality_level) {
+                    tight_data.push(TightCompression::JPEG);
+                    write_compact_length(&mut tight_data, jpeg_data.len());
+                    tight_data.extend(jpeg_data);
+                    return tight_data;
+                }
+            }
+        }
+
+        let filtered = gradient_filter(tpixels, width);
+        if is_smooth(&filtered) {
+            tight_data.push((TightStream::GRADIENT << 4) | TightCompression::EXPLICIT_FILTER);
+            tight_data.push(TightFilter::GRADIENT);
+            write_compressed(&mut tight_data, &filtered, stream_id, TightStream::GRADIENT, compress_level);
+            return tight_data;
+        }
+    }
+
+    tight_data.push(TightStream::FULL_COLOUR << 4);
+    write_compressed(&mut tight_data, tpixels, stream_id, TightStream::FULL_COLOUR, compress_level);
+    tight_data
+}
+
+fn write_png(tpixels: &[u8], width: usize, height: usize, compress_level: i32) -> Result<Vec<u8>, png::EncodingError> {
+    let mut png_data: Vec<u8> = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png_data, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_compression(match compress_level {
+        0..=3 => png::Compression::Fast,
+        4..=6 => png::Compression::Default,
+        _ => png::Compression::Best
+    });
+
+    let mut png_writer = encoder.write_header()?;
+    png_writer.write_image_data(tpixels)?;
+    png_writer.finish()?;
+    Ok(png_data)
+}
+
+fn encode_png(tpixels: &[u8], width: usize, height: usize, compress_level: i32, quality_level: Option<i32>) -> Option<Vec<u8>> {
+    let area = width * height;
+    let max_colours = (area / 16).clamp(2, 256);
+    let mut tight_data: Vec<u8> = vec![];
+
+    /* TightPNG has Fill, JPEG and PNG but no basic (zlib) compression */
+    let palette = find_palette(tpixels, 3, max_colours);
+    if let Some((palette, _)) = &palette {
+        if palette.len() == 1 {
+            tight_data.push(TightCompression::FILL);
+            tight_data.extend_from_slice(palette[0]);
+            return Option::Some(tight_data);
+        }
+    }
+
+    if let Some(quality_level) = quality_level {
+        if palette.is_none() && area >= TIGHT_MIN_JPEG_AREA {
+            if let Some(jpeg_data) = encode_jpeg(tpixels, width, height, quality_level) {
+                tight_data.push(TightCompression::JPEG);
+                write_compact_length(&mut tight_data, jpeg_data.len());
+                tight_data.extend(jpeg_data);
+                return Option::Some(tight_data);
+            }
+        }
+    }
+
+    let png_data = match write_png(tpixels, width, height, compress_level) {
+        Ok(png_data) => png_data,
+        Err(png_error) => {
+            println!("TightPNG: PNG Encoding failed (RAW Sent). Error: {}", png_error);
+            return Option::None;
+        }
+    };
+
+    tight_data.push(TightCompression::PNG);
+    write_compact_length(&mut tight_data, png_data.len());
+    tight_data.extend(png_data);
+    Option::Some(tight_data)
+}
+
+fn split_rectangles(
+    framebuffer: &FrameBuffer,
+    encoding_type: i32,
+    bytes_per_tpixel: usize,
+    encode_rectangle: impl Fn(&[u8], usize, usize) -> Option<Vec<u8>>
+) -> Vec<FrameBufferRectangle> {
+    let width = framebuffer.width as usize;
+    let height = framebuffer.height as usize;
+    let stride = width * bytes_per_tpixel;
+    let mut framebuffer_rectangles: Vec<FrameBufferRectangle> = vec![];
+
+    /* Split into Rectangles the Client is able to decode */
+    let chunk_width = width.clamp(1, TIGHT_MAX_WIDTH);
+    let chunk_height = TIGHT_MAX_AREA / chunk_width;
+
+    for y_offset in (0..height).step_by(chunk_height) {
+        let tight_height = chunk_height.min(height - y_offset);
+        for x_offset in (0..width).step_by(chunk_width) {
+            let tight_width = chunk_width.min(width - x_offset);
+            let mut tpixels: Vec<u8> = Vec::with_capacity(tight_width * tight_height * bytes_per_tpixel);
+
+            for line in y_offset..(y_offset + tight_height) {
+                let start = line * stride + x_offset * bytes_per_tpixel;
+                tpixels.extend_from_slice(&framebuffer.encoded_pixels[start..(start + tight_width * bytes_per_tpixel)]);
+            }
+
+            let tight_rectangle = DamagedRectangle {
+                x_position: framebuffer.x_position + x_offset as u16,
+                y_position: framebuffer.y_position + y_offset as u16,
+                width: tight_width as u16,
+                height: tight_height as u16,
+                copy_source: Option::None,
+            };
+
+            match encode_rectangle(&tpixels, tight_width, tight_height) {
+                Some(encoded_pixels) => framebuffer_rectangles.push(FrameBufferRectangle {
+                    x_position: tight_rectangle.x_position,
+                    y_position: tight_rectangle.y_position,
+                    width: tight_rectangle.width,
+                    height: tight_rectangle.height,
+                    encoding_type,
+                    encoded_pixels,
+                    encoded_pixels_length: 0,
+                }),
+                None => {
+                    /* The Encoder failed, every Client decodes RAW */
+                    framebuffer_rectangles.push(encoding_raw::get_pixel_data(incremental::crop(framebuffer, tight_rectangle)));
+                }
+            }
+        }
+    }
+
+    framebuffer_rectangles
+}
+
+pub fn get_pixel_data(
+    framebuffer: FrameBuffer,
+    stream_id: String,
+    compress_level: Option<i32>,
+    quality_level: Option<i32>
+) -> Vec<FrameBufferRectangle> {
+    /* encoded_pixels holds the TPIXEL data */
+    let bytes_per_tpixel = bytes_per_tpixel(&framebuffer);
+    let compress_level = compress_level.unwrap_or(encoding_zlib::ZLIB_COMPRESS_LEVEL);
+
+    split_rectangles(&framebuffer, RFBEncodingType::TIGHT, bytes_per_tpixel, |tpixels, width, height| {
+        Option::Some(encode(tpixels, width, height, bytes_per_tpixel, &stream_id, compress_level, quality_level))
+    })
+}
+
+pub fn get_png_pixel_data(
+    framebuffer: FrameBuffer,
+    compress_level: Option<i32>,
+    quality_level: Option<i32>
+) -> Vec<FrameBufferRectangle> {
+    /* encoded_pixels holds R, G, B TPIXELs, PNG has no other 8 bit layout */
+    let compress_level = compress_level.unwrap_or(encoding_zlib::ZLIB_COMPRESS_LEVEL);
+
+    split_rectangles(&framebuffer, RFBEncodingType::TIGHT_PNG, 3, |tpixels, width, height| {
+        encode_png(tpixels, width, height, compress_level, quality_level)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::test_support::{framebuffer, noise_pixels};
+
+    fn compact_length(length: usize) -> Vec<u8> {
+        let mut tight_data: Vec<u8> = vec![];
+        write_compact_length(&mut tight_data, length);
+        tight_data
+    }
+
+    #[test]
+    fn compact_length_forms() {
+        assert_eq!(compact_length(0), vec![0x00]);
+        assert_eq!(compact_length(0x7f), vec![0x7f]);
+        assert_eq!(compact_length(0x80), vec![0x80, 0x01]);
+        assert_eq!(compact_length(0x3fff), vec![0xff, 0x7f]);
+        assert_eq!(compact_length(0x4000), vec![0x80, 0x80, 0x01]);
+        assert_eq!(compact_length(4194303), vec![0xff, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn palette_in_order_of_appearance() {
+        let tpixels = [[9, 9, 9], [1, 1, 1], [9, 9, 9], [5, 5, 5]].concat();
+        let (palette, palette_index) = find_palette(&tpixels, 3, 4).unwrap();
+        assert_eq!(palette, vec![&[9, 9, 9][..], &[1, 1, 1][..], &[5, 5, 5][..]]);
+        assert_eq!(palette_index[&[5, 5, 5][..]], 2);
+
+        /* More Colours than allowed, no Palette */
+        assert!(find_palette(&tpixels, 3, 2).is_none());
+    }
+
+    #[test]
+    fn picks_fill_and_palette_subencodings() {
+        let solid = [7, 8, 9].repeat(64);
+        assert_eq!(encode(&solid, 8, 8, 3, "tight-fill", 6, Option::None), vec![TightCompression::FILL, 7, 8, 9]);
+
+        /* Two Colours are one bit per Pixel, 8 wide is one byte per Row */
+        let two_colours: Vec<u8> = (0..64).flat_map(|index| if index % 3 == 0 { [0, 0, 0] } else { [255, 0, 0] }).collect();
+        let tight_data = encode(&two_colours, 8, 8, 3, "tight-mono", 6, Option::None);
+        assert_eq!(tight_data[..3], [(TightStream::MONO << 4) | TightCompression::EXPLICIT_FILTER, TightFilter::PALETTE, 1]);
+        assert_eq!(tight_data[3..9], [0, 0, 0, 255, 0, 0]);
+
+        let three_colours: Vec<u8> = (0..64).flat_map(|index| [(index % 3) as u8 * 100; 3]).collect();
+        let tight_data = encode(&three_colours, 8, 8, 3, "tight-indexed", 6, Option::None);
+        assert_eq!(tight_data[..3], [(TightStream::INDEXED << 4) | TightCompression::EXPLICIT_FILTER, TightFilter::PALETTE, 2]);
+
+        flush_streams("tight-mono".to_string());
+        flush_streams("tight-indexed".to_string());
+    }
+
+    #[test]
+    fn gradient_threshold() {
+        /* Smooth Ramp, every Residual after the first Row and Column is zero */
+        let ramp: Vec<u8> = (0..32 * 32).flat_map(|index| [(index % 32 * 4) as u8, (index / 32 * 4) as u8, 128]).collect();
+        assert!(is_smooth(&gradient_filter(&ramp, 32)));
+
+        /* Mean Error right at the Threshold is not smooth */
+        assert!(is_smooth(&[TIGHT_SMOOTH_THRESHOLD as u8 - 1; 30]));
+        assert!(!is_smooth(&[TIGHT_SMOOTH_THRESHOLD as u8; 30]));
+        assert!(!is_smooth(&gradient_filter(&noise_pixels(32 * 32 * 3, 7), 32)));
+    }
+
+    #[test]
+    fn png_failure_falls_back_to_raw() {
+        /* Fewer Pixels than the Header promises */
+        assert!(encode_png(&noise_pixels(100, 8), 64, 64, 6, Option::None).is_none());
+
+        let raw_pixels = noise_pixels(40 * 30 * 4, 9);
+        let raw_framebuffer = FrameBuffer {
+            encoded_pixels: noise_pixels(40 * 30 * 3, 9),
+            ..framebuffer(40, 30, raw_pixels.clone())
+        };
+
+        let rectangles = split_rectangles(&raw_framebuffer, RFBEncodingType::TIGHT_PNG, 3, |_, _, _| Option::None);
+        assert_eq!(rectangles.len(), 1);
+        assert_eq!(rectangles[0].encoding_type, RFBEncodingType::RAW);
+        assert_eq!(rectangles[0].encoded_pixels, raw_pixels);
+    }
+}
@@ -0,0 +1,318 @@
+/*
+    SpifyRFB - Modern RFB Server implementation using Rust
+    Copyright (C) 2023  Atheesh Thirumalairajan
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::collections::HashMap;
+use super::{FrameBuffer, FrameBufferRectangle, RFBEncodingType};
+
+pub(crate) const TRLE_TILE_SIZE: usize = 16;
+
+/* Palette RLE sub-encodings (130-255) index at most 127 colours */
+const MAX_PALETTE_SIZE: usize = 127;
+const MAX_PACKED_PALETTE_SIZE: usize = 16;
+
+struct TileAnalysis {
+    palette: Vec<u32>,
+    palette_index: HashMap<u32, u8>,
+    runs: Vec<(u32, usize)>, /* (CPIXEL, RUN LENGTH) */
+}
+
+pub fn bytes_per_cpixel(framebuffer: &FrameBuffer) -> usize {
+    /* CPIXEL drops the unused byte of 32 bit pixels, when the PixelFormat allows it */
+    let pixel_count = framebuffer.width as usize * framebuffer.height as usize;
+    framebuffer.encoded_pixels.len().checked_div(pixel_count).unwrap_or(3)
+}
+
+fn cpixel_value(cpixel: &[u8]) -> u32 {
+    cpixel.iter().fold(0_u32, |value, byte| (value << 8) | *byte as u32)
+}
+
+fn write_cpixel(tile_data: &mut Vec<u8>, value: u32, bytes_per_cpixel: usize) {
+    tile_data.extend_from_slice(&value.to_be_bytes()[(4 - bytes_per_cpixel)..]);
+}
+
+fn write_run_length(tile_data: &mut Vec<u8>, run_length: usize) {
+    /* Run Length minus one, as a sum of bytes ending with one below 255 */
+    let mut remaining = run_length - 1;
+    while remaining >= 255 {
+        tile_data.push(255);
+        remaining -= 255;
+    }
+
+    tile_data.push(remaining as u8);
+}
+
+fn run_length_size(run_length: usize) -> usize {
+    (run_length - 1) / 255 + 1
+}
+
+fn analyze_tile(tile_pixels: &[u8], bytes_per_cpixel: usize) -> TileAnalysis {
+    let mut analysis = TileAnalysis {
+        palette: vec![],
+        palette_index: HashMap::new(),
+        runs: vec![],
+    };
+
+    for cpixel in tile_pixels.chunks_exact(bytes_per_cpixel) {
+        let value = cpixel_value(cpixel);
+        match analysis.runs.last_mut() {
+            Some(run) if run.0 == value => run.1 += 1,
+            _ => analysis.runs.push((value, 1)),
+        }
+
+        /* Past 127 colours no palette sub-encoding applies */
+        if analysis.palette.len() <= MAX_PALETTE_SIZE && !analysis.palette_index.contains_key(&value) {
+            analysis.palette_index.insert(value, analysis.palette.len() as u8);
+            analysis.palette.push(value);
+        }
+    }
+
+    analysis
+}
+
+fn packed_bits(palette_size: usize) -> usize {
+    match palette_size {
+        2 => 1,
+        3..=4 => 2,
+        _ => 4
+    }
+}
+
+pub fn encode_tile(tile_pixels: &[u8], width: usize, height: usize, bytes_per_cpixel: usize) -> Vec<u8> {
+    let analysis = analyze_tile(tile_pixels, bytes_per_cpixel);
+    let palette_size = analysis.palette.len();
+    let mut tile_data: Vec<u8> = vec![];
+
+    if palette_size == 1 {
+        /* Solid Tile */
+        tile_data.push(1);
+        write_cpixel(&mut tile_data, analysis.palette[0], bytes_per_cpixel);
+        return tile_data;
+    }
+
+    /* Work out the size of every sub-encoding, pick the smallest */
+    let raw_size = width * height * bytes_per_cpixel;
+    let plain_rle_size: usize = analysis.runs
+        .iter()
+        .map(|run| bytes_per_cpixel + run_length_size(run.1))
+        .sum();
+
+    let mut packed_palette_size = usize::MAX;
+    let mut palette_rle_size = usize::MAX;
+    if palette_size <= MAX_PALETTE_SIZE {
+        if palette_size <= MAX_PACKED_PALETTE_SIZE {
+            let row_bytes = (width * packed_bits(palette_size)).div_ceil(8);
+            packed_palette_size = palette_size * bytes_per_cpixel + row_bytes * height;
+        }
+
+        palette_rle_size = palette_size * bytes_per_cpixel + analysis.runs
+            .iter()
+            .map(|run| if run.1 == 1 { 1 } else { 1 + run_length_size(run.1) })
+            .sum::<usize>();
+    }
+
+    let smallest = raw_size.min(plain_rle_size).min(packed_palette_size).min(palette_rle_size);
+    if smallest == raw_size {
+        tile_data.push(0);
+        tile_data.extend_from_slice(tile_pixels);
+    } else if smallest == packed_palette_size {
+        let bits = packed_bits(palette_size);
+        tile_data.push(palette_size as u8);
+        for colour in &analysis.palette {
+            write_cpixel(&mut tile_data, *colour, bytes_per_cpixel);
+        }
+
+        /* Each Row starts on a Byte boundary */
+        for row in tile_pixels.chunks_exact(width * bytes_per_cpixel) {
+            let mut packed_byte: u8 = 0;
+            let mut used_bits = 0;
+            for cpixel in row.chunks_exact(bytes_per_cpixel) {
+                let index = analysis.palette_index[&cpixel_value(cpixel)];
+                packed_byte |= index << (8 - bits - used_bits);
+                used_bits += bits;
+                if used_bits == 8 {
+                    tile_data.push(packed_byte);
+                    packed_byte = 0;
+                    used_bits = 0;
+                }
+            }
+
+            if used_bits > 0 {
+                tile_data.push(packed_byte);
+            }
+        }
+    } else if smallest == plain_rle_size {
+        tile_data.push(128);
+        for (value, run_length) in &analysis.runs {
+            write_cpixel(&mut tile_data, *value, bytes_per_cpixel);
+            write_run_length(&mut tile_data, *run_length);
+        }
+    } else {
+        tile_data.push(128 + palette_size as u8);
+        for colour in &analysis.palette {
+            write_cpixel(&mut tile_data, *colour, bytes_per_cpixel);
+        }
+
+        for (value, run_length) in &analysis.runs {
+            let index = analysis.palette_index[value];
+            if *run_length == 1 {
+                tile_data.push(index);
+            } else {
+                tile_data.push(index | 128);
+                write_run_length(&mut tile_data, *run_length);
+            }
+        }
+    }
+
+    tile_data
+}
+
+pub fn encode_tiles(framebuffer: &FrameBuffer, tile_size: usize) -> Vec<u8> {
+    let bytes_per_cpixel = bytes_per_cpixel(framebuffer);
+    let width = framebuffer.width as usize;
+    let height = framebuffer.height as usize;
+    let stride = width * bytes_per_cpixel;
+    let mut encoded_tiles: Vec<u8> = vec![];
+
+    /* Tiles go left-to-right, top-to-bottom. Edge Tiles are smaller */
+    for tile_y in (0..height).step_by(tile_size) {
+        let tile_height = tile_size.min(height - tile_y);
+        for tile_x in (0..width).step_by(tile_size) {
+            let tile_width = tile_size.min(width - tile_x);
+            let mut tile_pixels: Vec<u8> = Vec::with_capacity(tile_width * tile_height * bytes_per_cpixel);
+
+            for line in tile_y..(tile_y + tile_height) {
+                let start = line * stride + tile_x * bytes_per_cpixel;
+                tile_pixels.extend_from_slice(&framebuffer.encoded_pixels[start..(start + tile_width * bytes_per_cpixel)]);
+            }
+
+            encoded_tiles.extend(encode_tile(&tile_pixels, tile_width, tile_height, bytes_per_cpixel));
+        }
+    }
+
+    encoded_tiles
+}
+
+pub fn get_pixel_data(framebuffer: FrameBuffer) -> FrameBufferRectangle {
+    /* encoded_pixels holds the CPIXEL data */
+    FrameBufferRectangle {
+        x_position: framebuffer.x_position,
+        y_position: framebuffer.y_position,
+        width: framebuffer.width,
+        height: framebuffer.height,
+        encoding_type: RFBEncodingType::TRLE,
+        encoded_pixels: encode_tiles(&framebuffer, TRLE_TILE_SIZE),
+        encoded_pixels_length: 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::test_support::{decode_tile, decode_tiles, framebuffer, noise_pixels};
+
+    const BYTES_PER_CPIXEL: usize = 3;
+
+    fn cpixel_framebuffer(width: u16, height: u16, encoded_pixels: Vec<u8>) -> FrameBuffer {
+        FrameBuffer {
+            encoding: RFBEncodingType::TRLE,
+            encoded_pixels,
+            ..framebuffer(width, height, vec![])
+        }
+    }
+
+    fn test_cpixels(width: usize, height: usize) -> Vec<u8> {
+        /* Solid, striped, few-colour, many-colour and noisy Tiles */
+        let noise = noise_pixels(width * height * BYTES_PER_CPIXEL, 0x2545F491);
+        let mut cpixels: Vec<u8> = vec![];
+        for y in 0..height {
+            for x in 0..width {
+                let noise_start = (y * width + x) * BYTES_PER_CPIXEL;
+                let cpixel: [u8; 3] = match (x / 16, y / 16) {
+                    (0, 0) => [10, 20, 30],
+                    (1, 0) => if y % 2 == 0 { [255, 0, 0] } else { [0, 0, 255] },
+                    (2, 0) => [(x % 3) as u8 * 100, 0, (y % 2) as u8 * 200],
+                    (0, 1) => if x < 8 { [0, 0, 0] } else { [0, (y % 16) as u8 * 15, 0] },
+                    (1, 1) => [((x * y) % 90) as u8, 0, 0],
+                    _ => noise[noise_start..(noise_start + BYTES_PER_CPIXEL)].try_into().unwrap(),
+                };
+                cpixels.extend_from_slice(&cpixel);
+            }
+        }
+
+        cpixels
+    }
+
+    #[test]
+    fn trle_round_trip() {
+        /* Not a multiple of 16, so the Edge Tiles are smaller */
+        let cpixels = test_cpixels(61, 40);
+        let rectangle = get_pixel_data(cpixel_framebuffer(61, 40, cpixels.clone()));
+
+        assert_eq!(rectangle.encoding_type, RFBEncodingType::TRLE);
+        assert!(rectangle.encoded_pixels.len() < cpixels.len());
+        assert_eq!(decode_tiles(&rectangle.encoded_pixels, 61, 40, TRLE_TILE_SIZE, BYTES_PER_CPIXEL), cpixels);
+    }
+
+    #[test]
+    fn picks_the_smallest_subencoding() {
+        let solid = [1, 2, 3].repeat(16 * 16);
+        assert_eq!(encode_tile(&solid, 16, 16, BYTES_PER_CPIXEL), vec![1, 1, 2, 3]);
+
+        /* Alternating pixels pack into one bit each */
+        let checkered: Vec<u8> = (0..256).flat_map(|index| if index % 2 == 0 { [0, 0, 0] } else { [9, 9, 9] }).collect();
+        let tile_data = encode_tile(&checkered, 16, 16, BYTES_PER_CPIXEL);
+        assert_eq!(tile_data[0], 2);
+        assert_eq!(tile_data.len(), 1 + 2 * BYTES_PER_CPIXEL + 2 * 16);
+
+        let mut noise_offset = 0;
+        let noise = test_cpixels(48, 48)[(48 * 32 * BYTES_PER_CPIXEL)..].to_vec();
+        let noise_tile: Vec<u8> = noise.chunks_exact(48 * BYTES_PER_CPIXEL).flat_map(|row| row[..(16 * BYTES_PER_CPIXEL)].to_vec()).collect();
+        let tile_data = encode_tile(&noise_tile, 16, 16, BYTES_PER_CPIXEL);
+        assert_eq!(tile_data[0], 0);
+        assert_eq!(decode_tile(&tile_data, &mut noise_offset, 16, 16, BYTES_PER_CPIXEL), noise_tile);
+    }
+
+    #[test]
+    fn long_runs_round_trip() {
+        /* Runs past 255 pixels need more than one length byte */
+        let mut cpixels = [7, 7, 7].repeat(600);
+        cpixels.extend([8, 8, 8].repeat(424));
+        let tile_data = encode_tile(&cpixels, 32, 32, BYTES_PER_CPIXEL);
+
+        let mut offset = 0;
+        assert_eq!(tile_data[0], 128);
+        assert_eq!(decode_tile(&tile_data, &mut offset, 32, 32, BYTES_PER_CPIXEL), cpixels);
+        assert_eq!(offset, tile_data.len());
+
+        /* Many Runs of few Colours are cheaper with a Palette */
+        let cpixels: Vec<u8> = (0..1024).flat_map(|index| [(index / 16 % 3) as u8 * 50; 3]).collect();
+        let tile_data = encode_tile(&cpixels, 32, 32, BYTES_PER_CPIXEL);
+
+        let mut offset = 0;
+        assert_eq!(tile_data[0], 128 + 3);
+        assert_eq!(decode_tile(&tile_data, &mut offset, 32, 32, BYTES_PER_CPIXEL), cpixels);
+        assert_eq!(offset, tile_data.len());
+    }
+
+    #[test]
+    fn bytes_per_cpixel_follows_encoded_pixels() {
+        assert_eq!(bytes_per_cpixel(&cpixel_framebuffer(2, 2, vec![0; 12])), 3);
+        assert_eq!(bytes_per_cpixel(&cpixel_framebuffer(2, 2, vec![0; 16])), 4);
+        assert_eq!(bytes_per_cpixel(&cpixel_framebuffer(0, 0, vec![])), 3);
+    }
+}
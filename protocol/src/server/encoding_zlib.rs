@@ -21,60 +21,66 @@ use once_cell::sync::Lazy;
 
 use super::{FrameBufferRectangle, FrameBuffer, RFBEncodingType};
 
-static mut LIVE_ZSTREAMS: Lazy<RwLock<HashMap<String, libz_sys::z_stream>>>
+/* Default Compress Level for ZLIB and ZRLE */
+pub(crate) const ZLIB_COMPRESS_LEVEL: i32 = 5;
+
+/* Boxed, zlib keeps a pointer back to the z_stream. Each Stream remembers its Compress Level */
+pub(crate) type LiveZStream = (Box<libz_sys::z_stream>, i32);
+
+static mut LIVE_ZSTREAMS: Lazy<RwLock<HashMap<String, LiveZStream>>>
     = Lazy::new(|| { RwLock::new(HashMap::new()) });
 
+pub(crate) fn new_stream() -> LiveZStream {
+    unsafe {
+        (Box::new(libz_sys::z_stream {
+            next_in: ptr::null_mut(),
+            avail_in: 0,
+            total_in: 0,
+            next_out: ptr::null_mut(),
+            avail_out: 0,
+            total_out: 0,
+            msg: ptr::null::<u8>() as _,
+            state: ptr::null::<u8>() as _,
+            zalloc: mem::transmute(ptr::null::<u8>()),
+            zfree: mem::transmute(ptr::null::<u8>()),
+            opaque: ptr::null::<u8>() as _,
+            data_type: libz_sys::Z_BINARY,
+            adler: 0,
+            reserved: 0,
+        }), ZLIB_COMPRESS_LEVEL)
+    }
+}
+
 pub fn create_stream(stream_id: String) {
     unsafe {
         let mut zstreams_lock = LIVE_ZSTREAMS.write().unwrap();
-        zstreams_lock.insert(
-            stream_id,
-            libz_sys::z_stream {
-                next_in: ptr::null_mut(),
-                avail_in: 0,
-                total_in: 0,
-                next_out: ptr::null_mut(),
-                avail_out: 0,
-                total_out: 0,
-                msg: ptr::null::<u8>() as _,
-                state: ptr::null::<u8>() as _,
-                zalloc: mem::transmute(ptr::null::<u8>()),
-                zfree: mem::transmute(ptr::null::<u8>()),
-                opaque: ptr::null::<u8>() as _,
-                data_type: libz_sys::Z_BINARY,
-                adler: 0,
-                reserved: 0,
-            }
-        );
+        zstreams_lock.insert(stream_id, new_stream());
     }
 }
 
 pub fn flush_stream(stream_id: String) {
     unsafe {
         let mut zstreams_lock = LIVE_ZSTREAMS.write().unwrap();
-        zstreams_lock.remove(&stream_id);
+        if let Some((mut zlib_stream, _)) = zstreams_lock.remove(&stream_id) {
+            if zlib_stream.total_in > 0 {
+                libz_sys::deflateEnd(zlib_stream.as_mut());
+            }
+        }
     }
 }
 
-pub fn deflate(framebuffer: FrameBuffer, stream_id: String) -> FrameBufferRectangle {
-    let zlib_data = framebuffer.encoded_pixels;
+pub fn compress(stream_id: String, zlib_data: &[u8], compress_level: i32) -> Option<Vec<u8>> {
     let max_compressed = zlib_data.len() + ((zlib_data.len() + 99) / 100) + 12;
-    let mut next_in: Vec<u8> = zlib_data.clone();
+    let mut next_in: Vec<u8> = zlib_data.to_vec();
     let mut next_out: Vec<u8> = vec![0; max_compressed];
 
-    let mut framebuffer_rectangle = FrameBufferRectangle {
-        x_position: framebuffer.x_position,
-        y_position: framebuffer.y_position,
-        width: framebuffer.width,
-        height: framebuffer.height,
-        encoding_type: RFBEncodingType::RAW,
-        encoded_pixels: framebuffer.raw_pixels,
-        encoded_pixels_length: 0,
-    };
-
     unsafe {
         let mut zlibstream_lock = LIVE_ZSTREAMS.write().unwrap();
-        let zlib_stream = zlibstream_lock.get_mut(&stream_id).unwrap();
+        let (zlib_stream, stream_level) = zlibstream_lock
+            .entry(stream_id.clone())
+            .or_insert_with(new_stream);
+
+        let zlib_stream = zlib_stream.as_mut();
         zlib_stream.next_in = next_in.as_mut_ptr();
         zlib_stream.avail_in = next_in.len() as u32;
         zlib_stream.next_out = next_out.as_mut_ptr();
@@ -87,7 +93,7 @@ pub fn deflate(framebuffer: FrameBuffer, stream_id: String) -> FrameBufferRectan
             /* Call deflateInit2_ */
             let deflate_init_status = libz_sys::deflateInit2_(
                 zlib_stream,
-                5, /* Set Compress Level (0-9, None-Max) */
+                compress_level, /* Set Compress Level (0-9, None-Max) */
                 libz_sys::Z_DEFLATED,
                 15, /* Range: 8-15 (Min-Max Memory) */
                 8,
@@ -98,11 +104,26 @@ pub fn deflate(framebuffer: FrameBuffer, stream_id: String) -> FrameBufferRectan
 
             if deflate_init_status != libz_sys::Z_OK {
                 println!("ZLIB: DeflateInit2_() failed (RAW Sent). Status: {}", deflate_init_status);
-                return framebuffer_rectangle;
+                return Option::None;
             }
+
+            *stream_level = compress_level;
         }
 
         let previous_total_out = zlib_stream.total_out;
+        if *stream_level != compress_level {
+            /* Level changed mid-stream, the Client's inflater doesn't care */
+            let params_status = libz_sys::deflateParams(
+                zlib_stream,
+                compress_level,
+                libz_sys::Z_DEFAULT_STRATEGY
+            );
+
+            if params_status == libz_sys::Z_OK {
+                *stream_level = compress_level;
+            }
+        }
+
         let deflate_status = libz_sys::deflate(
             zlib_stream,
             libz_sys::Z_SYNC_FLUSH
@@ -110,20 +131,145 @@ pub fn deflate(framebuffer: FrameBuffer, stream_id: String) -> FrameBufferRectan
 
         if deflate_status != libz_sys::Z_OK {
             println!("ZLIB: Deflate() failed (RAW Sent). Status: {}", deflate_status);
-            return framebuffer_rectangle;
+            return Option::None;
         }
 
         /* Calculate Compression and Update Stream */
         let compressed_bytes = zlib_stream.total_out - previous_total_out;
+        Option::Some(next_out[..(compressed_bytes as usize)].to_vec())
+    }
+}
+
+pub fn compress_once(zlib_data: &[u8], compress_level: i32) -> Option<Vec<u8>> {
+    /* A complete zlib Stream, for Messages that don't share one */
+    unsafe {
+        let mut compressed_length = libz_sys::compressBound(zlib_data.len() as _);
+        let mut next_out: Vec<u8> = vec![0; compressed_length as usize];
+        let compress_status = libz_sys::compress2(
+            next_out.as_mut_ptr(),
+            &mut compressed_length,
+            zlib_data.as_ptr(),
+            zlib_data.len() as _,
+            compress_level
+        );
+
+        if compress_status != libz_sys::Z_OK {
+            println!("ZLIB: Compress2() failed. Status: {}", compress_status);
+            return Option::None;
+        }
 
+        next_out.truncate(compressed_length as usize);
+        Option::Some(next_out)
+    }
+}
+
+pub fn decompress_once(zlib_data: &[u8], max_length: usize) -> Option<Vec<u8>> {
+    /* The Size is not known up front, grow the Output until everything fits */
+    let mut output_length = (zlib_data.len() * 4).max(1024);
+    loop {
+        let mut next_out: Vec<u8> = vec![0; output_length];
+        let mut decompressed_length = output_length as _;
+        let uncompress_status = unsafe {
+            libz_sys::uncompress(
+                next_out.as_mut_ptr(),
+                &mut decompressed_length,
+                zlib_data.as_ptr(),
+                zlib_data.len() as _
+            )
+        };
+
+        match uncompress_status {
+            libz_sys::Z_OK => {
+                next_out.truncate(decompressed_length as usize);
+                return Option::Some(next_out);
+            },
+            libz_sys::Z_BUF_ERROR if output_length < max_length => {
+                output_length = (output_length * 2).min(max_length);
+            },
+            _ => return Option::None
+        }
+    }
+}
+
+pub fn deflate(framebuffer: FrameBuffer, stream_id: String) -> FrameBufferRectangle {
+    let mut framebuffer_rectangle = FrameBufferRectangle {
+        x_position: framebuffer.x_position,
+        y_position: framebuffer.y_position,
+        width: framebuffer.width,
+        height: framebuffer.height,
+        encoding_type: RFBEncodingType::RAW,
+        encoded_pixels: framebuffer.raw_pixels,
+        encoded_pixels_length: 0,
+    };
+
+    if let Some(compressed_data) = compress(stream_id, &framebuffer.encoded_pixels, ZLIB_COMPRESS_LEVEL) {
         /* Update FrameBufferRectangle */
-        framebuffer_rectangle.encoded_pixels_length = compressed_bytes as u32;
+        framebuffer_rectangle.encoded_pixels_length = compressed_data.len() as u32;
         framebuffer_rectangle.encoding_type = framebuffer.encoding;
-        framebuffer_rectangle.encoded_pixels = next_out[..(compressed_bytes as usize)].to_vec();
-        framebuffer_rectangle
+        framebuffer_rectangle.encoded_pixels = compressed_data;
     }
+
+    framebuffer_rectangle
+}
+
+pub fn stream_name(stream_id: &str) -> String {
+    format!("{}-zlib", stream_id)
 }
 
 pub fn get_pixel_data(framebuffer: FrameBuffer, stream_id: String) -> FrameBufferRectangle {
-    deflate(framebuffer, stream_id)
+    /* RAW Pixels through zlib, ZLIB keeps its own Stream, apart from ZRLE and Tight */
+    deflate(FrameBuffer {
+        encoded_pixels: framebuffer.raw_pixels.clone(),
+        ..framebuffer
+    }, stream_name(&stream_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{encoding_trle, encoding_zrle, test_support::{framebuffer, noise_pixels, Inflater}};
+
+    fn zlib_framebuffer(width: u16, height: u16, raw_pixels: Vec<u8>) -> FrameBuffer {
+        FrameBuffer {
+            encoding: RFBEncodingType::ZLIB,
+            ..framebuffer(width, height, raw_pixels)
+        }
+    }
+
+    #[test]
+    fn zlib_round_trip() {
+        let raw_pixels = [10, 20, 30, 0].repeat(40 * 30);
+        let rectangle = get_pixel_data(zlib_framebuffer(40, 30, raw_pixels.clone()), "zlib-round-trip".to_string());
+
+        assert_eq!(rectangle.encoding_type, RFBEncodingType::ZLIB);
+        assert!(rectangle.encoded_pixels.len() < raw_pixels.len());
+        assert_eq!(Inflater::new().inflate(&rectangle.encoded_pixels), raw_pixels);
+        flush_stream(stream_name("zlib-round-trip"));
+    }
+
+    #[test]
+    fn zlib_and_zrle_keep_their_own_streams() {
+        /* A Client switching Encodings keeps one Inflater per Encoding */
+        let mut zlib_inflater = Inflater::new();
+        let mut zrle_inflater = Inflater::new();
+        let cpixels = noise_pixels(16 * 16 * 3, 5);
+        let zrle_framebuffer = FrameBuffer {
+            encoding: RFBEncodingType::ZRLE,
+            encoded_pixels: cpixels.clone(),
+            ..framebuffer(16, 16, vec![])
+        };
+
+        let first_zrle = encoding_zrle::get_pixel_data(zrle_framebuffer.clone(), "switching-client".to_string());
+        let raw_pixels = noise_pixels(16 * 16 * 4, 6);
+        let zlib = get_pixel_data(zlib_framebuffer(16, 16, raw_pixels.clone()), "switching-client".to_string());
+        let second_zrle = encoding_zrle::get_pixel_data(zrle_framebuffer, "switching-client".to_string());
+
+        let expected_tile = encoding_trle::encode_tile(&cpixels, 16, 16, 3);
+        assert_eq!(zrle_inflater.inflate(&first_zrle.encoded_pixels), expected_tile);
+        assert_eq!(zlib_inflater.inflate(&zlib.encoded_pixels), raw_pixels);
+        assert_eq!(zrle_inflater.inflate(&second_zrle.encoded_pixels), expected_tile);
+
+        flush_stream(stream_name("switching-client"));
+        flush_stream(encoding_zrle::stream_name("switching-client"));
+    }
 }
\ No newline at end of file
@@ -17,8 +17,13 @@
 */
 
 use crate::server::encoding_zlib::deflate;
+use crate::server::encoding_trle::encode_tiles;
 use super::{FrameBuffer, FrameBufferRectangle};
 
+pub fn stream_name(stream_id: &str) -> String {
+    format!("{}-zrle", stream_id)
+}
+
 pub fn get_pixel_data(framebuffer: FrameBuffer, stream_id: String) -> FrameBufferRectangle {
     let c_pixels: Vec<u8> = framebuffer.encoded_pixels.clone();
     let encoded_tiles: Vec<u8>;
@@ -31,76 +36,71 @@ pub fn get_pixel_data(framebuffer: FrameBuffer, stream_id: String) -> FrameBuffe
         encoded_tiles = framebuffer.raw_pixels.clone();
     }
     
-    /* Add encoded_structure fields */
+    /* Add encoded_structure fields, ZRLE keeps its own Stream */
     deflate(FrameBuffer {
         encoded_pixels: encoded_tiles,
         ..framebuffer.clone()
-    }, stream_id)
+    }, stream_name(&stream_id))
 }
 
 fn encode(framebuffer: FrameBuffer) -> Vec<u8> {
-    let bytes_per_cpixel: u16 = if framebuffer.bits_per_pixel >= 24 { 3 } else { (framebuffer.bits_per_pixel / 8) as u16 };
-    const ZRLE_TILE_WIDTH: f32 = 64_f32;
-    const ZRLE_TILE_HEIGHT: f32 = 64_f32;
-
-    /* Divide FrameBuffer into Tiles of 64x64 pixels */
-    let h_tiles = (framebuffer.width as f32 / ZRLE_TILE_WIDTH).ceil() as usize;
-    let v_tiles = (framebuffer.height as f32 / ZRLE_TILE_HEIGHT).ceil() as usize;
-
-    let mut zrle_tiles: Vec<u8> = vec![];
-    let hscan_lines: Vec<&[u8]>;
-    hscan_lines = framebuffer.encoded_pixels.chunks_exact((framebuffer.width * bytes_per_cpixel) as usize).collect();
-
-    for zrletile_ctr in 0..(v_tiles * h_tiles) {
-        let mut previous_scan_line: Vec<u8> = vec![];
-        let mut solid_tile = true;
-
-        let mut tile_pixels: Vec<u8> = Vec::with_capacity(
-            (ZRLE_TILE_WIDTH * ZRLE_TILE_HEIGHT) as usize
-             * bytes_per_cpixel as usize
-        );
-
-        let vertical_progress = ((zrletile_ctr as f32 / h_tiles as f32).floor()) as usize;
-        let horizontal_progress = zrletile_ctr % h_tiles;
-        let start = vertical_progress * ZRLE_TILE_HEIGHT as usize;
-        let end = 
-            if (start + ZRLE_TILE_HEIGHT as usize) > hscan_lines.len() { hscan_lines.len() } 
-            else { start + ZRLE_TILE_HEIGHT as usize };
-
-        for hscan_line_ctr in start..end {
-            let h_start = horizontal_progress * bytes_per_cpixel as usize * (ZRLE_TILE_WIDTH as usize);
-            let h_end = 
-            if (h_start + (ZRLE_TILE_HEIGHT as usize * bytes_per_cpixel as usize)) > hscan_lines[hscan_line_ctr].len() { hscan_lines[hscan_line_ctr].len() } 
-            else { h_start + (ZRLE_TILE_HEIGHT as usize * bytes_per_cpixel as usize) };
-
-            let scan_line = &hscan_lines[hscan_line_ctr][h_start..h_end];
-            tile_pixels.extend_from_slice(scan_line);
-
-            if previous_scan_line.len() == 0 {
-                /* This is the first Line */
-                previous_scan_line = scan_line.to_vec();
-            } else {
-                if scan_line != previous_scan_line {
-                    /* This is not a Solid Tile */
-                    solid_tile = false;
-                }
-            }
+    /* ZRLE Tiles are TRLE Tiles of 64x64 pixels */
+    const ZRLE_TILE_SIZE: usize = 64;
+    encode_tiles(&framebuffer, ZRLE_TILE_SIZE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{RFBEncodingType, encoding_zlib, test_support::{decode_tiles, framebuffer, noise_pixels, Inflater}};
+
+    const BYTES_PER_CPIXEL: usize = 3;
+    const ZRLE_TILE_SIZE: usize = 64;
+
+    fn cpixel_framebuffer(width: u16, height: u16, encoded_pixels: Vec<u8>) -> FrameBuffer {
+        FrameBuffer {
+            encoding: RFBEncodingType::ZRLE,
+            encoded_pixels,
+            ..framebuffer(width, height, vec![])
         }
+    }
 
-        if solid_tile == true {
-            let mut solid_color: Vec<u8> = vec![];
-            for subpixel in 0..(bytes_per_cpixel as usize) {
-                solid_color.push(tile_pixels[subpixel]);
+    fn test_cpixels(width: usize, height: usize, seed: u32) -> Vec<u8> {
+        /* Flat Desktop, a striped Window and a noisy corner */
+        let noise = noise_pixels(width * height * BYTES_PER_CPIXEL, seed);
+        let mut cpixels: Vec<u8> = vec![];
+        for y in 0..height {
+            for x in 0..width {
+                let noise_start = (y * width + x) * BYTES_PER_CPIXEL;
+                let cpixel: [u8; 3] = match (x, y) {
+                    (0..=20, 0..=20) => noise[noise_start..(noise_start + BYTES_PER_CPIXEL)].try_into().unwrap(),
+                    (40..=119, 10..=59) => if y % 4 < 2 { [255, 255, 255] } else { [0, 0, 200] },
+                    _ => [30, 60, 90],
+                };
+                cpixels.extend_from_slice(&cpixel);
             }
-
-            zrle_tiles.push(1_u8);
-            zrle_tiles.extend_from_slice(&solid_color);
-        } else {
-            zrle_tiles.push(0_u8);
-            zrle_tiles.extend_from_slice(tile_pixels.as_slice());
         }
+
+        cpixels
     }
 
-    /* Send Compressed Tiles */
-    zrle_tiles
-}
\ No newline at end of file
+    #[test]
+    fn zrle_round_trip() {
+        /* Both Rectangles share one Stream, the second depends on the first */
+        let mut inflater = Inflater::new();
+        for seed in [1, 2] {
+            /* Not a multiple of 64, so the Edge Tiles are smaller */
+            let cpixels = test_cpixels(150, 70, seed);
+            let rectangle = get_pixel_data(cpixel_framebuffer(150, 70, cpixels.clone()), "zrle-round-trip".to_string());
+
+            assert_eq!(rectangle.encoding_type, RFBEncodingType::ZRLE);
+            assert_eq!(rectangle.encoded_pixels_length as usize, rectangle.encoded_pixels.len());
+            assert!(rectangle.encoded_pixels.len() < cpixels.len());
+
+            let zrle_tiles = inflater.inflate(&rectangle.encoded_pixels);
+            assert_eq!(decode_tiles(&zrle_tiles, 150, 70, ZRLE_TILE_SIZE, BYTES_PER_CPIXEL), cpixels);
+        }
+
+        encoding_zlib::flush_stream(stream_name("zrle-round-trip"));
+    }
+}
@@ -0,0 +1,145 @@
+/*
+    SpifyRFB - Modern RFB Server implementation using Rust
+    Copyright (C) 2023  Atheesh Thirumalairajan
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::{collections::{HashMap, HashSet}, sync::RwLock};
+use once_cell::sync::Lazy;
+
+use super::clipboard::ClipboardData;
+
+#[derive(Debug, Clone, Default)]
+pub struct CursorImage {
+    pub(crate) width: u16,
+    pub(crate) height: u16,
+    pub(crate) x_hotspot: u16,
+    pub(crate) y_hotspot: u16,
+    pub(crate) pixels: Vec<u32>, /* ARGB, PREMULTIPLIED ALPHA */
+}
+
+/* Changes noticed by the Platform watcher, delivered to every Client */
+#[derive(Debug, Clone)]
+pub enum DesktopEvent {
+    Resized(u16, u16), /* (WIDTH, HEIGHT) */
+    Renamed(String),
+    CursorChanged(CursorImage),
+    PointerMoved(u16, u16), /* (X, Y) */
+    ClipboardChanged(ClipboardData, Option<String>), /* (CONTENTS, CLIENT THAT SENT IT) */
+    Bell,
+}
+
+/* Events not yet picked up, per Client */
+static PENDING_EVENTS: Lazy<RwLock<HashMap<String, Vec<DesktopEvent>>>>
+    = Lazy::new(|| { RwLock::new(HashMap::new()) });
+
+/* Clients enabling Cursor pseudo-encodings start from the latest Shape */
+static CURRENT_CURSOR: Lazy<RwLock<Option<CursorImage>>>
+    = Lazy::new(|| { RwLock::new(Option::None) });
+
+/* Clients that negotiated PointerPos, the Pointer is only watched for them */
+static POINTER_WATCHERS: Lazy<RwLock<HashSet<String>>>
+    = Lazy::new(|| { RwLock::new(HashSet::new()) });
+
+pub fn register_client(client_id: String) {
+    let mut events_lock = PENDING_EVENTS.write().unwrap();
+    events_lock.insert(client_id, vec![]);
+}
+
+pub fn unregister_client(client_id: String) {
+    let mut events_lock = PENDING_EVENTS.write().unwrap();
+    events_lock.remove(&client_id);
+    POINTER_WATCHERS.write().unwrap().remove(&client_id);
+}
+
+pub fn set_pointer_watcher(client_id: &str, watching: bool) {
+    let mut watchers_lock = POINTER_WATCHERS.write().unwrap();
+    if watching {
+        watchers_lock.insert(client_id.to_string());
+    } else {
+        watchers_lock.remove(client_id);
+    }
+}
+
+pub fn pointer_watched() -> bool {
+    !POINTER_WATCHERS.read().unwrap().is_empty()
+}
+
+/* Answers Extended Clipboard Requests and Peeks */
+static CURRENT_CLIPBOARD: Lazy<RwLock<Option<ClipboardData>>>
+    = Lazy::new(|| { RwLock::new(Option::None) });
+
+pub fn current_cursor() -> Option<CursorImage> {
+    CURRENT_CURSOR.read().unwrap().clone()
+}
+
+pub fn current_clipboard() -> Option<ClipboardData> {
+    CURRENT_CLIPBOARD.read().unwrap().clone()
+}
+
+pub fn broadcast(desktop_event: DesktopEvent) {
+    match &desktop_event {
+        DesktopEvent::CursorChanged(cursor) => *CURRENT_CURSOR.write().unwrap() = Option::Some(cursor.clone()),
+        DesktopEvent::ClipboardChanged(clipboard_data, _) => *CURRENT_CLIPBOARD.write().unwrap() = Option::Some(clipboard_data.clone()),
+        _ => {}
+    }
+
+    let mut events_lock = PENDING_EVENTS.write().unwrap();
+    let watchers_lock = POINTER_WATCHERS.read().unwrap();
+    for (client_id, pending_events) in events_lock.iter_mut() {
+        /* Pointer Moves would only be dropped by Clients without PointerPos */
+        if matches!(desktop_event, DesktopEvent::PointerMoved(..)) && !watchers_lock.contains(client_id) {
+            continue;
+        }
+
+        pending_events.push(desktop_event.clone());
+    }
+}
+
+pub fn take_events(client_id: &str) -> Vec<DesktopEvent> {
+    let mut events_lock = PENDING_EVENTS.write().unwrap();
+    events_lock
+        .get_mut(client_id)
+        .map(std::mem::take)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pointer_moves_reach_only_watchers() {
+        register_client(String::from("pointer-watcher"));
+        register_client(String::from("pointer-ignorer"));
+        assert!(!pointer_watched());
+
+        set_pointer_watcher("pointer-watcher", true);
+        assert!(pointer_watched());
+        broadcast(DesktopEvent::PointerMoved(10, 20));
+        broadcast(DesktopEvent::Bell);
+
+        assert!(matches!(take_events("pointer-watcher")[..], [DesktopEvent::PointerMoved(10, 20), DesktopEvent::Bell]));
+        assert!(matches!(take_events("pointer-ignorer")[..], [DesktopEvent::Bell]));
+
+        /* Dropping PointerPos, or leaving, stops the Polling */
+        set_pointer_watcher("pointer-watcher", false);
+        assert!(!pointer_watched());
+        set_pointer_watcher("pointer-watcher", true);
+        unregister_client(String::from("pointer-watcher"));
+        unregister_client(String::from("pointer-ignorer"));
+        assert!(!pointer_watched());
+    }
+}
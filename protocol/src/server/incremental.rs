@@ -0,0 +1,486 @@
+/*
+    SpifyRFB - Modern RFB Server implementation using Rust
+    Copyright (C) 2023  Atheesh Thirumalairajan
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::{collections::HashMap, sync::{RwLock, atomic::{AtomicBool, AtomicU64, Ordering}}};
+use once_cell::sync::Lazy;
+
+use super::{FrameBuffer, encoding_copyrect};
+
+/* Changes are tracked in square tiles of this size */
+pub(crate) const DAMAGE_TILE_SIZE: usize = 32;
+
+/* Last Frame sent to a Client, in Server PixelFormat */
+struct LiveFrameBuffer {
+    framebuffer: FrameBuffer,
+    damage_serial: Option<u64>, /* WHEN IT WAS CAPTURED */
+    cursor: Option<CompositedCursor>,
+}
+
+static LIVE_FRAMEBUFFERS: Lazy<RwLock<HashMap<String, LiveFrameBuffer>>>
+    = Lazy::new(|| { RwLock::new(HashMap::new()) });
+
+/* Bumped by the Platform (XDamage) whenever the Screen changes */
+static DAMAGE_REPORTS: AtomicBool = AtomicBool::new(false);
+static DAMAGE_SERIAL: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DamagedRectangle {
+    pub(crate) x_position: u16,
+    pub(crate) y_position: u16,
+    pub(crate) width: u16,
+    pub(crate) height: u16,
+    pub(crate) copy_source: Option<(u16, u16)>, /* SET FOR COPYRECT */
+}
+
+/* Pointer drawn into the Frame, for Clients without a Cursor Encoding */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompositedCursor {
+    pub(crate) area: Option<DamagedRectangle>, /* NONE OUTSIDE THE FRAME */
+    pub(crate) cursor_serial: u32,
+}
+
+pub fn start_damage_reports() {
+    DAMAGE_REPORTS.store(true, Ordering::SeqCst);
+}
+
+pub fn report_damage() {
+    DAMAGE_SERIAL.fetch_add(1, Ordering::SeqCst);
+}
+
+pub fn damage_serial() -> Option<u64> {
+    /* Without Damage Reports every Poll has to Capture and Compare */
+    if DAMAGE_REPORTS.load(Ordering::SeqCst) {
+        Option::Some(DAMAGE_SERIAL.load(Ordering::SeqCst))
+    } else {
+        Option::None
+    }
+}
+
+pub fn is_undamaged(client_id: &str, region: DamagedRectangle, damage_serial: Option<u64>) -> bool {
+    /* Same Region as the last Update, and no Damage reported since it was Captured */
+    let framebuffers_lock = LIVE_FRAMEBUFFERS.read().unwrap();
+    match (framebuffers_lock.get(client_id), damage_serial) {
+        (Some(LiveFrameBuffer { framebuffer, damage_serial: Some(previous_serial), .. }), Some(damage_serial)) => {
+            *previous_serial == damage_serial && whole_framebuffer(framebuffer) == region
+        },
+        _ => false
+    }
+}
+
+pub fn composited_cursor(client_id: &str) -> Option<CompositedCursor> {
+    let framebuffers_lock = LIVE_FRAMEBUFFERS.read().unwrap();
+    framebuffers_lock.get(client_id).and_then(|live_framebuffer| live_framebuffer.cursor)
+}
+
+pub fn clip(x_position: i32, y_position: i32, width: u16, height: u16, region: DamagedRectangle) -> Option<DamagedRectangle> {
+    let x_start = x_position.max(region.x_position as i32);
+    let y_start = y_position.max(region.y_position as i32);
+    let x_end = (x_position + width as i32).min(region.x_position as i32 + region.width as i32);
+    let y_end = (y_position + height as i32).min(region.y_position as i32 + region.height as i32);
+    if x_start >= x_end || y_start >= y_end {
+        return Option::None;
+    }
+
+    Option::Some(DamagedRectangle {
+        x_position: x_start as u16,
+        y_position: y_start as u16,
+        width: (x_end - x_start) as u16,
+        height: (y_end - y_start) as u16,
+        copy_source: Option::None,
+    })
+}
+
+pub fn flush_framebuffer(client_id: String) {
+    let mut framebuffers_lock = LIVE_FRAMEBUFFERS.write().unwrap();
+    framebuffers_lock.remove(&client_id);
+}
+
+fn whole_framebuffer(framebuffer: &FrameBuffer) -> DamagedRectangle {
+    DamagedRectangle {
+        x_position: framebuffer.x_position,
+        y_position: framebuffer.y_position,
+        width: framebuffer.width,
+        height: framebuffer.height,
+        copy_source: Option::None,
+    }
+}
+
+fn same_region(previous: &FrameBuffer, current: &FrameBuffer) -> bool {
+    previous.x_position == current.x_position
+        && previous.y_position == current.y_position
+        && previous.width == current.width
+        && previous.height == current.height
+        && previous.bits_per_pixel == current.bits_per_pixel
+        && previous.raw_pixels.len() == current.raw_pixels.len()
+}
+
+fn tile_changed(previous: &FrameBuffer, current: &FrameBuffer, tile_x: usize, tile_y: usize) -> bool {
+    let bytes_per_pixel = (current.bits_per_pixel / 8) as usize;
+    let stride = current.width as usize * bytes_per_pixel;
+    let row_start = tile_x * DAMAGE_TILE_SIZE * bytes_per_pixel;
+    let row_end = ((tile_x + 1) * DAMAGE_TILE_SIZE * bytes_per_pixel).min(stride);
+    let line_end = ((tile_y + 1) * DAMAGE_TILE_SIZE).min(current.height as usize);
+
+    for line in (tile_y * DAMAGE_TILE_SIZE)..line_end {
+        let start = line * stride + row_start;
+        let end = line * stride + row_end;
+        if previous.raw_pixels[start..end] != current.raw_pixels[start..end] {
+            return true;
+        }
+    }
+
+    false
+}
+
+pub(crate) fn merge_tiles(
+    framebuffer: &FrameBuffer,
+    tile_selected: impl Fn(usize, usize) -> bool
+) -> Vec<DamagedRectangle> {
+    let h_tiles = (framebuffer.width as usize).div_ceil(DAMAGE_TILE_SIZE);
+    let v_tiles = (framebuffer.height as usize).div_ceil(DAMAGE_TILE_SIZE);
+
+    /* Rectangles still growing downwards: (first tile, last tile, rectangle) */
+    let mut open_rectangles: Vec<(usize, usize, DamagedRectangle)> = vec![];
+    let mut damaged_rectangles: Vec<DamagedRectangle> = vec![];
+
+    for tile_y in 0..v_tiles {
+        /* Merge selected tiles of this row into horizontal runs */
+        let mut runs: Vec<(usize, usize)> = vec![];
+        for tile_x in 0..h_tiles {
+            if tile_selected(tile_x, tile_y) {
+                match runs.last_mut() {
+                    Some(run) if run.1 + 1 == tile_x => run.1 = tile_x,
+                    _ => runs.push((tile_x, tile_x)),
+                }
+            }
+        }
+
+        let line_start = tile_y * DAMAGE_TILE_SIZE;
+        let line_height = DAMAGE_TILE_SIZE.min(framebuffer.height as usize - line_start) as u16;
+        let mut next_open: Vec<(usize, usize, DamagedRectangle)> = vec![];
+
+        for run in runs {
+            /* Extend a rectangle from the previous row if it spans the same tiles */
+            let open_index = open_rectangles
+                .iter()
+                .position(|open| open.0 == run.0 && open.1 == run.1);
+
+            if let Some(open_index) = open_index {
+                let mut open = open_rectangles.remove(open_index);
+                open.2.height += line_height;
+                next_open.push(open);
+            } else {
+                let x_start = run.0 * DAMAGE_TILE_SIZE;
+                let x_end = ((run.1 + 1) * DAMAGE_TILE_SIZE).min(framebuffer.width as usize);
+                next_open.push((run.0, run.1, DamagedRectangle {
+                    x_position: framebuffer.x_position + x_start as u16,
+                    y_position: framebuffer.y_position + line_start as u16,
+                    width: (x_end - x_start) as u16,
+                    height: line_height,
+                    copy_source: Option::None,
+                }));
+            }
+        }
+
+        /* Rectangles not continued by this row are complete */
+        damaged_rectangles.extend(open_rectangles.into_iter().map(|open| open.2));
+        open_rectangles = next_open;
+    }
+
+    damaged_rectangles.extend(open_rectangles.into_iter().map(|open| open.2));
+    damaged_rectangles
+}
+
+fn find_damage(previous: &FrameBuffer, current: &FrameBuffer) -> Vec<DamagedRectangle> {
+    merge_tiles(current, |tile_x, tile_y| tile_changed(previous, current, tile_x, tile_y))
+}
+
+fn find_damage_with_copies(previous: &FrameBuffer, current: &FrameBuffer) -> Vec<DamagedRectangle> {
+    let damaged_rectangles = find_damage(previous, current);
+    let mut copy_rectangles = encoding_copyrect::find_copies(previous, current, &damaged_rectangles);
+    if copy_rectangles.is_empty() {
+        return damaged_rectangles;
+    }
+
+    /* Whatever the Copies did not fix is sent with the regular Encoding */
+    let predicted = encoding_copyrect::apply_copies(previous, &copy_rectangles);
+    copy_rectangles.extend(find_damage(&predicted, current));
+    copy_rectangles
+}
+
+pub fn get_damage(
+    client_id: &str,
+    framebuffer: &FrameBuffer,
+    incremental: bool,
+    copy_rect: bool,
+    damage_serial: Option<u64>,
+    cursor: Option<CompositedCursor>
+) -> Vec<DamagedRectangle> {
+    let mut framebuffers_lock = LIVE_FRAMEBUFFERS.write().unwrap();
+    let previous_framebuffer = framebuffers_lock.get(client_id).map(|live_framebuffer| &live_framebuffer.framebuffer);
+
+    let damaged_rectangles = match previous_framebuffer {
+        Some(previous) if incremental && same_region(previous, framebuffer) => {
+            if copy_rect {
+                find_damage_with_copies(previous, framebuffer)
+            } else {
+                find_damage(previous, framebuffer)
+            }
+        },
+        _ => {
+            /* Non-Incremental or First Request, Everything is Damaged */
+            vec![whole_framebuffer(framebuffer)]
+        }
+    };
+
+    if !damaged_rectangles.is_empty() {
+        /* Remember what the Client will see after this Update */
+        framebuffers_lock.insert(client_id.to_string(), LiveFrameBuffer {
+            framebuffer: framebuffer.clone(),
+            damage_serial,
+            cursor,
+        });
+    } else if let Some(live_framebuffer) = framebuffers_lock.get_mut(client_id) {
+        /* Damage that changed nothing here, the next Poll can skip the Capture */
+        live_framebuffer.damage_serial = damage_serial;
+        live_framebuffer.cursor = cursor;
+    }
+
+    damaged_rectangles
+}
+
+fn apply_patch(framebuffer: &mut FrameBuffer, patch: &FrameBuffer, changed_tiles: &mut Vec<(usize, usize)>) {
+    let bytes_per_pixel = (framebuffer.bits_per_pixel / 8) as usize;
+    let stride = framebuffer.width as usize * bytes_per_pixel;
+    let x_offset = (patch.x_position - framebuffer.x_position) as usize;
+    let y_offset = (patch.y_position - framebuffer.y_position) as usize;
+
+    for line in 0..patch.height as usize {
+        for column in 0..patch.width as usize {
+            let source = (line * patch.width as usize + column) * bytes_per_pixel;
+            let destination = (y_offset + line) * stride + (x_offset + column) * bytes_per_pixel;
+            let patch_pixel = &patch.raw_pixels[source..(source + bytes_per_pixel)];
+            if framebuffer.raw_pixels[destination..(destination + bytes_per_pixel)] == *patch_pixel {
+                continue;
+            }
+
+            framebuffer.raw_pixels[destination..(destination + bytes_per_pixel)].copy_from_slice(patch_pixel);
+            let tile = ((x_offset + column) / DAMAGE_TILE_SIZE, (y_offset + line) / DAMAGE_TILE_SIZE);
+            if !changed_tiles.contains(&tile) {
+                changed_tiles.push(tile);
+            }
+        }
+    }
+}
+
+pub fn get_cursor_damage(client_id: &str, cursor_patches: &[FrameBuffer], cursor: CompositedCursor) -> Option<Vec<FrameBuffer>> {
+    /*
+        The Screen is undamaged, only the old and new Cursor Areas were
+        Captured (with the Cursor drawn in). Patch them into the last Frame
+        and send the tiles that changed. None if there is no Frame to patch.
+    */
+    let mut framebuffers_lock = LIVE_FRAMEBUFFERS.write().unwrap();
+    let live_framebuffer = framebuffers_lock.get_mut(client_id)?;
+    let frame_region = whole_framebuffer(&live_framebuffer.framebuffer);
+    for cursor_patch in cursor_patches {
+        let patch_region = whole_framebuffer(cursor_patch);
+        if cursor_patch.bits_per_pixel != live_framebuffer.framebuffer.bits_per_pixel
+            || clip(patch_region.x_position as i32, patch_region.y_position as i32, patch_region.width, patch_region.height, frame_region) != Option::Some(patch_region)
+        {
+            return Option::None;
+        }
+    }
+
+    let mut changed_tiles: Vec<(usize, usize)> = vec![];
+    for cursor_patch in cursor_patches {
+        apply_patch(&mut live_framebuffer.framebuffer, cursor_patch, &mut changed_tiles);
+    }
+
+    live_framebuffer.cursor = Option::Some(cursor);
+    let damaged_rectangles = merge_tiles(&live_framebuffer.framebuffer, |tile_x, tile_y| changed_tiles.contains(&(tile_x, tile_y)));
+    Option::Some(damaged_rectangles.into_iter().map(|damaged_rectangle| crop(&live_framebuffer.framebuffer, damaged_rectangle)).collect())
+}
+
+pub fn crop(framebuffer: &FrameBuffer, rectangle: DamagedRectangle) -> FrameBuffer {
+    let bytes_per_pixel = (framebuffer.bits_per_pixel / 8) as usize;
+    let stride = framebuffer.width as usize * bytes_per_pixel;
+    let x_offset = (rectangle.x_position - framebuffer.x_position) as usize * bytes_per_pixel;
+    let y_offset = (rectangle.y_position - framebuffer.y_position) as usize;
+    let row_length = rectangle.width as usize * bytes_per_pixel;
+
+    let mut cropped_pixels: Vec<u8> = Vec::with_capacity(row_length * rectangle.height as usize);
+    for line in y_offset..(y_offset + rectangle.height as usize) {
+        let start = line * stride + x_offset;
+        cropped_pixels.extend_from_slice(&framebuffer.raw_pixels[start..(start + row_length)]);
+    }
+
+    FrameBuffer {
+        x_position: rectangle.x_position,
+        y_position: rectangle.y_position,
+        width: rectangle.width,
+        height: rectangle.height,
+        bits_per_pixel: framebuffer.bits_per_pixel,
+        encoding: framebuffer.encoding,
+        raw_pixels: cropped_pixels,
+        encoded_pixels: vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::test_support::{framebuffer, noise_pixels};
+
+    const BYTES_PER_PIXEL: usize = 4;
+
+    fn changed_pixel(previous: &FrameBuffer, x: usize, y: usize) -> FrameBuffer {
+        let mut current = previous.clone();
+        let start = (y * previous.width as usize + x) * BYTES_PER_PIXEL;
+        current.raw_pixels[start] ^= 0xFF;
+        current
+    }
+
+    #[test]
+    fn non_incremental_sends_everything() {
+        let previous = framebuffer(100, 80, noise_pixels(100 * 80 * BYTES_PER_PIXEL, 1));
+        get_damage("non-incremental", &previous, false, false, Option::None, Option::None);
+
+        /* Nothing changed, but the Client asked for all of it */
+        let damaged_rectangles = get_damage("non-incremental", &previous, false, false, Option::None, Option::None);
+        assert_eq!(damaged_rectangles, vec![whole_framebuffer(&previous)]);
+        flush_framebuffer("non-incremental".to_string());
+    }
+
+    #[test]
+    fn first_and_changed_regions_send_everything() {
+        let first = framebuffer(100, 80, noise_pixels(100 * 80 * BYTES_PER_PIXEL, 2));
+        assert_eq!(get_damage("changed-region", &first, true, false, Option::None, Option::None), vec![whole_framebuffer(&first)]);
+
+        /* Same pixels, but the Client now asks for another Region */
+        let moved = FrameBuffer { x_position: 10, ..first.clone() };
+        assert_eq!(get_damage("changed-region", &moved, true, false, Option::None, Option::None), vec![whole_framebuffer(&moved)]);
+
+        let resized = framebuffer(64, 80, noise_pixels(64 * 80 * BYTES_PER_PIXEL, 2));
+        assert_eq!(get_damage("changed-region", &resized, true, false, Option::None, Option::None), vec![whole_framebuffer(&resized)]);
+        flush_framebuffer("changed-region".to_string());
+    }
+
+    #[test]
+    fn one_pixel_damages_one_tile() {
+        let previous = framebuffer(100, 80, noise_pixels(100 * 80 * BYTES_PER_PIXEL, 3));
+        get_damage("one-pixel", &previous, true, false, Option::None, Option::None);
+
+        let current = changed_pixel(&previous, 40, 70);
+        assert_eq!(get_damage("one-pixel", &current, true, false, Option::None, Option::None), vec![DamagedRectangle {
+            x_position: 32,
+            y_position: 64,
+            width: 32,
+            height: 16, /* Bottom Edge Tile */
+            copy_source: Option::None,
+        }]);
+
+        /* Nothing changed since */
+        assert!(get_damage("one-pixel", &current, true, false, Option::None, Option::None).is_empty());
+        flush_framebuffer("one-pixel".to_string());
+    }
+
+    #[test]
+    fn undamaged_region_is_not_compared() {
+        let previous = framebuffer(100, 80, noise_pixels(100 * 80 * BYTES_PER_PIXEL, 4));
+        let region = whole_framebuffer(&previous);
+        assert!(!is_undamaged("damage-serial", region, Option::Some(7)));
+
+        get_damage("damage-serial", &previous, true, false, Option::Some(7), Option::None);
+        assert!(is_undamaged("damage-serial", region, Option::Some(7)));
+        assert!(!is_undamaged("damage-serial", region, Option::Some(8)));
+        assert!(!is_undamaged("damage-serial", DamagedRectangle { width: 50, ..region }, Option::Some(7)));
+
+        /* Damage elsewhere on the Screen moves the Serial on, without an Update */
+        assert!(get_damage("damage-serial", &previous, true, false, Option::Some(8), Option::None).is_empty());
+        assert!(is_undamaged("damage-serial", region, Option::Some(8)));
+
+        /* Without Damage Reports every Poll Compares */
+        assert!(!is_undamaged("damage-serial", region, Option::None));
+        flush_framebuffer("damage-serial".to_string());
+    }
+
+    #[test]
+    fn pointer_moves_send_only_the_cursor_areas() {
+        let background = framebuffer(128, 128, noise_pixels(128 * 128 * BYTES_PER_PIXEL, 5));
+        let region = whole_framebuffer(&background);
+        let draw_cursor = |frame: &FrameBuffer, area: DamagedRectangle| {
+            let mut frame = frame.clone();
+            for line in area.y_position as usize..(area.y_position + area.height) as usize {
+                let start = (line * frame.width as usize + area.x_position as usize) * BYTES_PER_PIXEL;
+                frame.raw_pixels[start..(start + area.width as usize * BYTES_PER_PIXEL)].fill(0xFF);
+            }
+
+            frame
+        };
+
+        let old_cursor = CompositedCursor { area: clip(10, 10, 16, 16, region), cursor_serial: 1 };
+        let new_cursor = CompositedCursor { area: clip(90, 70, 16, 16, region), cursor_serial: 1 };
+        let (old_area, new_area) = (old_cursor.area.unwrap(), new_cursor.area.unwrap());
+        get_damage("pointer-moved", &draw_cursor(&background, old_area), true, false, Option::Some(3), Option::Some(old_cursor));
+        assert!(is_undamaged("pointer-moved", region, Option::Some(3)));
+        assert_eq!(composited_cursor("pointer-moved"), Option::Some(old_cursor));
+
+        /* Only the two Cursor Areas were Captured again */
+        let cursor_patches = [crop(&background, old_area), crop(&draw_cursor(&background, new_area), new_area)];
+        let cursor_framebuffers = get_cursor_damage("pointer-moved", &cursor_patches, new_cursor).unwrap();
+        let sent_regions: Vec<DamagedRectangle> = cursor_framebuffers.iter().map(whole_framebuffer).collect();
+        let tile_rectangle = |x_position, y_position, width, height| DamagedRectangle {
+            x_position,
+            y_position,
+            width,
+            height,
+            copy_source: Option::None,
+        };
+
+        assert_eq!(sent_regions, vec![tile_rectangle(0, 0, 32, 32), tile_rectangle(64, 64, 64, 32)]);
+        assert_eq!(cursor_framebuffers[0].raw_pixels, crop(&background, sent_regions[0]).raw_pixels);
+        assert_eq!(cursor_framebuffers[1].raw_pixels, crop(&draw_cursor(&background, new_area), sent_regions[1]).raw_pixels);
+        assert_eq!(composited_cursor("pointer-moved"), Option::Some(new_cursor));
+
+        /* The Screen is still undamaged, and the same Patches change nothing */
+        assert!(is_undamaged("pointer-moved", region, Option::Some(3)));
+        assert!(get_cursor_damage("pointer-moved", &cursor_patches, new_cursor).unwrap().is_empty());
+
+        /* Patches outside the last Frame fall back to a full Capture */
+        let outside = FrameBuffer { x_position: 120, ..cursor_patches[1].clone() };
+        assert!(get_cursor_damage("pointer-moved", &[outside], new_cursor).is_none());
+        flush_framebuffer("pointer-moved".to_string());
+    }
+
+    #[test]
+    fn adjacent_tiles_merge() {
+        let frame = framebuffer(128, 128, vec![]);
+        let selected = [(1, 1), (2, 1), (1, 2), (2, 2), (0, 3)];
+        let damaged_rectangles = merge_tiles(&frame, |tile_x, tile_y| selected.contains(&(tile_x, tile_y)));
+
+        let rectangle = |x_position, y_position, width, height| DamagedRectangle {
+            x_position,
+            y_position,
+            width,
+            height,
+            copy_source: Option::None,
+        };
+
+        assert_eq!(damaged_rectangles, vec![rectangle(32, 32, 64, 64), rectangle(0, 96, 32, 32)]);
+    }
+}
@@ -17,14 +17,40 @@
 */
 
 pub mod encoding_raw;
+pub mod encoding_copyrect;
+pub mod encoding_rre;
+pub mod encoding_trle;
+pub mod encoding_tight;
+pub mod encoding_desktop;
+pub mod encoding_cursor;
+pub mod clipboard;
+pub mod translate;
 pub mod encoding_zrle;
 pub mod encoding_zlib;
 pub mod encoding_hextile;
+pub mod incremental;
+pub mod events;
+pub mod sessions;
+pub mod credentials;
+pub mod stream;
+pub mod vencrypt;
+pub mod rsa_aes;
 pub mod websocket;
 pub mod parser;
 pub mod ipc_client;
 
+#[cfg(test)]
+mod test_support;
+
 use crate::{server::{parser::GetBits, websocket::WSCreateOptions}, debug};
+use self::{
+    clipboard::{ClipboardAction, ClipboardCaps, ClipboardData, ClipboardFormat, ExtendedClipboardMessage},
+    encoding_desktop::{ResizeReason, ResizeStatus},
+    events::{CursorImage, DesktopEvent},
+    credentials::CredentialBackend,
+    sessions::{AccessLevel, SessionPolicy},
+    stream::RFBStream,
+};
 
 #[cfg(target_os = "windows")]
 use crate::win32;
@@ -32,24 +58,31 @@ use crate::win32;
 #[cfg(target_os = "linux")]
 use crate::x11;
 
-use std::{error::Error, sync::Arc, process};
+use std::{error::Error, sync::Arc, process, collections::HashSet, time::{Duration, Instant}};
 use des::{Des, cipher::{KeyInit, generic_array::GenericArray, typenum, BlockDecrypt}};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{
-        tcp::{ReadHalf, WriteHalf},
-        TcpListener, TcpStream,
-    },
+    io::{self, AsyncReadExt, AsyncWriteExt, WriteHalf},
+    net::TcpListener,
+    time::timeout,
 };
+use tokio_rustls::TlsAcceptor;
+use rsa::RsaPrivateKey;
 use uuid::Uuid;
 
 pub struct CreateOptions {
     pub ip_address: String, 
     pub ws_proxy: Option<(String, bool)>, 
     pub auth: Option<RFBAuthentication>,
-    pub spify_daemon: bool
+    pub spify_daemon: bool,
+    pub vencrypt: bool, /* OFFER ONLY VENCRYPT, EVERY SESSION RUNS OVER TLS */
+    pub rsa_aes: bool, /* OFFER RA2 AND RA2NE, KEYS ARE EXCHANGED OVER RSA */
+    pub bell_interval: Option<Duration>, /* RATE LIMIT FOR BELLS, NONE SENDS EVERY ONE */
+    pub session_policy: SessionPolicy
 }
 
+/* How often a pending Incremental Update Request is checked for changes */
+const UPDATE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
 struct ClientToServerMessage;
 impl ClientToServerMessage {
     const SET_PIXEL_FORMAT: u8 = 0;
@@ -58,6 +91,15 @@ impl ClientToServerMessage {
     const KEY_EVENT: u8 = 4;
     const POINTER_EVENT: u8 = 5;
     const CLIENT_CUT_TEXT: u8 = 6;
+    const ENABLE_CONTINUOUS_UPDATES: u8 = 150;
+    const FENCE: u8 = 248;
+    const SET_DESKTOP_SIZE: u8 = 251;
+    const QEMU_CLIENT_MESSAGE: u8 = 255;
+}
+
+struct QEMUClientMessage;
+impl QEMUClientMessage {
+    const EXTENDED_KEY_EVENT: u8 = 0;
 }
 
 pub struct ServerToClientMessage;
@@ -66,6 +108,19 @@ impl ServerToClientMessage {
     pub const SET_COLOR_MAP_ENTRIES: u8 = 1;
     pub const BELL: u8 = 2;
     pub const SERVER_CUT_TEXT: u8 = 3;
+    pub const END_OF_CONTINUOUS_UPDATES: u8 = 150;
+    pub const FENCE: u8 = 248;
+}
+
+struct FenceFlags;
+impl FenceFlags {
+    const BLOCK_BEFORE: u32 = 1;
+    const BLOCK_AFTER: u32 = 2;
+    const SYNC_NEXT: u32 = 4;
+    const REQUEST: u32 = 1 << 31;
+
+    /* Messages are handled one at a time, in order. SyncNext replies wait for the next Message */
+    const SUPPORTED: u32 = FenceFlags::BLOCK_BEFORE | FenceFlags::BLOCK_AFTER | FenceFlags::SYNC_NEXT;
 }
 
 struct RFBError {
@@ -73,14 +128,62 @@ struct RFBError {
     reason_string: String,
 }
 
+/* Version agreed on during the ProtocolVersion handshake */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum RFBVersion {
+    V3_3,
+    V3_7,
+    V3_8,
+}
+
+impl RFBVersion {
+    fn from_protocol_version(protocol_version: &[u8; 12]) -> Option<RFBVersion> {
+        /* "RFB 003.xxx\n", unknown minor versions are treated as 3.3 */
+        if !protocol_version.starts_with(b"RFB 003.") || protocol_version[11] != b'\n' {
+            return Option::None;
+        }
+
+        match &protocol_version[8..11] {
+            b"008" | b"889" => Option::Some(RFBVersion::V3_8), /* 3.889: APPLE REMOTE DESKTOP */
+            b"007" => Option::Some(RFBVersion::V3_7),
+            minor_version if minor_version.iter().all(u8::is_ascii_digit) => Option::Some(RFBVersion::V3_3),
+            _ => Option::None
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct VNCAuth {
     pub security_key: [u8; 8]
 }
 
+#[derive(Clone)]
+pub struct PlainAuth {
+    pub backend: Arc<dyn CredentialBackend>
+}
+
 #[derive(Clone)]
 pub enum RFBAuthentication {
-    Vnc(VNCAuth)
+    Vnc(VNCAuth),
+    Plain(PlainAuth) /* VENCRYPT OR RSA-AES ONLY */
+}
+
+/* Created once in create(), shared by every Connection */
+#[derive(Clone, Default)]
+struct EncryptionContext {
+    tls_acceptor: Option<TlsAcceptor>, /* VENCRYPT */
+    rsa_key: Option<Arc<RsaPrivateKey>>, /* RSA-AES */
+}
+
+struct SecurityType;
+impl SecurityType {
+    const NONE: u8 = 1;
+    const VNC_AUTHENTICATION: u8 = 2;
+    const RA2: u8 = 5;
+    const RA2NE: u8 = 6;
+    const VENCRYPT: u8 = 19;
+    const RA2_256: u8 = 129;
+    const RA2NE_256: u8 = 130;
 }
 
 #[derive(Debug, Clone, Copy, Default)]
@@ -98,6 +201,22 @@ pub struct PixelFormat {
     pub(crate) padding: [u8; 3], /* THREE BYTES */
 }
 
+impl PixelFormat {
+    fn is_valid(&self) -> bool {
+        /* Channels must fit inside 8, 16 or 32 bit Pixels */
+        let channel_fits = |max: u16, shift: u8| {
+            shift < self.bits_per_pixel && ((max as u64) << shift) >> self.bits_per_pixel == 0
+        };
+
+        matches!(self.bits_per_pixel, 8 | 16 | 32)
+            && (self.true_color_flag == 0 || (
+                channel_fits(self.red_max, self.red_shift)
+                    && channel_fits(self.green_max, self.green_shift)
+                    && channel_fits(self.blue_max, self.blue_shift)
+            ))
+    }
+}
+
 #[derive(Debug)]
 pub struct RFBServerInit {
     pub(crate) framebuffer_width: u16,
@@ -112,11 +231,115 @@ impl RFBEncodingType {
     pub const RAW: i32 = 0;
     pub const COPY_RECT: i32 = 1;
     pub const RRE: i32 = 2;
+    pub const CORRE: i32 = 4;
     pub const HEX_TILE: i32 = 5;
     pub const ZLIB: i32 = 6;
     pub const TIGHT: i32 = 7;
     pub const TRLE: i32 = 15;
     pub const ZRLE: i32 = 16;
+    pub const TIGHT_PNG: i32 = -260; /* AN ENCODING, DESPITE THE NUMBER */
+
+    /* Pseudo-encodings */
+    pub const COMPRESS_LEVEL_0: i32 = -256;
+    pub const QUALITY_LEVEL_0: i32 = -32;
+    pub const DESKTOP_SIZE: i32 = -223;
+    pub const POINTER_POS: i32 = -232;
+    pub const CURSOR: i32 = -239;
+    pub const X_CURSOR: i32 = -240;
+    pub const QEMU_EXTENDED_KEY_EVENT: i32 = -258;
+    pub const DESKTOP_NAME: i32 = -307;
+    pub const EXTENDED_DESKTOP_SIZE: i32 = -308;
+    pub const FENCE: i32 = -312;
+    pub const CONTINUOUS_UPDATES: i32 = -313;
+    pub const EXTENDED_CLIPBOARD: i32 = 0xC0A1E5CE_u32 as i32;
+
+    /* Encodings we can produce, in our own order of preference */
+    pub const SUPPORTED: &[i32] = &[
+        RFBEncodingType::TIGHT_PNG,
+        RFBEncodingType::TIGHT,
+        RFBEncodingType::ZRLE,
+        RFBEncodingType::TRLE,
+        RFBEncodingType::ZLIB,
+        RFBEncodingType::HEX_TILE,
+        RFBEncodingType::CORRE,
+        RFBEncodingType::RRE,
+        RFBEncodingType::RAW,
+    ];
+
+    pub fn is_pseudo_encoding(encoding: i32) -> bool {
+        /* Real encodings are small positive numbers, everything else is a capability */
+        encoding != RFBEncodingType::TIGHT_PNG && !(0..=255).contains(&encoding)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RFBEncodings {
+    pub(crate) encodings: Vec<i32>, /* CLIENT ORDER, MOST PREFERRED FIRST */
+    pub(crate) pseudo_encodings: HashSet<i32>,
+    pub(crate) browser_client: bool, /* CONNECTED THROUGH THE WEBSOCKET PROXY */
+}
+
+impl RFBEncodings {
+    fn from_encoding_list(encoding_list: &[u8], browser_client: bool) -> RFBEncodings {
+        let mut client_encodings = RFBEncodings { browser_client, ..Default::default() };
+        for encoding in encoding_list.chunks_exact(4) {
+            let encoding = i32::from_be_bytes([encoding[0], encoding[1], encoding[2], encoding[3]]);
+            if RFBEncodingType::is_pseudo_encoding(encoding) {
+                client_encodings.pseudo_encodings.insert(encoding);
+            } else if !client_encodings.encodings.contains(&encoding) {
+                client_encodings.encodings.push(encoding);
+            }
+        }
+
+        client_encodings
+    }
+
+    pub(crate) fn preferred_encoding(&self) -> i32 {
+        /* Browsers (noVNC) decode PNG natively, TightPNG wins wherever they list it */
+        if self.browser_client && self.encodings.contains(&RFBEncodingType::TIGHT_PNG) {
+            return RFBEncodingType::TIGHT_PNG;
+        }
+
+        /* First one we support in the Client's order, RAW must be supported by every client */
+        self.encodings
+            .iter()
+            .find(|encoding| RFBEncodingType::SUPPORTED.contains(encoding))
+            .copied()
+            .unwrap_or(RFBEncodingType::RAW)
+    }
+
+    pub(crate) fn has_pseudo_encoding(&self, pseudo_encoding: i32) -> bool {
+        self.pseudo_encodings.contains(&pseudo_encoding)
+    }
+
+    pub(crate) fn supports_desktop_resize(&self) -> bool {
+        self.has_pseudo_encoding(RFBEncodingType::EXTENDED_DESKTOP_SIZE)
+            || self.has_pseudo_encoding(RFBEncodingType::DESKTOP_SIZE)
+    }
+
+    pub(crate) fn cursor_encoding(&self) -> Option<i32> {
+        /* Rich Cursor keeps the colours, XCursor is the two-colour fallback */
+        [RFBEncodingType::CURSOR, RFBEncodingType::X_CURSOR]
+            .into_iter()
+            .find(|encoding| self.has_pseudo_encoding(*encoding))
+    }
+
+    fn pseudo_encoding_level(&self, first_level: i32) -> Option<i32> {
+        /* Levels are ten consecutive pseudo-encodings, level 0 first */
+        self.pseudo_encodings
+            .iter()
+            .filter(|encoding| (first_level..(first_level + 10)).contains(encoding))
+            .max()
+            .map(|encoding| encoding - first_level)
+    }
+
+    pub(crate) fn compress_level(&self) -> Option<i32> {
+        self.pseudo_encoding_level(RFBEncodingType::COMPRESS_LEVEL_0)
+    }
+
+    pub(crate) fn quality_level(&self) -> Option<i32> {
+        self.pseudo_encoding_level(RFBEncodingType::QUALITY_LEVEL_0)
+    }
 }
 
 #[derive(Debug)]
@@ -183,8 +406,67 @@ fn create_rfb_error(reason_string: String) -> RFBError {
     }
 }
 
+async fn write_rfb_error(client: &mut RFBStream, reason_string: &str) {
+    let rfb_error = create_rfb_error(String::from(reason_string));
+    client
+        .write_u32(rfb_error.reason_length)
+        .await
+        .unwrap_or(());
+    client
+        .write_all(rfb_error.reason_string.as_bytes())
+        .await
+        .unwrap_or(());
+    client.flush().await.unwrap_or(());
+}
+
+async fn write_security_result(client: &mut RFBStream, version: RFBVersion, failure_reason: Option<&str>) {
+    match failure_reason {
+        Option::None => client.write_u32(0).await.unwrap_or(()),
+        Option::Some(reason_string) => {
+            client.write_u32(1).await.unwrap_or(());
+
+            /* Failure Reason only exists from RFB Version 3.8 */
+            if version == RFBVersion::V3_8 {
+                write_rfb_error(client, reason_string).await;
+            }
+        }
+    }
+
+    client.flush().await.unwrap_or(());
+}
+
+async fn write_fence_message(client_tx: &mut WriteHalf<RFBStream>, flags: u32, payload: &[u8]) {
+    /* message-type, padding (U8 x 3), flags (U32), length (U8), payload */
+    let mut fence_message: Vec<u8> = vec![ServerToClientMessage::FENCE, 0, 0, 0];
+    fence_message.extend_from_slice(&flags.to_be_bytes());
+    fence_message.push(payload.len() as u8);
+    fence_message.extend_from_slice(payload);
+    client_tx.write_all(&fence_message).await.unwrap_or(());
+}
+
+async fn write_color_map_entries(client_tx: &mut WriteHalf<RFBStream>, first_colour: u16, colours: &[[u16; 3]]) {
+    /* message-type, padding, first-colour (U16), number-of-colours (U16), then R, G, B (U16) per colour */
+    let mut color_map_message: Vec<u8> = vec![ServerToClientMessage::SET_COLOR_MAP_ENTRIES, 0];
+    color_map_message.extend_from_slice(&first_colour.to_be_bytes());
+    color_map_message.extend_from_slice(&(colours.len() as u16).to_be_bytes());
+    for colour in colours {
+        for channel in colour {
+            color_map_message.extend_from_slice(&channel.to_be_bytes());
+        }
+    }
+
+    client_tx.write_all(&color_map_message).await.unwrap_or(());
+}
+
+async fn write_end_of_continuous_updates(client_tx: &mut WriteHalf<RFBStream>) {
+    client_tx
+        .write_u8(ServerToClientMessage::END_OF_CONTINUOUS_UPDATES)
+        .await
+        .unwrap_or(());
+}
+
 async fn write_framebuffer_update_message(
-    client_tx: &mut WriteHalf<'_>,
+    client_tx: &mut WriteHalf<RFBStream>,
     frame_buffer: FrameBufferUpdate,
 ) {
     client_tx
@@ -248,15 +530,156 @@ async fn write_framebuffer_update_message(
     //debug::l1(format!("FBU Response Time: {:?}", debug::time_now()));
 }
 
+/* Encodes a Rectangle with the Client's preferred Encoding, for every Platform */
+pub(crate) fn encode_rectangle(
+    client_encodings: &RFBEncodings,
+    mut framebuffer_struct: FrameBuffer,
+    server_pixelformat: PixelFormat,
+    pixelformat: PixelFormat,
+    zstream_id: String
+) -> Vec<FrameBufferRectangle> {
+    let encoding_type = client_encodings.preferred_encoding();
+
+    /* Captured Pixels to the Client's PixelFormat */
+    let client_pixels = translate::translate_pixels(&framebuffer_struct.raw_pixels, &server_pixelformat, &pixelformat);
+    let pixformat_data: Vec<u8> = match encoding_type {
+        /* CPIXELs for ZRLE and TRLE, TPIXELs for Tight */
+        RFBEncodingType::ZRLE | RFBEncodingType::TRLE => translate::get_cpixels(&client_pixels, &pixelformat),
+        RFBEncodingType::TIGHT => translate::get_tpixels(&client_pixels, &pixelformat),
+        RFBEncodingType::TIGHT_PNG => translate::get_rgb_pixels(&framebuffer_struct.raw_pixels, &server_pixelformat),
+        _ => vec![]
+    };
+
+    framebuffer_struct.raw_pixels = client_pixels;
+    framebuffer_struct.bits_per_pixel = pixelformat.bits_per_pixel;
+
+    match encoding_type {
+        RFBEncodingType::ZRLE => {
+            framebuffer_struct.encoding = RFBEncodingType::ZRLE;
+            framebuffer_struct.encoded_pixels = pixformat_data;
+            vec![encoding_zrle::get_pixel_data(framebuffer_struct, zstream_id)]
+        },
+        RFBEncodingType::TIGHT => {
+            framebuffer_struct.encoding = RFBEncodingType::TIGHT;
+            framebuffer_struct.encoded_pixels = pixformat_data;
+            encoding_tight::get_pixel_data(
+                framebuffer_struct,
+                zstream_id,
+                client_encodings.compress_level(),
+                client_encodings.quality_level()
+            )
+        },
+        RFBEncodingType::TIGHT_PNG => {
+            framebuffer_struct.encoding = RFBEncodingType::TIGHT_PNG;
+            framebuffer_struct.encoded_pixels = pixformat_data;
+            encoding_tight::get_png_pixel_data(
+                framebuffer_struct,
+                client_encodings.compress_level(),
+                client_encodings.quality_level()
+            )
+        },
+        RFBEncodingType::TRLE => {
+            framebuffer_struct.encoding = RFBEncodingType::TRLE;
+            framebuffer_struct.encoded_pixels = pixformat_data;
+            vec![encoding_trle::get_pixel_data(framebuffer_struct)]
+        },
+        RFBEncodingType::ZLIB => {
+            framebuffer_struct.encoding = RFBEncodingType::ZLIB;
+            vec![encoding_zlib::get_pixel_data(framebuffer_struct, zstream_id)]
+        },
+        RFBEncodingType::HEX_TILE => {
+            framebuffer_struct.encoding = RFBEncodingType::HEX_TILE;
+            vec![encoding_hextile::get_pixel_data(framebuffer_struct)]
+        },
+        RFBEncodingType::RRE => {
+            framebuffer_struct.encoding = RFBEncodingType::RRE;
+            vec![encoding_rre::get_pixel_data(framebuffer_struct)]
+        },
+        RFBEncodingType::CORRE => {
+            framebuffer_struct.encoding = RFBEncodingType::CORRE;
+            encoding_rre::get_corre_pixel_data(framebuffer_struct)
+        }
+        _ => {
+            framebuffer_struct.encoding = RFBEncodingType::RAW;
+            vec![encoding_raw::get_pixel_data(framebuffer_struct)]
+        }
+    }
+}
+
+async fn write_requested_framebuffer_update(
+    client_tx: &mut WriteHalf<RFBStream>,
+    buffer: &[u8],
+    pixelformat: PixelFormat,
+    client_encodings: &RFBEncodings,
+    visible_size: (u16, u16),
+    zstream_id: String,
+    wm: Arc<WindowManager>
+) -> bool {
+    let incremental: bool = buffer[0] != 0;
+    let x_position: u16 = ((buffer[1] as u16) << 8) | buffer[2] as u16;
+    let y_position: u16 = ((buffer[3] as u16) << 8) | buffer[4] as u16;
+    let width: u16 = ((buffer[5] as u16) << 8) | buffer[6] as u16;
+    let height: u16 = ((buffer[7] as u16) << 8) | buffer[8] as u16;
+
+    /* The Screen may have shrunk since the Client learnt its Size */
+    let width = width.min(visible_size.0.saturating_sub(x_position));
+    let height = height.min(visible_size.1.saturating_sub(y_position));
+    if width == 0 || height == 0 {
+        return false;
+    }
+
+    let framebuffer_update = match wm.as_ref() {
+        #[cfg(target_os = "windows")]
+        WindowManager::WIN32(win32_server) => {
+            win32::rectangle_framebuffer_update(
+                win32_server,
+                win32_server.monitors[0].clone(),
+                client_encodings,
+                incremental,
+                x_position as i16,
+                y_position as i16,
+                width,
+                height,
+                pixelformat,
+                zstream_id
+            )
+        },
+        #[cfg(target_os = "linux")]
+        WindowManager::X11(x11_server) => {
+            x11::rectangle_framebuffer_update(
+                x11_server,
+                x11_server.displays[0].clone(),
+                client_encodings,
+                incremental,
+                x_position as i16,
+                y_position as i16,
+                width,
+                height,
+                pixelformat,
+                zstream_id
+            )
+        }
+    };
+
+    if framebuffer_update.number_of_rectangles == 0 {
+        /* Nothing Changed, Hold the Reply until something does */
+        return false;
+    }
+
+    write_framebuffer_update_message(client_tx, framebuffer_update).await;
+    true
+}
+
 async fn process_clientserver_message(
-    _client_rx: &mut ReadHalf<'_>,
-    client_tx: &mut WriteHalf<'_>,
+    client_tx: &mut WriteHalf<RFBStream>,
     opcode: &[u8],
     buffer: &[u8],
     pixelformat: PixelFormat,
+    client_encodings: &RFBEncodings,
     zstream_id: String,
     wm: Arc<WindowManager>
 ) {
+    let encoding_type = client_encodings.preferred_encoding();
     match opcode[0] {
         ClientToServerMessage::SET_PIXEL_FORMAT => {
             /* Send Framebuffer Update */
@@ -269,7 +692,8 @@ async fn process_clientserver_message(
                         win32::rectangle_framebuffer_update(
                             win32_server,
                             win32_monitor.clone(),
-                            RFBEncodingType::ZRLE,
+                            client_encodings,
+                            false,
                             0,
                             0,
                             win32_monitor.monitor_devmode.dmPelsWidth as u16,
@@ -283,16 +707,18 @@ async fn process_clientserver_message(
                 #[cfg(target_os = "linux")]
                 WindowManager::X11(x11_server) => {
                     let x11_screen = x11_server.displays[0].clone();
+                    let (screen_width, screen_height) = x11::get_screen_size(&x11_server.connection, &x11_screen);
                     write_framebuffer_update_message(
                         client_tx,
                         x11::rectangle_framebuffer_update(
                             &x11_server,
                             x11_screen.clone(),
-                            RFBEncodingType::ZRLE,
+                            client_encodings,
+                            false,
                             0,
                             0,
-                            x11_screen.width_in_pixels,
-                            x11_screen.height_in_pixels,
+                            screen_width,
+                            screen_height,
                             pixelformat,
                             zstream_id
                         ),
@@ -302,53 +728,12 @@ async fn process_clientserver_message(
             }
         }
         ClientToServerMessage::SET_ENCODINGS => {
-            debug::l1(format!("Set Encodings Request"));
-        }
-        ClientToServerMessage::FRAME_BUFFER_UPDATE_REQUEST => {
-            /* let incremental: u8 = message[0]; */
-            let x_position: u16 = ((buffer[1] as u16) << 8) | buffer[2] as u16;
-            let y_position: u16 = ((buffer[3] as u16) << 8) | buffer[4] as u16;
-            let width: u16 = ((buffer[5] as u16) << 8) | buffer[6] as u16;
-            let height: u16 = ((buffer[7] as u16) << 8) | buffer[8] as u16;
-
-            match wm.as_ref() {
-                #[cfg(target_os = "windows")]
-                WindowManager::WIN32(win32_server) => {
-                    write_framebuffer_update_message(
-                        client_tx,
-                        win32::rectangle_framebuffer_update(
-                            win32_server,
-                            win32_server.monitors[0].clone(),
-                            RFBEncodingType::ZRLE,
-                            x_position as i16,
-                            y_position as i16,
-                            width,
-                            height,
-                            pixelformat,
-                            zstream_id
-                        ),
-                    )
-                    .await;
-                },
-                #[cfg(target_os = "linux")]
-                WindowManager::X11(x11_server) => {
-                    write_framebuffer_update_message(
-                        client_tx,
-                        x11::rectangle_framebuffer_update(
-                            &x11_server,
-                            x11_server.displays[0].clone(),
-                            RFBEncodingType::ZRLE,
-                            x_position as i16,
-                            y_position as i16,
-                            width,
-                            height,
-                            pixelformat,
-                            zstream_id
-                        ),
-                    )
-                    .await;
-                }
-            }
+            debug::l1(format!(
+                "Set Encodings Request: {:?}, Pseudo: {:?}, Using: {}",
+                client_encodings.encodings,
+                client_encodings.pseudo_encodings,
+                encoding_type
+            ));
         }
         ClientToServerMessage::POINTER_EVENT => match wm.as_ref() {
             #[cfg(target_os = "windows")]
@@ -417,40 +802,150 @@ async fn process_clientserver_message(
                 | (buffer[5] as u32) << 8
                 | (buffer[6] as u32);
 
-            match wm.as_ref() {
-                #[cfg(target_os = "windows")]
-                WindowManager::WIN32(win32_server) => {
-                    /* SEND WIN32 KEYPRESS EVENT */
-                    win32::fire_key_event(win32_server, key_sym, down_flag);
-                },
-                #[cfg(target_os = "linux")]
-                WindowManager::X11(x11_server) => {
-                    x11::fire_key_event(
-                        &x11_server, 
-                        x11_server.displays[0].clone(), 
-                        x11::X11KeyEvent {
-                            key_down: down_flag,
-                            key_sym,
-                        }
-                    );
-                }
+            fire_key_event(&wm, down_flag, key_sym, Option::None);
+        }
+        ClientToServerMessage::QEMU_CLIENT_MESSAGE if buffer[0] == QEMUClientMessage::EXTENDED_KEY_EVENT => {
+            /* submessage-type, down-flag (U16), keysym (U32), keycode (U32) */
+            let down_flag: u8 = (buffer[1] | buffer[2] != 0).into();
+            let key_sym = u32::from_be_bytes([buffer[3], buffer[4], buffer[5], buffer[6]]);
+            let key_code = u32::from_be_bytes([buffer[7], buffer[8], buffer[9], buffer[10]]);
+
+            fire_key_event(&wm, down_flag, key_sym, if key_code != 0 { Option::Some(key_code) } else { Option::None });
+        }
+        ClientToServerMessage::CLIENT_CUT_TEXT => {
+            if let Some(cut_text) = clipboard::parse_client_cut_text(buffer) {
+                set_clipboard(&wm, ClipboardData::from_text(cut_text), zstream_id);
             }
         }
-        ClientToServerMessage::CLIENT_CUT_TEXT => {}
         _ => {}
     }
 }
 
-async fn init_clientserver_handshake(mut client: TcpStream, wm: Arc<WindowManager>) {
-    /* Session Statics */
-    let (mut client_rx, mut client_tx) = client.split();
+fn set_clipboard(wm: &WindowManager, clipboard_data: ClipboardData, zstream_id: String) {
+    match wm {
+        #[cfg(target_os = "windows")]
+        WindowManager::WIN32(_win32_server) => { /* WIN32 CLIPBOARD IS NOT SHARED YET */ },
+        #[cfg(target_os = "linux")]
+        WindowManager::X11(_x11_server) => {
+            x11::set_clipboard(clipboard_data.clone());
+        }
+    }
+
+    /* Other Clients get the Text as ServerCutText */
+    events::broadcast(DesktopEvent::ClipboardChanged(clipboard_data, Option::Some(zstream_id)));
+}
+
+fn fire_key_event(wm: &WindowManager, down_flag: u8, key_sym: u32, key_code: Option<u32>) {
+    match wm {
+        #[cfg(target_os = "windows")]
+        WindowManager::WIN32(win32_server) => {
+            /* SEND WIN32 KEYPRESS EVENT */
+            win32::fire_key_event(win32_server, key_sym, down_flag);
+        },
+        #[cfg(target_os = "linux")]
+        WindowManager::X11(x11_server) => {
+            x11::fire_key_event(
+                &x11_server, 
+                x11_server.displays[0].clone(), 
+                x11::X11KeyEvent {
+                    key_down: down_flag,
+                    key_sym,
+                    key_code,
+                }
+            );
+        }
+    }
+}
+
+fn get_desktop_size(wm: &WindowManager) -> (u16, u16) {
+    match wm {
+        #[cfg(target_os = "windows")]
+        WindowManager::WIN32(win32_server) => {
+            let win32_monitor = &win32_server.monitors[0];
+            (
+                win32_monitor.monitor_devmode.dmPelsWidth as u16,
+                win32_monitor.monitor_devmode.dmPelsHeight as u16
+            )
+        },
+        #[cfg(target_os = "linux")]
+        WindowManager::X11(x11_server) => {
+            x11::get_screen_size(&x11_server.connection, &x11_server.displays[0])
+        }
+    }
+}
+
+/* Pseudo-rectangles waiting for the next Request */
+#[derive(Default)]
+struct PendingPseudoUpdate {
+    desktop_size: Option<(u16, u16)>, /* (REASON, STATUS) */
+    desktop_name: Option<String>,
+    cursor: Option<CursorImage>,
+    pointer_position: Option<(u16, u16)>,
+    extended_key_event: bool, /* CONFIRMS QEMU EXTENDED KEY EVENTS */
+}
+
+impl PendingPseudoUpdate {
+    fn is_empty(&self) -> bool {
+        self.desktop_size.is_none()
+            && self.desktop_name.is_none()
+            && self.cursor.is_none()
+            && self.pointer_position.is_none()
+            && !self.extended_key_event
+    }
+}
+
+fn get_desktop_update(
+    client_encodings: &RFBEncodings,
+    pixelformat: PixelFormat,
+    framebuffer_size: (u16, u16),
+    pending_pseudo: PendingPseudoUpdate
+) -> FrameBufferUpdate {
+    let mut pseudo_rectangles: Vec<FrameBufferRectangle> = vec![];
+    if let (Some(cursor), Some(cursor_encoding)) = (pending_pseudo.cursor, client_encodings.cursor_encoding()) {
+        pseudo_rectangles.push(encoding_cursor::get_cursor_rectangle(&cursor, cursor_encoding, pixelformat));
+    }
+
+    if let Some((x_position, y_position)) = pending_pseudo.pointer_position {
+        pseudo_rectangles.push(encoding_cursor::get_pointer_position_rectangle(x_position, y_position));
+    }
+
+    if pending_pseudo.extended_key_event {
+        pseudo_rectangles.push(encoding_desktop::get_capability_rectangle(RFBEncodingType::QEMU_EXTENDED_KEY_EVENT));
+    }
+
+    if let Some(name_string) = pending_pseudo.desktop_name {
+        pseudo_rectangles.push(encoding_desktop::get_desktop_name_rectangle(&name_string));
+    }
+
+    if let Some((reason, status)) = pending_pseudo.desktop_size {
+        let (width, height) = framebuffer_size;
+        if client_encodings.has_pseudo_encoding(RFBEncodingType::EXTENDED_DESKTOP_SIZE) {
+            pseudo_rectangles.push(encoding_desktop::get_extended_desktop_size_rectangle(reason, status, width, height));
+        } else if status == ResizeStatus::NO_ERROR {
+            pseudo_rectangles.push(encoding_desktop::get_desktop_size_rectangle(width, height));
+        }
+    }
+
+    FrameBufferUpdate {
+        message_type: ServerToClientMessage::FRAME_BUFFER_UPDATE,
+        padding: 0,
+        number_of_rectangles: pseudo_rectangles.len() as u16,
+        frame_buffer: pseudo_rectangles,
+    }
+}
+
+async fn init_clientserver_handshake(client: RFBStream, wm: Arc<WindowManager>, zstream_id: String) {
+    /* Session Statics, the Proxy registered itself before relaying the Handshake */
+    let browser_client = client.peer_addr().map(websocket::is_proxied).unwrap_or(false);
+    let (mut client_rx, mut client_tx) = io::split(client);
 
-    /* Create Endpoint Specific ZLib Stream, PixelFormat */
-    let zstream_id = Uuid::new_v4().to_string();
-    encoding_zlib::create_stream(zstream_id.clone());
+    /* Create Endpoint Specific ZLib Streams (one per Encoding), PixelFormat */
+    encoding_zlib::create_stream(encoding_zlib::stream_name(&zstream_id));
+    encoding_zlib::create_stream(encoding_zrle::stream_name(&zstream_id));
     
     #[allow(unused_assignments)]
     let mut pixel_format: PixelFormat = Default::default();
+    let mut client_encodings: RFBEncodings = Default::default();
 
     #[cfg(target_os = "windows")]
     { pixel_format = win32::get_pixelformat() }
@@ -466,115 +961,424 @@ async fn init_clientserver_handshake(mut client: TcpStream, wm: Arc<WindowManage
         }
     }
 
+    /* Incremental Requests wait here until the Screen changes */
+    let mut pending_update_request: Option<[u8; 9]> = Option::None;
+    let mut next_update_check = Instant::now();
+
+    /* Size the Client knows about, and the Size of the Screen right now */
+    let mut framebuffer_size = get_desktop_size(&wm);
+    let mut screen_size = framebuffer_size;
+
+    let mut pending_pseudo: PendingPseudoUpdate = Default::default();
+
+    /* ContinuousUpdates: the Region is kept as a standing Incremental Request */
+    let mut continuous_update_request: Option<[u8; 9]> = Option::None;
+
+    /* Fence Request with SyncNext: flags and payload, answered after the next Message */
+    let mut pending_sync_fence: Option<(u32, Vec<u8>)> = Option::None;
+
+    /* Extended Clipboard: what the Client can receive */
+    let mut client_clipboard_caps: ClipboardCaps = Default::default();
+
+    /* Where this Client last put the Pointer, not echoed back as PointerPos */
+    let mut client_pointer_position: Option<(u16, u16)> = Option::None;
+    events::register_client(zstream_id.clone());
+
+    /* View-only Clients have their Input read and dropped */
+    let view_only = sessions::access_level(&zstream_id) == AccessLevel::ViewOnly;
+
     loop {
+        if sessions::disconnect_requested(&zstream_id) {
+            debug::l1(format!("Client Disconnected by an Exclusive Session"));
+            break;
+        }
+
         let mut opcode: [u8; 1] = [0; 1];
-        let payload_result = client_rx.read_exact(&mut opcode).await;
-        if payload_result.unwrap_or(0) != 0 {
-            match opcode[0] {
-                ClientToServerMessage::SET_PIXEL_FORMAT => {
-                    let mut buffer: [u8; 19] = [0; 19];
-                    client_rx.read_exact(&mut buffer).await.unwrap();
+        let rx_timeout = timeout(
+            UPDATE_POLL_INTERVAL,
+            client_rx.read_exact(&mut opcode)
+        ).await;
+
+        if let Ok(payload_result) = rx_timeout {
+            if payload_result.unwrap_or(0) != 0 {
+                let sync_fence = pending_sync_fence.take();
+                match opcode[0] {
+                    ClientToServerMessage::SET_PIXEL_FORMAT => {
+                        let mut buffer: [u8; 19] = [0; 19];
+                        client_rx.read_exact(&mut buffer).await.unwrap();
                     
-                    /* Check if first three bytes are padding */
-                    if buffer[0] == 0 && buffer[1] == 0 && buffer[2] == 0 {
-                        let pfu = &buffer[3..];
-                        pixel_format = PixelFormat {
-                            bits_per_pixel: pfu[0],
-                            depth: pfu[1],
-                            big_endian_flag: pfu[2],
-                            true_color_flag: pfu[3],
-                            red_max: (pfu[4] as u16) << 8 | (pfu[5] as u16),
-                            green_max: (pfu[6] as u16) << 8 | (pfu[7] as u16),
-                            blue_max: (pfu[8] as u16) << 8 | (pfu[9] as u16),
-                            red_shift: pfu[10],
-                            green_shift: pfu[11],
-                            blue_shift: pfu[12],
-                            padding: [0, 0, 0],
-                        };
-                    } 
-
-                    process_clientserver_message(
-                        &mut client_rx, 
-                        &mut client_tx, 
-                        &opcode, 
-                        &buffer, 
-                        pixel_format,
-                        zstream_id.clone(), 
-                        wm.clone()
-                    )
-                    .await;
-                }
-                ClientToServerMessage::SET_ENCODINGS => {
-                    let mut buffer: [u8; 3] = [0; 3];
-                    client_rx.read_exact(&mut buffer).await.unwrap();
-                    process_clientserver_message(
-                        &mut client_rx,
-                        &mut client_tx,
-                        &opcode,
-                        &buffer,
-                        pixel_format,
-                        zstream_id.clone(),
-                        wm.clone()
-                    )
-                    .await;
-                }
-                ClientToServerMessage::FRAME_BUFFER_UPDATE_REQUEST => {
-                    //debug::l1(format!("FBU Request Time: {:?}", debug::time_now()));
-                    let mut buffer: [u8; 9] = [0; 9];
-                    client_rx.read_exact(&mut buffer).await.unwrap();
-                    process_clientserver_message(
-                        &mut client_rx,
-                        &mut client_tx,
-                        &opcode,
-                        &buffer,
-                        pixel_format,
-                        zstream_id.clone(),
-                        wm.clone()
-                    )
-                    .await;
+                        /* Check if first three bytes are padding */
+                        if buffer[0] == 0 && buffer[1] == 0 && buffer[2] == 0 {
+                            let pfu = &buffer[3..];
+                            let requested_format = PixelFormat {
+                                bits_per_pixel: pfu[0],
+                                depth: pfu[1],
+                                big_endian_flag: pfu[2],
+                                true_color_flag: pfu[3],
+                                red_max: (pfu[4] as u16) << 8 | (pfu[5] as u16),
+                                green_max: (pfu[6] as u16) << 8 | (pfu[7] as u16),
+                                blue_max: (pfu[8] as u16) << 8 | (pfu[9] as u16),
+                                red_shift: pfu[10],
+                                green_shift: pfu[11],
+                                blue_shift: pfu[12],
+                                padding: [0, 0, 0],
+                            };
+
+                            if requested_format.is_valid() {
+                                pixel_format = requested_format;
+
+                                /* Colour-map Clients need the Palette before any Pixels */
+                                if pixel_format.true_color_flag == 0 {
+                                    write_color_map_entries(&mut client_tx, 0, &translate::colour_map_entries()).await;
+                                }
+                            }
+                        } 
+
+                        process_clientserver_message(
+                            &mut client_tx, 
+                            &opcode, 
+                            &buffer, 
+                            pixel_format,
+                            &client_encodings,
+                            zstream_id.clone(), 
+                            wm.clone()
+                        )
+                        .await;
+                    }
+                    ClientToServerMessage::SET_ENCODINGS => {
+                        let mut buffer: [u8; 3] = [0; 3];
+                        client_rx.read_exact(&mut buffer).await.unwrap();
+
+                        /* Read Encoding List: number-of-encodings (U16) x S32 */
+                        let number_of_encodings = ((buffer[1] as u16) << 8) | buffer[2] as u16;
+                        let mut encoding_list: Vec<u8> = vec![0; number_of_encodings as usize * 4];
+                        client_rx.read_exact(&mut encoding_list).await.unwrap();
+                        let previous_encodings = client_encodings;
+                        client_encodings = RFBEncodings::from_encoding_list(&encoding_list, browser_client);
+                        events::set_pointer_watcher(&zstream_id, client_encodings.has_pseudo_encoding(RFBEncodingType::POINTER_POS));
+
+                        /* Clients learn about ExtendedDesktopSize support from the first one */
+                        let announce_extended = client_encodings.has_pseudo_encoding(RFBEncodingType::EXTENDED_DESKTOP_SIZE)
+                            && !previous_encodings.has_pseudo_encoding(RFBEncodingType::EXTENDED_DESKTOP_SIZE);
+
+                        if client_encodings.supports_desktop_resize() && (announce_extended || framebuffer_size != screen_size) {
+                            pending_pseudo.desktop_size = Option::Some((ResizeReason::SERVER, ResizeStatus::NO_ERROR));
+                        }
+
+                        if client_encodings.has_pseudo_encoding(RFBEncodingType::EXTENDED_CLIPBOARD)
+                            && !previous_encodings.has_pseudo_encoding(RFBEncodingType::EXTENDED_CLIPBOARD) {
+                            client_clipboard_caps = Default::default();
+                            clipboard::write_caps(&mut client_tx).await;
+                        }
+
+                        /* Servers confirm ContinuousUpdates and Fence support with the matching Message */
+                        if client_encodings.has_pseudo_encoding(RFBEncodingType::CONTINUOUS_UPDATES)
+                            && !previous_encodings.has_pseudo_encoding(RFBEncodingType::CONTINUOUS_UPDATES) {
+                            write_end_of_continuous_updates(&mut client_tx).await;
+                        }
+
+                        if client_encodings.has_pseudo_encoding(RFBEncodingType::FENCE)
+                            && !previous_encodings.has_pseudo_encoding(RFBEncodingType::FENCE) {
+                            write_fence_message(&mut client_tx, FenceFlags::REQUEST | FenceFlags::SUPPORTED, &[]).await;
+                        }
+
+                        /* A Confirmation still waiting for the next Update stays pending */
+                        pending_pseudo.extended_key_event |= client_encodings.has_pseudo_encoding(RFBEncodingType::QEMU_EXTENDED_KEY_EVENT)
+                            && !previous_encodings.has_pseudo_encoding(RFBEncodingType::QEMU_EXTENDED_KEY_EVENT);
+
+                        /* Send the current Shape as soon as a Cursor pseudo-encoding is enabled */
+                        if client_encodings.cursor_encoding().is_some() && client_encodings.cursor_encoding() != previous_encodings.cursor_encoding() {
+                            pending_pseudo.cursor = events::current_cursor();
+                        }
+
+                        process_clientserver_message(
+                            &mut client_tx,
+                            &opcode,
+                            &buffer,
+                            pixel_format,
+                            &client_encodings,
+                            zstream_id.clone(),
+                            wm.clone()
+                        )
+                        .await;
+                    }
+                    ClientToServerMessage::FRAME_BUFFER_UPDATE_REQUEST => {
+                        //debug::l1(format!("FBU Request Time: {:?}", debug::time_now()));
+                        let mut buffer: [u8; 9] = [0; 9];
+                        client_rx.read_exact(&mut buffer).await.unwrap();
+
+                        /* Newer Requests replace older ones, Answer right away */
+                        pending_update_request = Option::Some(buffer);
+                        next_update_check = Instant::now();
+                    }
+                    ClientToServerMessage::POINTER_EVENT => {
+                        let mut buffer: [u8; 5] = [0; 5];
+                        client_rx.read_exact(&mut buffer).await.unwrap();
+                        if !view_only {
+                            client_pointer_position = Option::Some((
+                                ((buffer[1] as u16) << 8) | buffer[2] as u16,
+                                ((buffer[3] as u16) << 8) | buffer[4] as u16
+                            ));
+
+                            process_clientserver_message(
+                                &mut client_tx,
+                                &opcode,
+                                &buffer,
+                                pixel_format,
+                                &client_encodings,
+                                zstream_id.clone(),
+                                wm.clone()
+                            )
+                            .await;
+                        }
+                    }
+                    ClientToServerMessage::KEY_EVENT => {
+                        let mut buffer: [u8; 7] = [0; 7];
+                        client_rx.read_exact(&mut buffer).await.unwrap();
+                        if !view_only {
+                            process_clientserver_message(
+                                &mut client_tx,
+                                &opcode,
+                                &buffer,
+                                pixel_format.clone(),
+                                &client_encodings,
+                                zstream_id.clone(),
+                                wm.clone()
+                            )
+                            .await;
+                        }
+                    }
+                    ClientToServerMessage::QEMU_CLIENT_MESSAGE => {
+                        /* The submessage-type decides the Length, only Extended Key Event is known */
+                        let mut buffer: [u8; 11] = [0; 11];
+                        client_rx.read_exact(&mut buffer[..1]).await.unwrap();
+                        if buffer[0] != QEMUClientMessage::EXTENDED_KEY_EVENT {
+                            debug::l1(format!("Unknown QEMU Client Message {}, Closing Connection", buffer[0]));
+                            break;
+                        }
+
+                        client_rx.read_exact(&mut buffer[1..]).await.unwrap();
+                        if !view_only {
+                            process_clientserver_message(
+                                &mut client_tx,
+                                &opcode,
+                                &buffer,
+                                pixel_format,
+                                &client_encodings,
+                                zstream_id.clone(),
+                                wm.clone()
+                            )
+                            .await;
+                        }
+                    }
+                    ClientToServerMessage::SET_DESKTOP_SIZE => {
+                        let mut buffer: [u8; 7] = [0; 7];
+                        client_rx.read_exact(&mut buffer).await.unwrap();
+
+                        /* Skip the Screen Layout: number-of-screens x 16 bytes */
+                        let mut screen_layout: Vec<u8> = vec![0; buffer[5] as usize * 16];
+                        client_rx.read_exact(&mut screen_layout).await.unwrap();
+
+                        /* The Screen is resized on the Server only */
+                        pending_pseudo.desktop_size = Option::Some((ResizeReason::CLIENT, ResizeStatus::PROHIBITED));
+                    }
+                    ClientToServerMessage::ENABLE_CONTINUOUS_UPDATES => {
+                        /* enable-flag, x, y, width, height: laid out like an Update Request */
+                        let mut buffer: [u8; 9] = [0; 9];
+                        client_rx.read_exact(&mut buffer).await.unwrap();
+
+                        if buffer[0] != 0 {
+                            continuous_update_request = Option::Some(buffer);
+                        } else {
+                            continuous_update_request = Option::None;
+                            write_end_of_continuous_updates(&mut client_tx).await;
+                        }
+                    }
+                    ClientToServerMessage::FENCE => {
+                        let mut buffer: [u8; 8] = [0; 8];
+                        client_rx.read_exact(&mut buffer).await.unwrap();
+
+                        let flags = u32::from_be_bytes([buffer[3], buffer[4], buffer[5], buffer[6]]);
+                        let mut payload: Vec<u8> = vec![0; buffer[7] as usize];
+                        client_rx.read_exact(&mut payload).await.unwrap();
+
+                        /* Responses to our own Requests need no answer */
+                        if flags & FenceFlags::REQUEST != 0 && flags & FenceFlags::SYNC_NEXT != 0 {
+                            pending_sync_fence = Option::Some((flags & FenceFlags::SUPPORTED, payload));
+                        } else if flags & FenceFlags::REQUEST != 0 {
+                            write_fence_message(&mut client_tx, flags & FenceFlags::SUPPORTED, &payload).await;
+                        }
+                    }
+                    ClientToServerMessage::CLIENT_CUT_TEXT => {
+                        let mut buffer: Vec<u8> = vec![0; 7];
+                        client_rx.read_exact(&mut buffer).await.unwrap();
+
+                        /*
+                            Negative Lengths carry Extended Clipboard Messages, they are
+                            dropped if it wasn't negotiated. Oversized Text is dropped too,
+                            both are still read, or the next Message would be parsed from it.
+                        */
+                        let text_length = i32::from_be_bytes([buffer[3], buffer[4], buffer[5], buffer[6]]);
+                        let extended_clipboard = client_encodings.has_pseudo_encoding(RFBEncodingType::EXTENDED_CLIPBOARD);
+                        if !clipboard::accepts_cut_text_length(text_length, extended_clipboard) {
+                            let mut dropped_text = (&mut client_rx).take(text_length.unsigned_abs() as u64);
+                            io::copy(&mut dropped_text, &mut io::sink()).await.unwrap();
+                        } else {
+                            let mut cut_text: Vec<u8> = vec![0; text_length.unsigned_abs() as usize];
+                            client_rx.read_exact(&mut cut_text).await.unwrap();
+
+                            if text_length < 0 {
+                                match clipboard::parse_extended_cut_text(&cut_text) {
+                                    Some(ExtendedClipboardMessage::Caps(clipboard_caps)) => {
+                                        client_clipboard_caps = clipboard_caps;
+                                    },
+                                    Some(ExtendedClipboardMessage::Request(formats)) => {
+                                        if let Some(clipboard_data) = events::current_clipboard() {
+                                            clipboard::write_provide(&mut client_tx, &clipboard_data, formats).await;
+                                        }
+                                    },
+                                    Some(ExtendedClipboardMessage::Peek) => {
+                                        let formats = events::current_clipboard().map(|clipboard_data| clipboard_data.formats()).unwrap_or(0);
+                                        clipboard::write_notify(&mut client_tx, formats).await;
+                                    },
+                                    Some(ExtendedClipboardMessage::Notify(formats)) if !view_only && formats & ClipboardFormat::SUPPORTED != 0 => {
+                                        clipboard::write_request(&mut client_tx, formats & ClipboardFormat::SUPPORTED).await;
+                                    },
+                                    Some(ExtendedClipboardMessage::Provide(clipboard_data)) if !view_only => {
+                                        set_clipboard(&wm, clipboard_data, zstream_id.clone());
+                                    },
+                                    _ => {}
+                                }
+                            } else if !view_only {
+                                buffer.extend(cut_text);
+                                process_clientserver_message(
+                                    &mut client_tx,
+                                    &opcode,
+                                    &buffer,
+                                    pixel_format,
+                                    &client_encodings,
+                                    zstream_id.clone(),
+                                    wm.clone()
+                                )
+                                .await;
+                            }
+                        }
+                    }
+                    _ => { /* EXCEPTION EVENT: UNKNOWN MESSAGE */ }
                 }
-                ClientToServerMessage::POINTER_EVENT => {
-                    let mut buffer: [u8; 5] = [0; 5];
-                    client_rx.read_exact(&mut buffer).await.unwrap();
-                    process_clientserver_message(
-                        &mut client_rx,
-                        &mut client_tx,
-                        &opcode,
-                        &buffer,
-                        pixel_format,
-                        zstream_id.clone(),
-                        wm.clone()
-                    )
-                    .await;
+
+                /* The Message after a SyncNext Fence is done, the Reply can go out */
+                if let Some((flags, payload)) = sync_fence {
+                    write_fence_message(&mut client_tx, flags, &payload).await;
                 }
-                ClientToServerMessage::KEY_EVENT => {
-                    let mut buffer: [u8; 7] = [0; 7];
-                    client_rx.read_exact(&mut buffer).await.unwrap();
-                    process_clientserver_message(
-                        &mut client_rx,
-                        &mut client_tx,
-                        &opcode,
-                        &buffer,
-                        pixel_format.clone(),
-                        zstream_id.clone(),
-                        wm.clone()
-                    )
-                    .await;
+            } else {
+                debug::l1(format!("Client Has Disconnected"));
+                break;
+            }
+        }
+
+        for desktop_event in events::take_events(&zstream_id) {
+            match desktop_event {
+                DesktopEvent::Resized(width, height) => {
+                    screen_size = (width, height);
+                    if client_encodings.supports_desktop_resize() {
+                        pending_pseudo.desktop_size = Option::Some((ResizeReason::SERVER, ResizeStatus::NO_ERROR));
+                    }
+                },
+                DesktopEvent::Renamed(name_string) => {
+                    if client_encodings.has_pseudo_encoding(RFBEncodingType::DESKTOP_NAME) {
+                        pending_pseudo.desktop_name = Option::Some(name_string);
+                    }
+                },
+                DesktopEvent::CursorChanged(cursor) => {
+                    if client_encodings.cursor_encoding().is_some() {
+                        pending_pseudo.cursor = Option::Some(cursor);
+                    }
+                },
+                DesktopEvent::PointerMoved(x_position, y_position) => {
+                    if client_encodings.has_pseudo_encoding(RFBEncodingType::POINTER_POS)
+                        && client_pointer_position != Option::Some((x_position, y_position)) {
+                        pending_pseudo.pointer_position = Option::Some((x_position, y_position));
+                    }
+                },
+                DesktopEvent::ClipboardChanged(clipboard_data, origin_client) if origin_client.as_ref() != Option::Some(&zstream_id) => {
+                    /* Cut Text is not part of an Update, it goes out right away */
+                    if !client_encodings.has_pseudo_encoding(RFBEncodingType::EXTENDED_CLIPBOARD) {
+                        if let Some(text) = clipboard_data.text {
+                            clipboard::write_server_cut_text(&mut client_tx, &text).await;
+                        }
+                    } else if client_clipboard_caps.flags & ClipboardAction::PROVIDE != 0 && clipboard::fits_unsolicited(&clipboard_data, &client_clipboard_caps) {
+                        clipboard::write_provide(&mut client_tx, &clipboard_data, client_clipboard_caps.flags & ClipboardFormat::SUPPORTED).await;
+                    } else if client_clipboard_caps.flags & ClipboardAction::NOTIFY != 0 {
+                        clipboard::write_notify(&mut client_tx, clipboard_data.formats()).await;
+                    }
+                },
+                DesktopEvent::ClipboardChanged(_clipboard_data, _origin_client) => {},
+                DesktopEvent::Bell => {
+                    client_tx.write_u8(ServerToClientMessage::BELL).await.unwrap_or(());
                 }
-                _ => { /* EXCEPTION EVENT: CLIENT_CUT_TEXT */ }
             }
-        } else {
-            encoding_zlib::flush_stream(zstream_id.clone());
-            debug::l1(format!("Client Has Disconnected"));
-            break;
         }
+
+        if pending_update_request.is_none() {
+            pending_update_request = continuous_update_request;
+        }
+
+        if pending_update_request.is_some() && !pending_pseudo.is_empty() {
+            if pending_pseudo.desktop_size.is_some_and(|(_reason, status)| status == ResizeStatus::NO_ERROR) {
+                /* The Client starts over with a Framebuffer of the new Size */
+                framebuffer_size = screen_size;
+                incremental::flush_framebuffer(zstream_id.clone());
+            }
+
+            let desktop_update = get_desktop_update(
+                &client_encodings,
+                pixel_format,
+                framebuffer_size,
+                std::mem::take(&mut pending_pseudo)
+            );
+
+            if desktop_update.number_of_rectangles > 0 {
+                write_framebuffer_update_message(&mut client_tx, desktop_update).await;
+                pending_update_request = Option::None;
+            }
+        }
+
+        if pending_update_request.is_some() && Instant::now() >= next_update_check {
+            next_update_check = Instant::now() + UPDATE_POLL_INTERVAL;
+            let update_sent = write_requested_framebuffer_update(
+                &mut client_tx,
+                &pending_update_request.unwrap(),
+                pixel_format,
+                &client_encodings,
+                (framebuffer_size.0.min(screen_size.0), framebuffer_size.1.min(screen_size.1)),
+                zstream_id.clone(),
+                wm.clone()
+            )
+            .await;
+
+            if update_sent {
+                pending_update_request = Option::None;
+            }
+        }
+
+        /* Replies, Events and Updates from this pass go out before the next Read */
+        client_tx.flush().await.unwrap_or(());
     }
+
+    sessions::close_session(&zstream_id);
+    events::unregister_client(zstream_id.clone());
+    encoding_zlib::flush_stream(encoding_zlib::stream_name(&zstream_id));
+    encoding_zlib::flush_stream(encoding_zrle::stream_name(&zstream_id));
+    encoding_tight::flush_streams(zstream_id.clone());
+    incremental::flush_framebuffer(zstream_id.clone());
 }
 
 async fn write_serverinit_message(
-    mut client: TcpStream,
+    mut client: RFBStream,
     server_init: RFBServerInit,
     wm: Arc<WindowManager>,
+    client_id: String
 ) {
     client
         .write_u16(server_init.framebuffer_width)
@@ -625,30 +1429,32 @@ async fn write_serverinit_message(
         .await
         .unwrap_or(());
     client
-        .write(server_init.server_pixelformat.padding.as_slice())
+        .write_all(server_init.server_pixelformat.padding.as_slice())
         .await
-        .unwrap_or(0);
+        .unwrap_or(());
     client
         .write_u32(server_init.name_length)
         .await
         .unwrap_or(());
     client
-        .write(server_init.name_string.as_bytes())
+        .write_all(server_init.name_string.as_bytes())
         .await
-        .unwrap_or(0);
+        .unwrap_or(());
+    client.flush().await.unwrap_or(());
 
     /* SERVER-INIT PROCESSING COMPLETE */
-    init_clientserver_handshake(client, wm).await;
+    init_clientserver_handshake(client, wm, client_id).await;
 }
 
-async fn init_serverinit_handshake(client: TcpStream, wm: Arc<WindowManager>) {
+async fn init_serverinit_handshake(client: RFBStream, wm: Arc<WindowManager>, client_id: String) {
     match wm.as_ref() {
         #[cfg(target_os = "windows")]
         WindowManager::WIN32(win32_server) => {
             write_serverinit_message(
                 client, 
                 win32::get_display_struct(win32_server.monitors[0].clone()), 
-                wm
+                wm,
+                client_id
             )
             .await;
         },
@@ -659,112 +1465,167 @@ async fn init_serverinit_handshake(client: TcpStream, wm: Arc<WindowManager>) {
                 client,
                 x11::get_display_struct(x11_server, x11_server.displays[0].clone()),
                 wm,
+                client_id
             )
             .await;
         }
     }
 }
 
-async fn init_clientinit_handshake(mut client: TcpStream, wm: Arc<WindowManager>) {
-    match client.read_u8().await.unwrap_or(0) {
-        0 => {
-            /* SHARED_FLAG = 0, DISCONNECT ALL OTHERS */
-            /* TRY IMPLEMENTATION */
-        }
-        1.. => {
-            /* SHARED_FLAG != 0, SHARE SCREEN WITH ALL CLIENTS */
-            init_serverinit_handshake(client, wm).await;
+async fn init_clientinit_handshake(
+    mut client: RFBStream,
+    wm: Arc<WindowManager>,
+    session_policy: SessionPolicy,
+    access_level: AccessLevel
+) {
+    let shared_flag = match client.read_u8().await {
+        Ok(shared_flag) => shared_flag,
+        Err(_) => {
+            client.shutdown().await.unwrap_or(());
+            return;
         }
+    };
+
+    /* SHARED_FLAG = 0 DISCONNECTS ALL OTHERS, OR IS REFUSED BY POLICY */
+    let client_id = Uuid::new_v4().to_string();
+    if sessions::open_session(client_id.clone(), shared_flag != 0, session_policy, access_level) {
+        init_serverinit_handshake(client, wm, client_id).await;
+    } else {
+        debug::l1(format!("Exclusive Client Refused, a Session is Active"));
+        client.shutdown().await.unwrap_or(());
     }
 }
 
-async fn init_securityresult_handshake(
-    mut client: TcpStream,
-    security_type: u8,
-    wm: Arc<WindowManager>,
-    auth: Option<RFBAuthentication>
-) {
-    match security_type {
-        0 | 3.. => {
-            let rfb_error = create_rfb_error(String::from("Authentication Type not Supported"));
-            client
-                .write_u32(rfb_error.reason_length)
-                .await
-                .unwrap_or(());
-            client
-                .write(rfb_error.reason_string.as_bytes())
-                .await
-                .unwrap_or(0);
-        }
-        1 => {
-            /* HANDLE AUTHENTICATION TYPE NONE */
-            client.write_u32(0).await.unwrap_or(());
-            init_clientinit_handshake(client, wm).await;
-        }
-        2 => {
-            /* HANDLE VNC AUTHENTICATION, Get Password */
-            let vnc_key: [u8; 8];
-            match auth.unwrap() {
-                RFBAuthentication::Vnc(key) => vnc_key = key.security_key
-            }
+async fn vnc_authenticate(client: &mut RFBStream, vnc_key: [u8; 8]) -> bool {
+    /*
+        THIS IS NOT A PART OF THE RFB PROTOCOL SPECIFICATION
+        VNC Authentication reverses the order of bits
+        Know more at https://catonmat.net/curious-case-of-des-algorithm
+    */
 
-            /*
-                THIS IS NOT A PART OF THE RFB PROTOCOL SPECIFICATION
-                VNC Authentication reverses the order of bits
-                Know more at https://catonmat.net/curious-case-of-des-algorithm
-            */
+    let mut vnckey_le: [u8; 8] = [0; 8];
+    for index in 0..vnc_key.len() {
+        vnckey_le[index] = u8::from_bits(vnc_key[index].get_bits_le(), false);
+    }
 
-            let mut vnckey_le: [u8; 8] = [0; 8];
-            for index in 0..vnc_key.len() {
-                vnckey_le[index] = u8::from_bits(vnc_key[index].get_bits_le(), false);
-            }
+    /* Create DES Encryption Object */
+    let des = Des::new_from_slice(&vnckey_le);
+    let des = des.unwrap();
 
-            /* Create DES Encryption Object */
-            let des = Des::new_from_slice(&vnckey_le);
-            let des = des.unwrap();
+    /* Auth Challenge Key */
+    let challenge = parser::security::vnc_auth_challenge();
+    client.write_u128(challenge).await.unwrap();
+    client.flush().await.unwrap();
 
-            /* Auth Challenge Key */
-            let challenge = parser::security::vnc_auth_challenge();
-            client.write_u128(challenge).await.unwrap();
+    /* Read Encrypted Key from Client */
+    let mut challenge_buf: [u8; 16] = [0; 16];
+    client.read_exact(&mut challenge_buf).await.unwrap();
 
-            /* Read Encrypted Key from Client */
-            let mut challenge_buf: [u8; 16] = [0; 16];
-            client.read_exact(&mut challenge_buf).await.unwrap();
+    /* Decrypt Client Challenge */
+    let mut decrypted_challenge: Vec<GenericArray<u8, typenum::U8>> = vec![
+        GenericArray::clone_from_slice(&challenge_buf[0..8]),
+        GenericArray::clone_from_slice(&challenge_buf[8..16])
+    ];
 
-            /* Decrypt Client Challenge */
-            let mut decrypted_challenge: Vec<GenericArray<u8, typenum::U8>> = vec![
-                GenericArray::clone_from_slice(&challenge_buf[0..8]),
-                GenericArray::clone_from_slice(&challenge_buf[8..16])
-            ];
+    /* Call Decryptor and Verify */
+    des.decrypt_blocks(&mut decrypted_challenge);
+    challenge.to_be_bytes().eq(decrypted_challenge.concat().as_slice())
+}
 
-            /* Call Decryptor and Verify */
-            des.decrypt_blocks(&mut decrypted_challenge);
-            if challenge.to_be_bytes().eq(decrypted_challenge.concat().as_slice()) {
+async fn init_securityresult_handshake(
+    mut client: RFBStream,
+    version: RFBVersion,
+    security_type: u8,
+    wm: Arc<WindowManager>,
+    auth: Option<RFBAuthentication>,
+    session_policy: SessionPolicy,
+    encryption: EncryptionContext
+) {
+    match (security_type, auth, encryption.tls_acceptor) {
+        (SecurityType::NONE, _, _) => {
+            /* HANDLE AUTHENTICATION TYPE NONE, No SecurityResult before 3.8 */
+            if version == RFBVersion::V3_8 {
+                write_security_result(&mut client, version, Option::None).await;
+            }
+
+            init_clientinit_handshake(client, wm, session_policy, AccessLevel::FullControl).await;
+        }
+        (SecurityType::VNC_AUTHENTICATION, Option::Some(RFBAuthentication::Vnc(vnc_auth)), _) => {
+            /* HANDLE VNC AUTHENTICATION */
+            if vnc_authenticate(&mut client, vnc_auth.security_key).await {
                 /* Security Result Message: Ok(0) */
-                client.write_u32(0).await.unwrap_or(());
-                init_clientinit_handshake(client, wm).await;
+                write_security_result(&mut client, version, Option::None).await;
+                init_clientinit_handshake(client, wm, session_policy, AccessLevel::FullControl).await;
             } else {
                 /* Security Result Message: Failed(1) */
-                client.write_u32(1).await.unwrap_or(());
-
-                /* Failure Reason for RFB Version 3.8 */
-                let rfb_error = create_rfb_error(String::from("Password is Incorrect"));
-                client.write_u32(rfb_error.reason_length).await.unwrap_or(());
-                client.write(rfb_error.reason_string.as_bytes()).await.unwrap_or(0);
+                write_security_result(&mut client, version, Option::Some("Password is Incorrect")).await;
             }
         }
+        (SecurityType::VENCRYPT, auth, Option::Some(tls_acceptor)) => {
+            /* HANDLE VENCRYPT, Authentication runs inside TLS */
+            if let Option::Some((client, access_level)) = vencrypt::init_vencrypt_handshake(client, version, auth, tls_acceptor).await {
+                init_clientinit_handshake(client, wm, session_policy, access_level).await;
+            }
+        }
+        (SecurityType::RA2 | SecurityType::RA2NE | SecurityType::RA2_256 | SecurityType::RA2NE_256, auth, _) if encryption.rsa_key.is_some() => {
+            /* HANDLE RSA-AES, Authentication runs inside AES-EAX */
+            let rsa_key = encryption.rsa_key.unwrap();
+            if let Option::Some((client, access_level)) = rsa_aes::init_rsa_aes_handshake(client, version, security_type, auth, rsa_key).await {
+                init_clientinit_handshake(client, wm, session_policy, access_level).await;
+            }
+        }
+        _ => {
+            write_security_result(&mut client, version, Option::Some("Authentication Type not Supported")).await;
+        }
     }
 }
 
-async fn init_authentication_handshake(mut client: TcpStream, wm: Arc<WindowManager>, auth: Option<RFBAuthentication>) {
+async fn init_authentication_handshake(
+    mut client: RFBStream,
+    version: RFBVersion,
+    wm: Arc<WindowManager>,
+    auth: Option<RFBAuthentication>,
+    session_policy: SessionPolicy,
+    encryption: EncryptionContext
+) {
     /* INITIATE SECURITY HANDSHAKE, VNC_SERVER CONSTANTS */
     let mut rfb_server = RFBServer::init();
-    if auth.is_some() {
+    if encryption.tls_acceptor.is_some() || encryption.rsa_key.is_some() {
+        /* Only Encrypted Security Types, they carry the Authentication */
+        rfb_server.supported_security_types = vec![];
+        if encryption.rsa_key.is_some() {
+            rfb_server.supported_security_types.extend_from_slice(&[
+                SecurityType::RA2_256, SecurityType::RA2, SecurityType::RA2NE_256, SecurityType::RA2NE
+            ]);
+        }
+
+        if encryption.tls_acceptor.is_some() {
+            rfb_server.supported_security_types.push(SecurityType::VENCRYPT);
+        }
+
+        rfb_server.supported_security_types_length = rfb_server.supported_security_types.len() as u8;
+    } else if auth.is_some() {
         /* Fix this in future */
-        rfb_server.supported_security_types = vec![2];
+        rfb_server.supported_security_types = vec![SecurityType::VNC_AUTHENTICATION];
         rfb_server.supported_security_types_length = 1;
     }
 
+    if version == RFBVersion::V3_3 {
+        /* RFB Version 3.3: Server decides, security-type (U32) */
+        let security_type = rfb_server.supported_security_types[0];
+        if security_type != SecurityType::NONE && security_type != SecurityType::VNC_AUTHENTICATION {
+            /* security-type = 0 (Failed), then the Reason */
+            client.write_u32(0).await.unwrap_or(());
+            write_rfb_error(&mut client, "Encrypted Security Types need RFB 3.7 or newer").await;
+            return;
+        }
+
+        client.write_u32(security_type as u32).await.unwrap_or(());
+        client.flush().await.unwrap_or(());
+        init_securityresult_handshake(client, version, security_type, wm, auth, session_policy, encryption).await;
+        return;
+    }
+
     /* SEND AVAILABLE SECURITY METHODS */
     client
         .write_u8(rfb_server.supported_security_types_length)
@@ -774,38 +1635,46 @@ async fn init_authentication_handshake(mut client: TcpStream, wm: Arc<WindowMana
         .write_all(rfb_server.supported_security_types.as_slice())
         .await
         .unwrap_or(());
+    client.flush().await.unwrap_or(());
 
     /* READ CLIENT RESPONSE */
     match client.read_u8().await {
-        Ok(selected_type) => init_securityresult_handshake(client, selected_type, wm, auth).await,
+        Ok(selected_type) if rfb_server.supported_security_types.contains(&selected_type) => {
+            init_securityresult_handshake(client, version, selected_type, wm, auth, session_policy, encryption).await
+        },
+        Ok(_) => {
+            write_security_result(&mut client, version, Option::Some("Authentication Type not Supported")).await;
+        },
         Err(_) => {
             client.shutdown().await.unwrap_or(());
         }
     }
 }
 
-async fn init_handshake(mut client: TcpStream, wm: Arc<WindowManager>, auth: Option<RFBAuthentication>) {
+async fn init_handshake(
+    mut client: RFBStream,
+    wm: Arc<WindowManager>,
+    auth: Option<RFBAuthentication>,
+    session_policy: SessionPolicy,
+    encryption: EncryptionContext
+) {
     let rfb_server = RFBServer::init();
     let mut buf: [u8; 12] = [0; 12];
     client
-        .write(&rfb_server.protocol_version)
+        .write_all(&rfb_server.protocol_version)
         .await
-        .unwrap_or(0);
+        .unwrap_or(());
+    client.flush().await.unwrap_or(());
     match client.read_exact(&mut buf).await {
-        Ok(protocol_index) => {
-            if &buf[0..protocol_index] == b"RFB 003.008\n" {
-                debug::l1(format!("RFB Client agreed on V3.8"));
-                init_authentication_handshake(client, wm, auth).await;
-            } else {
-                let rfb_error = create_rfb_error(String::from("Version not Supported"));
-                client
-                    .write_u32(rfb_error.reason_length)
-                    .await
-                    .unwrap_or(());
-                client
-                    .write(rfb_error.reason_string.as_bytes())
-                    .await
-                    .unwrap_or(0);
+        Ok(_) => match RFBVersion::from_protocol_version(&buf) {
+            Option::Some(version) => {
+                debug::l1(format!("RFB Client agreed on {:?}", version));
+                init_authentication_handshake(client, version, wm, auth, session_policy, encryption).await;
+            }
+            Option::None => {
+                /* Refused as in 3.8: number-of-security-types = 0, then the Reason */
+                client.write_u8(0).await.unwrap_or(());
+                write_rfb_error(&mut client, "Version not Supported").await;
             }
         }
         Err(_) => {
@@ -875,6 +1744,10 @@ pub async fn create(options: CreateOptions) -> Result<(), Box<dyn Error>> {
             let x11_connection = x11::connect();
             if x11_connection.is_ok() {
                 wm_arc = Option::Some(x11_connection.unwrap());
+
+                /* Resizes and Renames reach Clients through events */
+                x11::watch_desktop(options.bell_interval);
+                x11::watch_clipboard();
             } else {
                 /* Return X11 Connection Error */
                 return Err(String::from("X11 Connection Error").into());                    
@@ -885,16 +1758,30 @@ pub async fn create(options: CreateOptions) -> Result<(), Box<dyn Error>> {
             /* Unwrap WindowManager Object */
             let wm_arc = wm_arc.unwrap();
 
+            /* Plain Authentication is only ever sent inside TLS or AES-EAX */
+            let plain_auth = matches!(options.auth, Option::Some(RFBAuthentication::Plain(_)));
+            let mut encryption = EncryptionContext::default();
+            if options.vencrypt || (plain_auth && !options.rsa_aes) {
+                encryption.tls_acceptor = Option::Some(TlsAcceptor::from(Arc::new(parser::tls::load_serverconfig())));
+            }
+
+            /* Generating the Key takes a moment, it is done once and kept */
+            if options.rsa_aes {
+                encryption.rsa_key = Option::Some(Arc::new(rsa_aes::load_or_generate_key(rsa_aes::key_path())));
+            }
+
             /* Accept All Incoming Connections */
             loop {
                 let (client, _) = listener.accept().await?;
                 let wm = Arc::clone(&wm_arc);
                 let auth_clone = options.auth.clone();
+                let session_policy = options.session_policy;
+                let encryption = encryption.clone();
     
                 tokio::spawn(async move {
                     /* Init Handshake */
                     debug::l1(format!("Connection Established: {:?}", client));
-                    init_handshake(client, wm, auth_clone).await;
+                    init_handshake(RFBStream::Tcp(client), wm, auth_clone, session_policy, encryption).await;
                 });
             }
         } else {
@@ -906,4 +1793,100 @@ pub async fn create(options: CreateOptions) -> Result<(), Box<dyn Error>> {
         debug::l1(format!("IP Address Binding Failed -> {}", err.to_string()));
         return Err(err.into());
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoding_list(encodings: &[i32]) -> Vec<u8> {
+        encodings.iter().flat_map(|encoding| encoding.to_be_bytes()).collect()
+    }
+
+    #[test]
+    fn follows_the_client_order() {
+        let client_encodings = RFBEncodings::from_encoding_list(&encoding_list(&[
+            RFBEncodingType::HEX_TILE,
+            RFBEncodingType::ZRLE,
+            RFBEncodingType::RAW,
+        ]), false);
+        assert_eq!(client_encodings.preferred_encoding(), RFBEncodingType::HEX_TILE);
+
+        let client_encodings = RFBEncodings::from_encoding_list(&encoding_list(&[
+            RFBEncodingType::ZRLE,
+            RFBEncodingType::HEX_TILE,
+        ]), false);
+        assert_eq!(client_encodings.preferred_encoding(), RFBEncodingType::ZRLE);
+    }
+
+    #[test]
+    fn unsupported_encodings_fall_back_to_raw() {
+        /* 8 is unassigned, 21 is JPEG */
+        let client_encodings = RFBEncodings::from_encoding_list(&encoding_list(&[8, 21]), false);
+        assert_eq!(client_encodings.preferred_encoding(), RFBEncodingType::RAW);
+
+        let client_encodings = RFBEncodings::from_encoding_list(&[], false);
+        assert_eq!(client_encodings.preferred_encoding(), RFBEncodingType::RAW);
+
+        /* Pseudo-encodings are never picked to encode pixels */
+        let client_encodings = RFBEncodings::from_encoding_list(&encoding_list(&[
+            RFBEncodingType::CURSOR,
+            RFBEncodingType::DESKTOP_SIZE,
+        ]), false);
+        assert_eq!(client_encodings.preferred_encoding(), RFBEncodingType::RAW);
+        assert!(client_encodings.has_pseudo_encoding(RFBEncodingType::CURSOR));
+    }
+
+    #[test]
+    fn tight_png_only_for_browser_clients() {
+        let encodings = encoding_list(&[RFBEncodingType::TIGHT, RFBEncodingType::TIGHT_PNG]);
+        assert_eq!(RFBEncodings::from_encoding_list(&encodings, true).preferred_encoding(), RFBEncodingType::TIGHT_PNG);
+        assert_eq!(RFBEncodings::from_encoding_list(&encodings, false).preferred_encoding(), RFBEncodingType::TIGHT);
+
+        /* Native clients get TightPNG only if nothing before it is supported */
+        let encodings = encoding_list(&[8, RFBEncodingType::TIGHT_PNG, RFBEncodingType::RAW]);
+        assert_eq!(RFBEncodings::from_encoding_list(&encodings, false).preferred_encoding(), RFBEncodingType::TIGHT_PNG);
+    }
+
+    #[test]
+    fn sorts_pseudo_encodings() {
+        for encoding in [RFBEncodingType::RAW, RFBEncodingType::ZRLE, RFBEncodingType::TIGHT_PNG, 255] {
+            assert!(!RFBEncodingType::is_pseudo_encoding(encoding));
+        }
+
+        for encoding in [
+            RFBEncodingType::CURSOR,
+            RFBEncodingType::COMPRESS_LEVEL_0,
+            RFBEncodingType::EXTENDED_CLIPBOARD,
+            256,
+        ] {
+            assert!(RFBEncodingType::is_pseudo_encoding(encoding));
+        }
+    }
+
+    #[test]
+    fn known_protocol_versions() {
+        assert_eq!(RFBVersion::from_protocol_version(b"RFB 003.003\n"), Option::Some(RFBVersion::V3_3));
+        assert_eq!(RFBVersion::from_protocol_version(b"RFB 003.007\n"), Option::Some(RFBVersion::V3_7));
+        assert_eq!(RFBVersion::from_protocol_version(b"RFB 003.008\n"), Option::Some(RFBVersion::V3_8));
+        assert_eq!(RFBVersion::from_protocol_version(b"RFB 003.889\n"), Option::Some(RFBVersion::V3_8));
+    }
+
+    #[test]
+    fn unknown_minor_versions_are_3_3() {
+        assert_eq!(RFBVersion::from_protocol_version(b"RFB 003.005\n"), Option::Some(RFBVersion::V3_3));
+        assert_eq!(RFBVersion::from_protocol_version(b"RFB 003.010\n"), Option::Some(RFBVersion::V3_3));
+    }
+
+    #[test]
+    fn rejects_malformed_versions() {
+        for protocol_version in [
+            b"RFB 004.008\n",
+            b"RFB 003.008 ",
+            b"RFB 003.0x8\n",
+            b"GET / HTTP/1",
+            &[0; 12],
+        ] {
+            assert_eq!(RFBVersion::from_protocol_version(protocol_version), Option::None);
+        }
+    }
+}
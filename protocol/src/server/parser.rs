@@ -283,8 +283,8 @@ pub mod http {
 }
 
 pub mod tls {
-    use std::{fs, io};
-    use rustls::{Certificate, PrivateKey};
+    use std::{fs, io, env};
+    use rustls::{Certificate, PrivateKey, ServerConfig};
     use rustls_pemfile::{certs, rsa_private_keys, pkcs8_private_keys};
 
     pub fn load_certificates(pem_path: &str) -> Vec<Certificate> {
@@ -307,6 +307,37 @@ pub mod tls {
         
         PrivateKey(privatekey[0].clone())   
     }
+
+    pub fn load_serverconfig() -> ServerConfig {
+        /* cert.pem and key.pem from the 'ssl' directory next to the Executable */
+        let mut spify_installpath = env::current_exe().unwrap();
+        spify_installpath.pop();
+        spify_installpath.push("ssl");
+        spify_installpath.push("cert");
+
+        /* 
+            We added an extra directory 'cert' as
+            Rust pops the last element in the path
+            when set_file_name() is invoked.
+        */
+
+        spify_installpath.set_file_name("cert.pem");
+        let certificate_path = spify_installpath.clone();
+        let certificate_path = certificate_path.to_str().unwrap();
+
+        spify_installpath.set_file_name("key.pem");
+        let key_path = spify_installpath.clone();
+        let key_path = key_path.to_str().unwrap();
+
+        ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                load_certificates(certificate_path),
+                load_privatekey(key_path)
+            )
+            .unwrap()
+    }
 }
 
 pub mod websocket {
@@ -0,0 +1,664 @@
+/*
+    SpifyRFB - Modern RFB Server implementation using Rust
+    Copyright (C) 2023  Atheesh Thirumalairajan
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::{env, fs, io, net::SocketAddr, path::{Path, PathBuf}, pin::Pin, sync::Arc, task::{ready, Context, Poll}};
+use aes::{Aes128, Aes256};
+use eax::{Eax, aead::{AeadInPlace, KeyInit, generic_array::GenericArray}};
+use rand::Rng;
+use rsa::{
+    BigUint, Pkcs1v15Encrypt, RsaPrivateKey, RsaPublicKey, traits::PublicKeyParts,
+    pkcs8::{DecodePrivateKey, EncodePrivateKey, LineEnding}
+};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use tokio::{io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf}, net::TcpStream};
+use super::{RFBAuthentication, RFBVersion, SecurityType, sessions::AccessLevel, stream::RFBStream, write_security_result};
+
+/* Key generated on first launch, RealVNC and TigerVNC use 2048 bits */
+const SERVER_KEY_LENGTH: usize = 2048;
+
+/* Client Keys outside these bounds are refused */
+const MIN_KEY_LENGTH: u32 = 1024;
+const MAX_KEY_LENGTH: u32 = 8192;
+
+/* Plaintext bytes per AES-EAX Message, as TigerVNC */
+const MAX_MESSAGE_LENGTH: usize = 8192;
+
+/* length (U16), AES-EAX Tag (U8 x 16) */
+const MESSAGE_HEADER_LENGTH: usize = 2;
+const MESSAGE_TAG_LENGTH: usize = 16;
+
+pub struct RA2Subtype;
+impl RA2Subtype {
+    pub const USER_PASS: u8 = 1;
+    pub const PASS: u8 = 2;
+}
+
+pub fn key_path() -> PathBuf {
+    /* Next to cert.pem and key.pem */
+    let mut spify_installpath = env::current_exe().unwrap();
+    spify_installpath.pop();
+    spify_installpath.push("ssl");
+    spify_installpath.push("rsa_key.pem");
+    spify_installpath
+}
+
+pub fn load_or_generate_key(path: PathBuf) -> RsaPrivateKey {
+    /* Viewers remember the Key, keep it across restarts */
+    if let Ok(Ok(rsa_key)) = fs::read_to_string(&path).map(|pem| RsaPrivateKey::from_pkcs8_pem(&pem)) {
+        return rsa_key;
+    }
+
+    let rsa_key = RsaPrivateKey::new(&mut rand::thread_rng(), SERVER_KEY_LENGTH).unwrap();
+    if let Some(key_directory) = path.parent() {
+        fs::create_dir_all(key_directory).unwrap_or(());
+    }
+
+    if let Ok(pem) = rsa_key.to_pkcs8_pem(LineEnding::LF) {
+        write_private_file(&path, pem.as_bytes()).unwrap_or(());
+    }
+
+    rsa_key
+}
+
+#[cfg(unix)]
+fn write_private_file(path: &Path, contents: &[u8]) -> io::Result<()> {
+    use std::{fs::OpenOptions, io::Write, os::unix::fs::OpenOptionsExt};
+    OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)?.write_all(contents)
+}
+
+#[cfg(not(unix))]
+fn write_private_file(path: &Path, contents: &[u8]) -> io::Result<()> {
+    fs::write(path, contents)
+}
+
+enum RA2Cipher {
+    Aes128(Box<Eax<Aes128>>),
+    Aes256(Box<Eax<Aes256>>),
+}
+
+impl RA2Cipher {
+    fn new(key: &[u8]) -> RA2Cipher {
+        match key.len() {
+            16 => RA2Cipher::Aes128(Box::new(Eax::new_from_slice(key).unwrap())),
+            _ => RA2Cipher::Aes256(Box::new(Eax::new_from_slice(key).unwrap())),
+        }
+    }
+
+    fn encrypt(&self, nonce: &[u8; 16], header: &[u8], message: &mut [u8]) -> Vec<u8> {
+        let nonce = GenericArray::from_slice(nonce);
+        let tag = match self {
+            RA2Cipher::Aes128(cipher) => cipher.encrypt_in_place_detached(nonce, header, message),
+            RA2Cipher::Aes256(cipher) => cipher.encrypt_in_place_detached(nonce, header, message),
+        };
+
+        tag.unwrap().to_vec()
+    }
+
+    fn decrypt(&self, nonce: &[u8; 16], header: &[u8], message: &mut [u8], tag: &[u8]) -> bool {
+        let nonce = GenericArray::from_slice(nonce);
+        let tag = GenericArray::from_slice(tag);
+        match self {
+            RA2Cipher::Aes128(cipher) => cipher.decrypt_in_place_detached(nonce, header, message, tag).is_ok(),
+            RA2Cipher::Aes256(cipher) => cipher.decrypt_in_place_detached(nonce, header, message, tag).is_ok(),
+        }
+    }
+}
+
+fn increment_nonce(nonce: &mut [u8; 16]) {
+    /* 128-bit Little Endian Counter, one step per Message */
+    for byte in nonce.iter_mut() {
+        *byte = byte.wrapping_add(1);
+        if *byte != 0 {
+            break;
+        }
+    }
+}
+
+fn encrypt_message(encryptor: &RA2Cipher, nonce: &mut [u8; 16], message: &[u8]) -> Vec<u8> {
+    /* Every Message is length (U16), AES-EAX ciphertext, Tag. The length is the Associated Data */
+    let header = (message.len() as u16).to_be_bytes();
+    let mut message = message.to_vec();
+    let tag = encryptor.encrypt(nonce, &header, &mut message);
+    increment_nonce(nonce);
+    [&header[..], &message, &tag].concat()
+}
+
+fn session_keys(aes256: bool, client_random: &[u8], server_random: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    /* (Client to Server, Server to Client), each as long as the Randoms */
+    let random_length = client_random.len();
+    let read_key = ra2_digest(aes256, &[client_random, server_random]);
+    let write_key = ra2_digest(aes256, &[server_random, client_random]);
+    (read_key[..random_length].to_vec(), write_key[..random_length].to_vec())
+}
+
+pub struct RA2Stream {
+    stream: TcpStream,
+    decryptor: RA2Cipher,
+    encryptor: RA2Cipher,
+    read_nonce: [u8; 16],
+    write_nonce: [u8; 16],
+    ciphertext: Vec<u8>,
+    plaintext: Vec<u8>,
+    plaintext_offset: usize,
+    write_buffer: Vec<u8>, /* PLAINTEXT, SENT AS A MESSAGE ON FLUSH OR ONCE FULL */
+    write_message: Vec<u8>,
+    write_offset: usize,
+}
+
+impl RA2Stream {
+    fn new(stream: TcpStream, read_key: &[u8], write_key: &[u8]) -> RA2Stream {
+        RA2Stream {
+            stream,
+            decryptor: RA2Cipher::new(read_key),
+            encryptor: RA2Cipher::new(write_key),
+            read_nonce: [0; 16],
+            write_nonce: [0; 16],
+            ciphertext: vec![],
+            plaintext: vec![],
+            plaintext_offset: 0,
+            write_buffer: Vec::with_capacity(MAX_MESSAGE_LENGTH),
+            write_message: vec![],
+            write_offset: 0,
+        }
+    }
+
+    fn into_inner(self) -> TcpStream {
+        self.stream
+    }
+
+    pub(crate) fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    fn decrypt_message(&mut self) -> io::Result<bool> {
+        if self.ciphertext.len() < MESSAGE_HEADER_LENGTH {
+            return Ok(false);
+        }
+
+        let message_length = u16::from_be_bytes([self.ciphertext[0], self.ciphertext[1]]) as usize;
+        let frame_length = MESSAGE_HEADER_LENGTH + message_length + MESSAGE_TAG_LENGTH;
+        if self.ciphertext.len() < frame_length {
+            return Ok(false);
+        }
+
+        let frame: Vec<u8> = self.ciphertext.drain(..frame_length).collect();
+        let (header, message) = frame.split_at(MESSAGE_HEADER_LENGTH);
+        let (message, tag) = message.split_at(message_length);
+        let mut message = message.to_vec();
+        if !self.decryptor.decrypt(&self.read_nonce, header, &mut message, tag) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "RA2 Message failed Authentication"));
+        }
+
+        increment_nonce(&mut self.read_nonce);
+        self.plaintext = message;
+        self.plaintext_offset = 0;
+        Ok(true)
+    }
+
+    fn poll_write_buffer(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            while self.write_offset < self.write_message.len() {
+                let written = ready!(Pin::new(&mut self.stream).poll_write(cx, &self.write_message[self.write_offset..]))?;
+                if written == 0 {
+                    return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+                }
+
+                self.write_offset += written;
+            }
+
+            if self.write_buffer.is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+
+            let message_length = self.write_buffer.len().min(MAX_MESSAGE_LENGTH);
+            let message: Vec<u8> = self.write_buffer.drain(..message_length).collect();
+            self.write_message = encrypt_message(&self.encryptor, &mut self.write_nonce, &message);
+            self.write_offset = 0;
+        }
+    }
+}
+
+impl AsyncRead for RA2Stream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let ra2_stream = &mut *self;
+        while ra2_stream.plaintext_offset == ra2_stream.plaintext.len() {
+            if ra2_stream.decrypt_message()? {
+                continue;
+            }
+
+            let mut read_buffer: [u8; 4096] = [0; 4096];
+            let mut read_buffer = ReadBuf::new(&mut read_buffer);
+            ready!(Pin::new(&mut ra2_stream.stream).poll_read(cx, &mut read_buffer))?;
+            if read_buffer.filled().is_empty() {
+                /* Closed, a partial Message is an error */
+                if ra2_stream.ciphertext.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+
+            ra2_stream.ciphertext.extend_from_slice(read_buffer.filled());
+        }
+
+        let read_length = buf.remaining().min(ra2_stream.plaintext.len() - ra2_stream.plaintext_offset);
+        buf.put_slice(&ra2_stream.plaintext[ra2_stream.plaintext_offset..ra2_stream.plaintext_offset + read_length]);
+        ra2_stream.plaintext_offset += read_length;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for RA2Stream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        /* Bytes are taken into the Buffer, a full Buffer is sent before taking more */
+        let ra2_stream = &mut *self;
+        if ra2_stream.write_buffer.len() >= MAX_MESSAGE_LENGTH {
+            ready!(ra2_stream.poll_write_buffer(cx))?;
+        }
+
+        let written = buf.len().min(MAX_MESSAGE_LENGTH - ra2_stream.write_buffer.len());
+        ra2_stream.write_buffer.extend_from_slice(&buf[..written]);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        ready!(self.poll_write_buffer(cx))?;
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        ready!(self.poll_write_buffer(cx))?;
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+fn ra2_digest(aes256: bool, parts: &[&[u8]]) -> Vec<u8> {
+    /* RA2 and RA2ne hash with SHA-1, the _256 variants with SHA-256 */
+    if aes256 {
+        parts.iter().fold(Sha256::new(), |hasher, part| hasher.chain_update(part)).finalize().to_vec()
+    } else {
+        parts.iter().fold(Sha1::new(), |hasher, part| hasher.chain_update(part)).finalize().to_vec()
+    }
+}
+
+fn padded_bytes(value: &BigUint, length: usize) -> Vec<u8> {
+    let value_bytes = value.to_bytes_be();
+    let mut padded: Vec<u8> = vec![0; length.saturating_sub(value_bytes.len())];
+    padded.extend_from_slice(&value_bytes);
+    padded
+}
+
+fn encode_public_key(public_key: &RsaPublicKey) -> Vec<u8> {
+    /* key-length in bits (U32), modulus, public exponent, both key-length / 8 bytes */
+    let key_bits = public_key.n().bits();
+    let key_bytes = key_bits.div_ceil(8);
+    let mut encoded_key: Vec<u8> = (key_bits as u32).to_be_bytes().to_vec();
+    encoded_key.extend(padded_bytes(public_key.n(), key_bytes));
+    encoded_key.extend(padded_bytes(public_key.e(), key_bytes));
+    encoded_key
+}
+
+async fn read_public_key(client: &mut RFBStream) -> Option<(RsaPublicKey, Vec<u8>)> {
+    let key_bits = client.read_u32().await.ok()?;
+    if !(MIN_KEY_LENGTH..=MAX_KEY_LENGTH).contains(&key_bits) {
+        return Option::None;
+    }
+
+    let key_bytes = key_bits.div_ceil(8) as usize;
+    let mut modulus: Vec<u8> = vec![0; key_bytes];
+    let mut exponent: Vec<u8> = vec![0; key_bytes];
+    client.read_exact(&mut modulus).await.ok()?;
+    client.read_exact(&mut exponent).await.ok()?;
+
+    let public_key = RsaPublicKey::new_with_max_size(
+        BigUint::from_bytes_be(&modulus),
+        BigUint::from_bytes_be(&exponent),
+        MAX_KEY_LENGTH as usize
+    ).ok()?;
+
+    /* Hashed as the Client sent it */
+    let encoded_key = [&key_bits.to_be_bytes()[..], &modulus, &exponent].concat();
+    Option::Some((public_key, encoded_key))
+}
+
+async fn read_credentials(client: &mut RFBStream) -> Option<(String, String)> {
+    /* username-length (U8), username, password-length (U8), password */
+    let username_length = client.read_u8().await.ok()?;
+    let mut username: Vec<u8> = vec![0; username_length as usize];
+    client.read_exact(&mut username).await.ok()?;
+
+    let password_length = client.read_u8().await.ok()?;
+    let mut password: Vec<u8> = vec![0; password_length as usize];
+    client.read_exact(&mut password).await.ok()?;
+
+    Option::Some((String::from_utf8(username).ok()?, String::from_utf8(password).ok()?))
+}
+
+async fn authenticate(client: &mut RFBStream, auth: &Option<RFBAuthentication>) -> Option<AccessLevel> {
+    let (username, password) = read_credentials(client).await?;
+    check_credentials(auth, username, password).await
+}
+
+async fn check_credentials(auth: &Option<RFBAuthentication>, username: String, password: String) -> Option<AccessLevel> {
+    match auth {
+        /* No Authentication configured, Credentials are read and ignored */
+        Option::None => Option::Some(AccessLevel::FullControl),
+        Option::Some(RFBAuthentication::Vnc(vnc_auth)) => {
+            /* VNC Passwords are truncated or padded to 8 bytes */
+            let mut password_key: [u8; 8] = [0; 8];
+            let password_length = password.len().min(8);
+            password_key[..password_length].copy_from_slice(&password.as_bytes()[..password_length]);
+            /* Every byte is compared, the time taken says nothing about where they differ */
+            let difference = password_key
+                .iter()
+                .zip(vnc_auth.security_key.iter())
+                .fold(0, |difference, (password_byte, key_byte)| difference | (password_byte ^ key_byte));
+
+            (difference == 0).then_some(AccessLevel::FullControl)
+        },
+        Option::Some(RFBAuthentication::Plain(plain_auth)) => {
+            /* bcrypt and argon2 are slow on purpose, keep them off the Runtime */
+            let backend = plain_auth.backend.clone();
+            tokio::task::spawn_blocking(move || backend.verify(&username, &password)).await.ok()?
+        },
+    }
+}
+
+pub(crate) async fn init_rsa_aes_handshake(
+    mut client: RFBStream,
+    version: RFBVersion,
+    security_type: u8,
+    auth: Option<RFBAuthentication>,
+    rsa_key: Arc<RsaPrivateKey>
+) -> Option<(RFBStream, AccessLevel)> {
+    let aes256 = matches!(security_type, SecurityType::RA2_256 | SecurityType::RA2NE_256);
+    let all_encrypted = matches!(security_type, SecurityType::RA2 | SecurityType::RA2_256);
+    let random_length = if aes256 { 32 } else { 16 };
+
+    /* Exchange Public Keys */
+    let server_public_key = rsa_key.to_public_key();
+    let server_encoded_key = encode_public_key(&server_public_key);
+    client.write_all(&server_encoded_key).await.ok()?;
+    client.flush().await.ok()?;
+    let (client_public_key, client_encoded_key) = read_public_key(&mut client).await?;
+
+    /* Exchange Randoms: length (U16), RSAES-PKCS1-v1_5 ciphertext */
+    let mut server_random: Vec<u8> = vec![0; random_length];
+    rand::thread_rng().fill(server_random.as_mut_slice());
+    let encrypted_random = client_public_key.encrypt(&mut rand::thread_rng(), Pkcs1v15Encrypt, &server_random).ok()?;
+    client.write_u16(encrypted_random.len() as u16).await.ok()?;
+    client.write_all(&encrypted_random).await.ok()?;
+    client.flush().await.ok()?;
+
+    let encrypted_length = client.read_u16().await.ok()?;
+    if encrypted_length as usize != server_public_key.size() {
+        return Option::None;
+    }
+
+    let mut encrypted_random: Vec<u8> = vec![0; encrypted_length as usize];
+    client.read_exact(&mut encrypted_random).await.ok()?;
+    let client_random = rsa_key.decrypt(Pkcs1v15Encrypt, &encrypted_random).ok()?;
+    if client_random.len() != random_length {
+        return Option::None;
+    }
+
+    /* Everything from here on is framed with AES-EAX */
+    let RFBStream::Tcp(tcp_stream) = client else {
+        return Option::None;
+    };
+
+    let (read_key, write_key) = session_keys(aes256, &client_random, &server_random);
+    let mut client = RFBStream::Ra2(Box::new(RA2Stream::new(tcp_stream, &read_key, &write_key)));
+
+    /* Both sides prove they saw the same Public Keys */
+    client.write_all(&ra2_digest(aes256, &[&server_encoded_key, &client_encoded_key])).await.ok()?;
+    client.flush().await.ok()?;
+    let mut client_hash: Vec<u8> = vec![0; if aes256 { 32 } else { 20 }];
+    client.read_exact(&mut client_hash).await.ok()?;
+    if client_hash != ra2_digest(aes256, &[&client_encoded_key, &server_encoded_key]) {
+        return Option::None;
+    }
+
+    /* Only Plain Authentication has Usernames */
+    let subtype = match auth {
+        Option::Some(RFBAuthentication::Plain(_)) => RA2Subtype::USER_PASS,
+        _ => RA2Subtype::PASS,
+    };
+
+    client.write_u8(subtype).await.ok()?;
+    client.flush().await.ok()?;
+    let access_level = authenticate(&mut client, &auth).await;
+
+    /* RA2ne only protects the Handshake, the SecurityResult onwards is unencrypted */
+    if !all_encrypted {
+        let RFBStream::Ra2(ra2_stream) = client else {
+            return Option::None;
+        };
+
+        client = RFBStream::Tcp(ra2_stream.into_inner());
+    }
+
+    match access_level {
+        Option::Some(access_level) => {
+            write_security_result(&mut client, version, Option::None).await;
+            Option::Some((client, access_level))
+        },
+        Option::None => {
+            write_security_result(&mut client, version, Option::Some("Authentication Failed")).await;
+            Option::None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+    use super::*;
+    use crate::server::{PlainAuth, VNCAuth, credentials::CredentialBackend, test_support::noise_pixels};
+
+    /* Client to Server, Server to Client */
+    const READ_KEY: [u8; 16] = [1; 16];
+    const WRITE_KEY: [u8; 16] = [2; 16];
+
+    async fn connected_streams() -> (RA2Stream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _peer_addr) = listener.accept().await.unwrap();
+        (RA2Stream::new(server, &READ_KEY, &WRITE_KEY), client)
+    }
+
+    fn decrypt_messages(decryptor: &RA2Cipher, mut frames: &[u8]) -> Vec<Vec<u8>> {
+        let mut nonce: [u8; 16] = [0; 16];
+        let mut messages: Vec<Vec<u8>> = vec![];
+        while !frames.is_empty() {
+            let message_length = u16::from_be_bytes([frames[0], frames[1]]) as usize;
+            let (header, rest) = frames.split_at(MESSAGE_HEADER_LENGTH);
+            let (message, rest) = rest.split_at(message_length);
+            let (tag, rest) = rest.split_at(MESSAGE_TAG_LENGTH);
+            let mut message = message.to_vec();
+            assert!(decryptor.decrypt(&nonce, header, &mut message, tag));
+            increment_nonce(&mut nonce);
+            messages.push(message);
+            frames = rest;
+        }
+
+        messages
+    }
+
+    fn hex(hex_string: &str) -> Vec<u8> {
+        (0..hex_string.len()).step_by(2).map(|index| u8::from_str_radix(&hex_string[index..index + 2], 16).unwrap()).collect()
+    }
+
+    struct TestBackend;
+    impl CredentialBackend for TestBackend {
+        fn verify(&self, username: &str, password: &str) -> Option<AccessLevel> {
+            (username == "viewer" && password == "hunter2").then_some(AccessLevel::ViewOnly)
+        }
+    }
+
+    #[test]
+    fn nonce_is_a_little_endian_counter() {
+        let mut nonce: [u8; 16] = [0; 16];
+        increment_nonce(&mut nonce);
+        assert_eq!(nonce[..2], [1, 0]);
+
+        nonce[0] = 0xFF;
+        increment_nonce(&mut nonce);
+        assert_eq!(nonce[..3], [0, 1, 0]);
+
+        let mut nonce: [u8; 16] = [0xFF; 16];
+        nonce[15] = 0;
+        increment_nonce(&mut nonce);
+        assert_eq!(nonce[..15], [0; 15]);
+        assert_eq!(nonce[15], 1);
+
+        /* The whole Counter wraps around */
+        let mut nonce: [u8; 16] = [0xFF; 16];
+        increment_nonce(&mut nonce);
+        assert_eq!(nonce, [0; 16]);
+    }
+
+    #[test]
+    fn message_round_trip() {
+        let mut nonce: [u8; 16] = [0; 16];
+        let frame = encrypt_message(&RA2Cipher::new(&WRITE_KEY), &mut nonce, b"RFB over RA2");
+        assert_eq!(nonce[0], 1);
+        assert_eq!(frame.len(), MESSAGE_HEADER_LENGTH + 12 + MESSAGE_TAG_LENGTH);
+        assert_eq!(frame[..2], [0, 12]);
+
+        let decryptor = RA2Cipher::new(&WRITE_KEY);
+        assert_eq!(decrypt_messages(&decryptor, &frame), vec![b"RFB over RA2".to_vec()]);
+
+        /* A flipped Tag bit, or a replayed Nonce, fails Authentication */
+        let (header, message) = frame.split_at(MESSAGE_HEADER_LENGTH);
+        let (message, tag) = message.split_at(12);
+        let mut tampered_tag = tag.to_vec();
+        tampered_tag[0] ^= 1;
+        assert!(!decryptor.decrypt(&[0; 16], header, &mut message.to_vec(), &tampered_tag));
+        assert!(!decryptor.decrypt(&nonce, header, &mut message.to_vec(), tag));
+    }
+
+    #[tokio::test]
+    async fn reads_messages_split_across_reads() {
+        let (mut server, mut client) = connected_streams().await;
+        let first_message = noise_pixels(6000, 7);
+        let mut client_nonce: [u8; 16] = [0; 16];
+        let encryptor = RA2Cipher::new(&READ_KEY);
+        let frames = [
+            encrypt_message(&encryptor, &mut client_nonce, &first_message),
+            encrypt_message(&encryptor, &mut client_nonce, b"end"),
+        ].concat();
+
+        /* Frames dribble in, never lined up with Message boundaries */
+        let client_task = tokio::spawn(async move {
+            for frame_part in frames.chunks(1000) {
+                client.write_all(frame_part).await.unwrap();
+                client.flush().await.unwrap();
+                tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+            }
+
+            client
+        });
+
+        let mut plaintext: Vec<u8> = vec![0; 6003];
+        server.read_exact(&mut plaintext).await.unwrap();
+        assert_eq!(plaintext, [&first_message[..], b"end"].concat());
+        client_task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_tampered_messages() {
+        let (mut server, mut client) = connected_streams().await;
+        let mut frame = encrypt_message(&RA2Cipher::new(&READ_KEY), &mut [0; 16], b"tampered");
+        *frame.last_mut().unwrap() ^= 1;
+        client.write_all(&frame).await.unwrap();
+
+        let read_error = server.read_u8().await.unwrap_err();
+        assert_eq!(read_error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn writes_are_buffered_until_flush() {
+        let (mut server, mut client) = connected_streams().await;
+        server.write_all(b"ab").await.unwrap();
+        server.write_all(b"cd").await.unwrap();
+        server.flush().await.unwrap();
+
+        /* Larger Writes are split at MAX_MESSAGE_LENGTH */
+        let large_write = noise_pixels(20000, 3);
+        server.write_all(&large_write).await.unwrap();
+        server.shutdown().await.unwrap();
+
+        let mut frames: Vec<u8> = vec![];
+        client.read_to_end(&mut frames).await.unwrap();
+        let messages = decrypt_messages(&RA2Cipher::new(&WRITE_KEY), &frames);
+        let message_lengths: Vec<usize> = messages.iter().map(Vec::len).collect();
+        assert_eq!(message_lengths, vec![4, MAX_MESSAGE_LENGTH, MAX_MESSAGE_LENGTH, 20000 - 2 * MAX_MESSAGE_LENGTH]);
+        assert_eq!(messages[0], b"abcd");
+        assert_eq!(messages[1..].concat(), large_write);
+    }
+
+    #[test]
+    fn session_keys_per_direction() {
+        let (read_key, write_key) = session_keys(false, &[1; 16], &[2; 16]);
+        assert_eq!(read_key, hex("ae08162f2a7989e6aeac927c2346c697"));
+        assert_eq!(write_key, hex("9de59bbcf26b5adee32983762561b933"));
+
+        let (read_key, write_key) = session_keys(true, &[1; 32], &[2; 32]);
+        assert_eq!(read_key, hex("f818afd37a6dc3bc92fb44731011277006db4efa6e9023cd7468c02335d22a4d"));
+        assert_eq!(write_key, hex("c57d4f59c961b13e406cd991b0f342ec79e571dc2c1415ff72c6550645a3b198"));
+
+        /* What the Server reads with, the Client writes with */
+        assert_eq!(session_keys(true, &[2; 32], &[1; 32]), (write_key, read_key));
+    }
+
+    #[tokio::test]
+    async fn checks_vnc_passwords() {
+        let vnc_auth = Option::Some(RFBAuthentication::Vnc(VNCAuth { security_key: *b"password" }));
+        let check = |password: &str| check_credentials(&vnc_auth, String::new(), String::from(password));
+        assert_eq!(check("password").await, Option::Some(AccessLevel::FullControl));
+        assert_eq!(check("passwordextra").await, Option::Some(AccessLevel::FullControl));
+        assert_eq!(check("passw0rd").await, Option::None);
+        assert_eq!(check("pass").await, Option::None);
+
+        /* Short Passwords are padded with zeroes */
+        let vnc_auth = Option::Some(RFBAuthentication::Vnc(VNCAuth { security_key: *b"pass\0\0\0\0" }));
+        assert_eq!(check_credentials(&vnc_auth, String::new(), String::from("pass")).await, Option::Some(AccessLevel::FullControl));
+    }
+
+    #[tokio::test]
+    async fn checks_plain_passwords() {
+        let plain_auth = Option::Some(RFBAuthentication::Plain(PlainAuth { backend: Arc::new(TestBackend) }));
+        let check = |username: &str, password: &str| check_credentials(&plain_auth, String::from(username), String::from(password));
+        assert_eq!(check("viewer", "hunter2").await, Option::Some(AccessLevel::ViewOnly));
+        assert_eq!(check("viewer", "hunter3").await, Option::None);
+        assert_eq!(check("admin", "hunter2").await, Option::None);
+
+        /* Without Authentication, any Credentials are accepted */
+        assert_eq!(check_credentials(&Option::None, String::new(), String::new()).await, Option::Some(AccessLevel::FullControl));
+    }
+}
@@ -0,0 +1,199 @@
+/*
+    SpifyRFB - Modern RFB Server implementation using Rust
+    Copyright (C) 2023  Atheesh Thirumalairajan
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::{collections::HashMap, sync::RwLock};
+use once_cell::sync::Lazy;
+
+/* How the ClientInit shared-flag is treated */
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum SharedMode {
+    #[default]
+    Client, /* HONOR THE SHARED-FLAG */
+    AlwaysShared,
+    NeverShared,
+}
+
+/* What an exclusive Client does to the Viewers already connected */
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ExclusivePolicy {
+    #[default]
+    DisconnectOthers,
+    RefuseNew,
+}
+
+/* What an authenticated Client may do in its Session */
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum AccessLevel {
+    #[default]
+    FullControl,
+    ViewOnly, /* NO KEYBOARD, POINTER OR CLIPBOARD INPUT */
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SessionPolicy {
+    pub shared_mode: SharedMode,
+    pub exclusive_policy: ExclusivePolicy,
+}
+
+impl SharedMode {
+    pub fn from_name(name: &str) -> Option<SharedMode> {
+        match name {
+            "client" => Option::Some(SharedMode::Client),
+            "always" => Option::Some(SharedMode::AlwaysShared),
+            "never" => Option::Some(SharedMode::NeverShared),
+            _ => Option::None
+        }
+    }
+}
+
+impl ExclusivePolicy {
+    pub fn from_name(name: &str) -> Option<ExclusivePolicy> {
+        match name {
+            "disconnect" => Option::Some(ExclusivePolicy::DisconnectOthers),
+            "refuse" => Option::Some(ExclusivePolicy::RefuseNew),
+            _ => Option::None
+        }
+    }
+}
+
+struct ActiveSession {
+    access_level: AccessLevel,
+    disconnect_requested: bool, /* AN EXCLUSIVE CLIENT ASKED IT TO LEAVE */
+}
+
+static ACTIVE_SESSIONS: Lazy<RwLock<HashMap<String, ActiveSession>>>
+    = Lazy::new(|| { RwLock::new(HashMap::new()) });
+
+pub fn open_session(
+    client_id: String,
+    shared_flag: bool,
+    session_policy: SessionPolicy,
+    access_level: AccessLevel
+) -> bool {
+    let shared = match session_policy.shared_mode {
+        SharedMode::Client => shared_flag,
+        SharedMode::AlwaysShared => true,
+        SharedMode::NeverShared => false,
+    };
+
+    let mut sessions_lock = ACTIVE_SESSIONS.write().unwrap();
+    if !shared {
+        if session_policy.exclusive_policy == ExclusivePolicy::RefuseNew && !sessions_lock.is_empty() {
+            return false;
+        }
+
+        for active_session in sessions_lock.values_mut() {
+            active_session.disconnect_requested = true;
+        }
+    }
+
+    sessions_lock.insert(client_id, ActiveSession {
+        access_level,
+        disconnect_requested: false,
+    });
+    true
+}
+
+pub fn close_session(client_id: &str) {
+    let mut sessions_lock = ACTIVE_SESSIONS.write().unwrap();
+    sessions_lock.remove(client_id);
+}
+
+pub fn disconnect_requested(client_id: &str) -> bool {
+    let sessions_lock = ACTIVE_SESSIONS.read().unwrap();
+    sessions_lock
+        .get(client_id)
+        .map(|active_session| active_session.disconnect_requested)
+        .unwrap_or(false)
+}
+
+pub fn access_level(client_id: &str) -> AccessLevel {
+    let sessions_lock = ACTIVE_SESSIONS.read().unwrap();
+    sessions_lock
+        .get(client_id)
+        .map(|active_session| active_session.access_level)
+        .unwrap_or(AccessLevel::ViewOnly)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Mutex, MutexGuard};
+    use super::*;
+
+    /* Sessions are global, tests using them run one at a time */
+    static SESSIONS_TEST_LOCK: Mutex<()> = Mutex::new(());
+
+    fn no_sessions() -> MutexGuard<'static, ()> {
+        let test_lock = SESSIONS_TEST_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        ACTIVE_SESSIONS.write().unwrap().clear();
+        test_lock
+    }
+
+    fn policy(shared_mode: SharedMode, exclusive_policy: ExclusivePolicy) -> SessionPolicy {
+        SessionPolicy { shared_mode, exclusive_policy }
+    }
+
+    #[test]
+    fn shared_mode_overrides_the_shared_flag() {
+        /* (Mode, Shared-flag, Existing Session asked to leave) */
+        for (shared_mode, shared_flag, disconnected) in [
+            (SharedMode::Client, true, false),
+            (SharedMode::Client, false, true),
+            (SharedMode::AlwaysShared, true, false),
+            (SharedMode::AlwaysShared, false, false),
+            (SharedMode::NeverShared, true, true),
+            (SharedMode::NeverShared, false, true),
+        ] {
+            let _test_lock = no_sessions();
+            let session_policy = policy(shared_mode, ExclusivePolicy::DisconnectOthers);
+            assert!(open_session(String::from("existing"), true, session_policy, AccessLevel::FullControl));
+            assert!(open_session(String::from("new"), shared_flag, session_policy, AccessLevel::FullControl));
+            assert_eq!(disconnect_requested("existing"), disconnected, "{:?}, shared-flag {}", shared_mode, shared_flag);
+            assert!(!disconnect_requested("new"));
+        }
+    }
+
+    #[test]
+    fn exclusive_client_disconnects_others() {
+        let _test_lock = no_sessions();
+        let shared_policy = policy(SharedMode::Client, ExclusivePolicy::DisconnectOthers);
+        assert!(open_session(String::from("first"), true, shared_policy, AccessLevel::FullControl));
+        assert!(open_session(String::from("second"), true, shared_policy, AccessLevel::ViewOnly));
+        assert!(open_session(String::from("exclusive"), false, shared_policy, AccessLevel::FullControl));
+
+        assert!(disconnect_requested("first"));
+        assert!(disconnect_requested("second"));
+        assert!(!disconnect_requested("exclusive"));
+        assert_eq!(access_level("exclusive"), AccessLevel::FullControl);
+    }
+
+    #[test]
+    fn exclusive_client_is_refused() {
+        let _test_lock = no_sessions();
+        let refuse_policy = policy(SharedMode::Client, ExclusivePolicy::RefuseNew);
+        assert!(open_session(String::from("first"), true, refuse_policy, AccessLevel::FullControl));
+        assert!(!open_session(String::from("exclusive"), false, refuse_policy, AccessLevel::FullControl));
+
+        assert!(!disconnect_requested("first"));
+        assert!(!ACTIVE_SESSIONS.read().unwrap().contains_key("exclusive"));
+
+        /* Nobody to refuse it for once the others are gone */
+        close_session("first");
+        assert!(open_session(String::from("exclusive"), false, refuse_policy, AccessLevel::FullControl));
+    }
+}
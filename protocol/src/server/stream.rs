@@ -0,0 +1,83 @@
+/*
+    SpifyRFB - Modern RFB Server implementation using Rust
+    Copyright (C) 2023  Atheesh Thirumalairajan
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::{io, net::SocketAddr, pin::Pin, task::{Context, Poll}};
+use tokio::{io::{AsyncRead, AsyncWrite, ReadBuf}, net::TcpStream};
+use tokio_rustls::server::TlsStream;
+use super::rsa_aes::RA2Stream;
+
+/* An RFB Session, upgraded to TLS or AES-EAX by Security Types that negotiate it */
+pub enum RFBStream {
+    Tcp(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+    Ra2(Box<RA2Stream>)
+}
+
+impl RFBStream {
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            RFBStream::Tcp(stream) => stream.peer_addr(),
+            RFBStream::Tls(stream) => stream.get_ref().0.peer_addr(),
+            RFBStream::Ra2(stream) => stream.peer_addr(),
+        }
+    }
+}
+
+impl AsyncRead for RFBStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match &mut *self {
+            RFBStream::Tcp(stream) => { Pin::new(stream).poll_read(cx, buf) },
+            RFBStream::Tls(stream) => { Pin::new(stream).poll_read(cx, buf) },
+            RFBStream::Ra2(stream) => { Pin::new(stream).poll_read(cx, buf) },
+        }
+    }
+}
+
+impl AsyncWrite for RFBStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        match &mut *self {
+            RFBStream::Tcp(stream) => { Pin::new(stream).poll_write(cx, buf) },
+            RFBStream::Tls(stream) => { Pin::new(stream).poll_write(cx, buf) },
+            RFBStream::Ra2(stream) => { Pin::new(stream).poll_write(cx, buf) },
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        match &mut *self {
+            RFBStream::Tcp(stream) => { Pin::new(stream).poll_flush(cx) },
+            RFBStream::Tls(stream) => { Pin::new(stream).poll_flush(cx) },
+            RFBStream::Ra2(stream) => { Pin::new(stream).poll_flush(cx) },
+        }
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        match &mut *self {
+            RFBStream::Tcp(stream) => { Pin::new(stream).poll_shutdown(cx) },
+            RFBStream::Tls(stream) => { Pin::new(stream).poll_shutdown(cx) },
+            RFBStream::Ra2(stream) => { Pin::new(stream).poll_shutdown(cx) },
+        }
+    }
+}
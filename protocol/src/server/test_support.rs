@@ -0,0 +1,174 @@
+/*
+    SpifyRFB - Modern RFB Server implementation using Rust
+    Copyright (C) 2023  Atheesh Thirumalairajan
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+/* Fixtures shared by the Encoder Tests */
+
+use std::ptr;
+use super::{FrameBuffer, RFBEncodingType, encoding_zlib};
+
+pub fn noise_pixels(length: usize, seed: u32) -> Vec<u8> {
+    /* Reproducible noise from a Linear Congruential Generator */
+    let mut seed = seed;
+    (0..length).map(|_| {
+        seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+        (seed >> 16) as u8
+    }).collect()
+}
+
+pub fn framebuffer(width: u16, height: u16, raw_pixels: Vec<u8>) -> FrameBuffer {
+    /* 32 bit RAW Capture at the origin */
+    FrameBuffer {
+        x_position: 0,
+        y_position: 0,
+        width,
+        height,
+        bits_per_pixel: 32,
+        encoding: RFBEncodingType::RAW,
+        raw_pixels,
+        encoded_pixels: vec![],
+    }
+}
+
+pub fn decode_tile(tile_data: &[u8], offset: &mut usize, width: usize, height: usize, bytes_per_cpixel: usize) -> Vec<u8> {
+    /* TRLE and ZRLE Tile, any sub-encoding */
+    let mut take = |length: usize| {
+        *offset += length;
+        &tile_data[(*offset - length)..*offset]
+    };
+
+    let subencoding = take(1)[0] as usize;
+    let pixel_count = width * height;
+    match subencoding {
+        0 => take(pixel_count * bytes_per_cpixel).to_vec(),
+        1 => take(bytes_per_cpixel).repeat(pixel_count),
+        2..=16 => {
+            let palette = take(subencoding * bytes_per_cpixel).to_vec();
+            let bits = match subencoding { 2 => 1, 3..=4 => 2, _ => 4 };
+            let row_bytes = (width * bits).div_ceil(8);
+            let mut cpixels: Vec<u8> = vec![];
+            for _ in 0..height {
+                let row = take(row_bytes);
+                for x in 0..width {
+                    let bit_offset = x * bits;
+                    let index = (row[bit_offset / 8] >> (8 - bits - bit_offset % 8)) as usize & ((1 << bits) - 1);
+                    cpixels.extend_from_slice(&palette[(index * bytes_per_cpixel)..((index + 1) * bytes_per_cpixel)]);
+                }
+            }
+            cpixels
+        },
+        128 | 130..=255 => {
+            let palette = take(subencoding.saturating_sub(128) * bytes_per_cpixel).to_vec();
+            let mut cpixels: Vec<u8> = vec![];
+            while cpixels.len() < pixel_count * bytes_per_cpixel {
+                let (cpixel, run) = if subencoding == 128 {
+                    (take(bytes_per_cpixel).to_vec(), true)
+                } else {
+                    let index = take(1)[0] as usize;
+                    let palette_index = index & 127;
+                    (palette[(palette_index * bytes_per_cpixel)..((palette_index + 1) * bytes_per_cpixel)].to_vec(), index & 128 != 0)
+                };
+
+                let mut run_length = 1;
+                if run {
+                    loop {
+                        let byte = take(1)[0];
+                        run_length += byte as usize;
+                        if byte != 255 {
+                            break;
+                        }
+                    }
+                }
+
+                cpixels.extend(cpixel.repeat(run_length));
+            }
+            cpixels
+        },
+        _ => panic!("unexpected sub-encoding {}", subencoding),
+    }
+}
+
+pub fn decode_tiles(encoded_tiles: &[u8], width: usize, height: usize, tile_size: usize, bytes_per_cpixel: usize) -> Vec<u8> {
+    let mut cpixels: Vec<u8> = vec![0; width * height * bytes_per_cpixel];
+    let mut offset = 0;
+    for tile_y in (0..height).step_by(tile_size) {
+        let tile_height = tile_size.min(height - tile_y);
+        for tile_x in (0..width).step_by(tile_size) {
+            let tile_width = tile_size.min(width - tile_x);
+            let tile = decode_tile(encoded_tiles, &mut offset, tile_width, tile_height, bytes_per_cpixel);
+            for (line, row) in tile.chunks_exact(tile_width * bytes_per_cpixel).enumerate() {
+                let start = ((tile_y + line) * width + tile_x) * bytes_per_cpixel;
+                cpixels[start..(start + row.len())].copy_from_slice(row);
+            }
+        }
+    }
+
+    assert_eq!(offset, encoded_tiles.len());
+    cpixels
+}
+
+/* Client side of a zlib Stream shared by many Rectangles */
+pub struct Inflater {
+    zlib_stream: Box<libz_sys::z_stream>,
+}
+
+impl Inflater {
+    pub fn new() -> Inflater {
+        let (mut zlib_stream, _) = encoding_zlib::new_stream();
+        let inflate_init_status = unsafe {
+            libz_sys::inflateInit_(
+                zlib_stream.as_mut(),
+                libz_sys::zlibVersion(),
+                std::mem::size_of::<libz_sys::z_stream>() as i32
+            )
+        };
+
+        assert_eq!(inflate_init_status, libz_sys::Z_OK);
+        Inflater { zlib_stream }
+    }
+
+    pub fn inflate(&mut self, zlib_data: &[u8]) -> Vec<u8> {
+        let mut next_in = zlib_data.to_vec();
+        let mut inflated: Vec<u8> = vec![];
+        self.zlib_stream.next_in = next_in.as_mut_ptr();
+        self.zlib_stream.avail_in = next_in.len() as u32;
+
+        loop {
+            let mut next_out: Vec<u8> = vec![0; 4096];
+            self.zlib_stream.next_out = next_out.as_mut_ptr();
+            self.zlib_stream.avail_out = next_out.len() as u32;
+
+            let inflate_status = unsafe { libz_sys::inflate(self.zlib_stream.as_mut(), libz_sys::Z_SYNC_FLUSH) };
+            assert!(inflate_status == libz_sys::Z_OK || inflate_status == libz_sys::Z_BUF_ERROR, "inflate failed: {}", inflate_status);
+
+            let produced = next_out.len() - self.zlib_stream.avail_out as usize;
+            inflated.extend_from_slice(&next_out[..produced]);
+            if self.zlib_stream.avail_in == 0 && self.zlib_stream.avail_out != 0 {
+                break;
+            }
+        }
+
+        self.zlib_stream.next_in = ptr::null_mut();
+        inflated
+    }
+}
+
+impl Drop for Inflater {
+    fn drop(&mut self) {
+        unsafe { libz_sys::inflateEnd(self.zlib_stream.as_mut()); }
+    }
+}
@@ -0,0 +1,304 @@
+/*
+    SpifyRFB - Modern RFB Server implementation using Rust
+    Copyright (C) 2023  Atheesh Thirumalairajan
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use super::PixelFormat;
+
+/* Colour-map Clients get a fixed Colour Cube, six levels per channel */
+const COLOUR_CUBE_LEVELS: u32 = 6;
+
+fn read_pixel(pixel: &[u8], pixelformat: &PixelFormat) -> u32 {
+    if pixelformat.big_endian_flag != 0 {
+        pixel.iter().fold(0_u32, |value, byte| (value << 8) | *byte as u32)
+    } else {
+        pixel.iter().rev().fold(0_u32, |value, byte| (value << 8) | *byte as u32)
+    }
+}
+
+fn write_pixel(pixel_data: &mut Vec<u8>, value: u32, pixelformat: &PixelFormat) {
+    let bytes_per_pixel = (pixelformat.bits_per_pixel / 8) as usize;
+    if pixelformat.big_endian_flag != 0 {
+        pixel_data.extend_from_slice(&value.to_be_bytes()[(4 - bytes_per_pixel)..]);
+    } else {
+        pixel_data.extend_from_slice(&value.to_le_bytes()[..bytes_per_pixel]);
+    }
+}
+
+fn scale_channel(value: u32, from_max: u16, to_max: u16) -> u32 {
+    if from_max == to_max || from_max == 0 {
+        return value;
+    }
+
+    (value * to_max as u32 + from_max as u32 / 2) / from_max as u32
+}
+
+fn channels(value: u32, pixelformat: &PixelFormat) -> (u32, u32, u32) {
+    (
+        (value >> pixelformat.red_shift) & pixelformat.red_max as u32,
+        (value >> pixelformat.green_shift) & pixelformat.green_max as u32,
+        (value >> pixelformat.blue_shift) & pixelformat.blue_max as u32,
+    )
+}
+
+fn colour_mask(pixelformat: &PixelFormat) -> u32 {
+    (pixelformat.red_max as u32) << pixelformat.red_shift
+        | (pixelformat.green_max as u32) << pixelformat.green_shift
+        | (pixelformat.blue_max as u32) << pixelformat.blue_shift
+}
+
+fn colour_cube_index(red: u32, green: u32, blue: u32) -> u32 {
+    /* 8 bit channels to the nearest level of the Cube */
+    let level = |value: u32| (value * (COLOUR_CUBE_LEVELS - 1) + 127) / 255;
+    (level(red) * COLOUR_CUBE_LEVELS + level(green)) * COLOUR_CUBE_LEVELS + level(blue)
+}
+
+pub fn colour_map_entries() -> Vec<[u16; 3]> {
+    /* R, G, B (U16) for every index of the Cube */
+    let level = |value: u32| (value * 65535 / (COLOUR_CUBE_LEVELS - 1)) as u16;
+    (0..COLOUR_CUBE_LEVELS.pow(3))
+        .map(|index| [
+            level(index / (COLOUR_CUBE_LEVELS * COLOUR_CUBE_LEVELS)),
+            level(index / COLOUR_CUBE_LEVELS % COLOUR_CUBE_LEVELS),
+            level(index % COLOUR_CUBE_LEVELS)
+        ])
+        .collect()
+}
+
+pub fn same_format(format_a: &PixelFormat, format_b: &PixelFormat) -> bool {
+    format_a.bits_per_pixel == format_b.bits_per_pixel
+        && format_a.big_endian_flag == format_b.big_endian_flag
+        && format_a.true_color_flag == format_b.true_color_flag
+        && format_a.red_max == format_b.red_max
+        && format_a.green_max == format_b.green_max
+        && format_a.blue_max == format_b.blue_max
+        && format_a.red_shift == format_b.red_shift
+        && format_a.green_shift == format_b.green_shift
+        && format_a.blue_shift == format_b.blue_shift
+}
+
+pub fn rgb_to_pixel(red: u8, green: u8, blue: u8, pixelformat: &PixelFormat) -> Vec<u8> {
+    let value = if pixelformat.true_color_flag == 0 {
+        colour_cube_index(red as u32, green as u32, blue as u32)
+    } else {
+        scale_channel(red as u32, 255, pixelformat.red_max) << pixelformat.red_shift
+            | scale_channel(green as u32, 255, pixelformat.green_max) << pixelformat.green_shift
+            | scale_channel(blue as u32, 255, pixelformat.blue_max) << pixelformat.blue_shift
+    };
+
+    let mut pixel_data: Vec<u8> = Vec::with_capacity(4);
+    write_pixel(&mut pixel_data, value, pixelformat);
+    pixel_data
+}
+
+pub fn translate_pixels(pixels: &[u8], server_format: &PixelFormat, client_format: &PixelFormat) -> Vec<u8> {
+    /* Only True-Colour Servers can be translated */
+    if same_format(server_format, client_format) || server_format.true_color_flag == 0 {
+        return pixels.to_vec();
+    }
+
+    let server_bytes = (server_format.bits_per_pixel / 8) as usize;
+    let client_bytes = (client_format.bits_per_pixel / 8) as usize;
+    let mut client_pixels: Vec<u8> = Vec::with_capacity(pixels.len() / server_bytes * client_bytes);
+
+    for pixel in pixels.chunks_exact(server_bytes) {
+        let (red, green, blue) = channels(read_pixel(pixel, server_format), server_format);
+        let value = if client_format.true_color_flag == 0 {
+            colour_cube_index(
+                scale_channel(red, server_format.red_max, 255),
+                scale_channel(green, server_format.green_max, 255),
+                scale_channel(blue, server_format.blue_max, 255)
+            )
+        } else {
+            scale_channel(red, server_format.red_max, client_format.red_max) << client_format.red_shift
+                | scale_channel(green, server_format.green_max, client_format.green_max) << client_format.green_shift
+                | scale_channel(blue, server_format.blue_max, client_format.blue_max) << client_format.blue_shift
+        };
+
+        write_pixel(&mut client_pixels, value, client_format);
+    }
+
+    client_pixels
+}
+
+pub fn get_cpixels(client_pixels: &[u8], client_format: &PixelFormat) -> Vec<u8> {
+    /* CPIXEL: 32 bpp True-Colour with every colour bit in three of the four bytes drops the other one */
+    let bytes_per_pixel = (client_format.bits_per_pixel / 8) as usize;
+    let colour_mask = colour_mask(client_format);
+    let compact = client_format.true_color_flag != 0 && bytes_per_pixel == 4 && client_format.depth <= 24;
+
+    let kept_bytes = if compact && colour_mask & 0xFF000000 == 0 {
+        /* Least significant three bytes */
+        if client_format.big_endian_flag != 0 { 1..4 } else { 0..3 }
+    } else if compact && colour_mask & 0xFF == 0 {
+        /* Most significant three bytes */
+        if client_format.big_endian_flag != 0 { 0..3 } else { 1..4 }
+    } else {
+        0..bytes_per_pixel
+    };
+
+    client_pixels
+        .chunks_exact(bytes_per_pixel)
+        .flat_map(|pixel| pixel[kept_bytes.clone()].to_vec())
+        .collect()
+}
+
+pub fn get_tpixels(client_pixels: &[u8], client_format: &PixelFormat) -> Vec<u8> {
+    /* TPIXEL: R, G, B for 24 bit depth True-Colour, otherwise the whole PIXEL */
+    let bytes_per_pixel = (client_format.bits_per_pixel / 8) as usize;
+    let rgb_format = client_format.true_color_flag != 0
+        && bytes_per_pixel == 4
+        && client_format.depth == 24
+        && client_format.red_max == 255
+        && client_format.green_max == 255
+        && client_format.blue_max == 255;
+
+    if !rgb_format {
+        return client_pixels.to_vec();
+    }
+
+    get_rgb_pixels(client_pixels, client_format)
+}
+
+pub fn get_rgb_pixels(pixels: &[u8], pixelformat: &PixelFormat) -> Vec<u8> {
+    /* Eight bits per channel, R, G, B (TightPNG, JPEG) */
+    let bytes_per_pixel = (pixelformat.bits_per_pixel / 8) as usize;
+    let mut rgb_pixels: Vec<u8> = Vec::with_capacity(pixels.len() / bytes_per_pixel * 3);
+    for pixel in pixels.chunks_exact(bytes_per_pixel) {
+        let (red, green, blue) = channels(read_pixel(pixel, pixelformat), pixelformat);
+        rgb_pixels.extend_from_slice(&[
+            scale_channel(red, pixelformat.red_max, 255) as u8,
+            scale_channel(green, pixelformat.green_max, 255) as u8,
+            scale_channel(blue, pixelformat.blue_max, 255) as u8,
+        ]);
+    }
+
+    rgb_pixels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rgb888(big_endian_flag: u8) -> PixelFormat {
+        PixelFormat {
+            bits_per_pixel: 32,
+            depth: 24,
+            big_endian_flag,
+            true_color_flag: 1,
+            red_max: 255,
+            green_max: 255,
+            blue_max: 255,
+            red_shift: 16,
+            green_shift: 8,
+            blue_shift: 0,
+            padding: [0; 3],
+        }
+    }
+
+    fn rgb565(big_endian_flag: u8) -> PixelFormat {
+        PixelFormat {
+            bits_per_pixel: 16,
+            depth: 16,
+            big_endian_flag,
+            true_color_flag: 1,
+            red_max: 31,
+            green_max: 63,
+            blue_max: 31,
+            red_shift: 11,
+            green_shift: 5,
+            blue_shift: 0,
+            padding: [0; 3],
+        }
+    }
+
+    fn colour_map() -> PixelFormat {
+        PixelFormat { bits_per_pixel: 8, depth: 8, true_color_flag: 0, ..Default::default() }
+    }
+
+    /* Red, Green, Blue, White as little endian 0x00RRGGBB */
+    const SERVER_PIXELS: [u8; 16] = [
+        0x00, 0x00, 0xFF, 0x00,
+        0x00, 0xFF, 0x00, 0x00,
+        0xFF, 0x00, 0x00, 0x00,
+        0xFF, 0xFF, 0xFF, 0x00,
+    ];
+
+    #[test]
+    fn same_format_is_copied() {
+        assert_eq!(translate_pixels(&SERVER_PIXELS, &rgb888(0), &rgb888(0)), SERVER_PIXELS.to_vec());
+    }
+
+    #[test]
+    fn translates_to_big_endian() {
+        let client_pixels = translate_pixels(&SERVER_PIXELS, &rgb888(0), &rgb888(1));
+        assert_eq!(client_pixels, vec![
+            0x00, 0xFF, 0x00, 0x00,
+            0x00, 0x00, 0xFF, 0x00,
+            0x00, 0x00, 0x00, 0xFF,
+            0x00, 0xFF, 0xFF, 0xFF,
+        ]);
+
+        /* And back */
+        assert_eq!(translate_pixels(&client_pixels, &rgb888(1), &rgb888(0)), SERVER_PIXELS.to_vec());
+    }
+
+    #[test]
+    fn translates_to_rgb565() {
+        assert_eq!(translate_pixels(&SERVER_PIXELS, &rgb888(0), &rgb565(0)), vec![
+            0x00, 0xF8,
+            0xE0, 0x07,
+            0x1F, 0x00,
+            0xFF, 0xFF,
+        ]);
+
+        assert_eq!(translate_pixels(&SERVER_PIXELS, &rgb888(0), &rgb565(1))[..2], [0xF8, 0x00]);
+    }
+
+    #[test]
+    fn translates_to_colour_cube() {
+        /* Index is (R * 6 + G) * 6 + B with six levels per channel */
+        assert_eq!(translate_pixels(&SERVER_PIXELS, &rgb888(0), &colour_map()), vec![180, 30, 5, 215]);
+        assert_eq!(rgb_to_pixel(0, 0, 0, &colour_map()), vec![0]);
+        assert_eq!(colour_map_entries()[215], [65535, 65535, 65535]);
+        assert_eq!(colour_map_entries().len(), 216);
+    }
+
+    #[test]
+    fn rgb_to_pixel_matches_translation() {
+        assert_eq!(rgb_to_pixel(255, 0, 0, &rgb565(0)), vec![0x00, 0xF8]);
+        assert_eq!(rgb_to_pixel(0, 0, 255, &rgb888(1)), vec![0x00, 0x00, 0x00, 0xFF]);
+    }
+
+    #[test]
+    fn cpixels_drop_the_unused_byte() {
+        assert_eq!(get_cpixels(&SERVER_PIXELS[..8], &rgb888(0)), vec![0x00, 0x00, 0xFF, 0x00, 0xFF, 0x00]);
+
+        let big_endian = translate_pixels(&SERVER_PIXELS[..4], &rgb888(0), &rgb888(1));
+        assert_eq!(get_cpixels(&big_endian, &rgb888(1)), vec![0xFF, 0x00, 0x00]);
+
+        /* 16 bit Pixels are kept whole */
+        assert_eq!(get_cpixels(&[0x00, 0xF8], &rgb565(0)), vec![0x00, 0xF8]);
+    }
+
+    #[test]
+    fn rgb_pixels_scale_to_eight_bits() {
+        assert_eq!(get_rgb_pixels(&SERVER_PIXELS[12..], &rgb888(0)), vec![0xFF, 0xFF, 0xFF]);
+        assert_eq!(get_rgb_pixels(&[0xE0, 0x07], &rgb565(0)), vec![0x00, 0xFF, 0x00]);
+        assert_eq!(get_tpixels(&SERVER_PIXELS[..4], &rgb888(0)), vec![0xFF, 0x00, 0x00]);
+        assert_eq!(get_tpixels(&[0xE0, 0x07], &rgb565(0)), vec![0xE0, 0x07]);
+    }
+}
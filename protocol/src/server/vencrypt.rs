@@ -0,0 +1,135 @@
+/*
+    SpifyRFB - Modern RFB Server implementation using Rust
+    Copyright (C) 2023  Atheesh Thirumalairajan
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_rustls::TlsAcceptor;
+use super::{
+    PlainAuth, RFBAuthentication, RFBVersion, sessions::AccessLevel, stream::RFBStream,
+    vnc_authenticate, write_security_result
+};
+
+/* VeNCrypt Version 0.2 */
+const VENCRYPT_VERSION: [u8; 2] = [0, 2];
+
+/* Longer Usernames and Passwords are refused without reading them */
+const MAX_CREDENTIAL_LENGTH: u32 = 1024;
+
+pub struct VeNCryptSubtype;
+impl VeNCryptSubtype {
+    pub const X509_NONE: u32 = 260;
+    pub const X509_VNC: u32 = 261;
+    pub const X509_PLAIN: u32 = 262;
+}
+
+fn offered_subtypes(auth: &Option<RFBAuthentication>) -> Vec<u32> {
+    /*
+        TLSNone, TLSVnc and TLSPlain (257-259) are anonymous Diffie-Hellman,
+        rustls has no such cipher suites. Clients asking for them refuse a
+        handshake with a Certificate, so only X509* sub-types are offered.
+    */
+    match auth {
+        Option::None => vec![VeNCryptSubtype::X509_NONE],
+        Option::Some(RFBAuthentication::Vnc(_)) => vec![VeNCryptSubtype::X509_VNC],
+        Option::Some(RFBAuthentication::Plain(_)) => vec![VeNCryptSubtype::X509_PLAIN],
+    }
+}
+
+async fn plain_authenticate(client: &mut RFBStream, plain_auth: &PlainAuth) -> Option<AccessLevel> {
+    /* username-length (U32), password-length (U32), username, password */
+    let username_length = client.read_u32().await.ok()?;
+    let password_length = client.read_u32().await.ok()?;
+    if username_length > MAX_CREDENTIAL_LENGTH || password_length > MAX_CREDENTIAL_LENGTH {
+        return Option::None;
+    }
+
+    let mut username: Vec<u8> = vec![0; username_length as usize];
+    let mut password: Vec<u8> = vec![0; password_length as usize];
+    client.read_exact(&mut username).await.ok()?;
+    client.read_exact(&mut password).await.ok()?;
+
+    /* bcrypt and argon2 are slow on purpose, keep them off the Runtime */
+    let username = String::from_utf8(username).ok()?;
+    let password = String::from_utf8(password).ok()?;
+    let backend = plain_auth.backend.clone();
+    tokio::task::spawn_blocking(move || backend.verify(&username, &password)).await.ok()?
+}
+
+pub(crate) async fn init_vencrypt_handshake(
+    mut client: RFBStream,
+    version: RFBVersion,
+    auth: Option<RFBAuthentication>,
+    tls_acceptor: TlsAcceptor
+) -> Option<(RFBStream, AccessLevel)> {
+    /* Agree on the VeNCrypt Version, the Server answers 0 (Ok) or 1 (Failed) */
+    client.write_all(&VENCRYPT_VERSION).await.ok()?;
+    client.flush().await.ok()?;
+    let mut client_version: [u8; 2] = [0; 2];
+    client.read_exact(&mut client_version).await.ok()?;
+    if client_version != VENCRYPT_VERSION {
+        client.write_u8(1).await.unwrap_or(());
+        return Option::None;
+    }
+
+    client.write_u8(0).await.ok()?;
+
+    /* number-of-subtypes (U8), subtypes (U32), the Client picks one */
+    let subtypes = offered_subtypes(&auth);
+    let mut subtypes_message: Vec<u8> = vec![subtypes.len() as u8];
+    for subtype in &subtypes {
+        subtypes_message.extend_from_slice(&subtype.to_be_bytes());
+    }
+
+    client.write_all(&subtypes_message).await.ok()?;
+    client.flush().await.ok()?;
+    let selected_subtype = client.read_u32().await.ok()?;
+
+    /* 1 (Accepted) or 0 (Rejected) */
+    if !subtypes.contains(&selected_subtype) {
+        client.write_u8(0).await.unwrap_or(());
+        return Option::None;
+    }
+
+    client.write_u8(1).await.ok()?;
+    client.flush().await.ok()?;
+
+    /* Everything from here on runs over TLS */
+    let RFBStream::Tcp(tcp_stream) = client else {
+        return Option::None;
+    };
+
+    let mut client = RFBStream::Tls(Box::new(tls_acceptor.accept(tcp_stream).await.ok()?));
+    let access_level = match &auth {
+        Option::None => Option::Some(AccessLevel::FullControl),
+        Option::Some(RFBAuthentication::Vnc(vnc_auth)) => vnc_authenticate(&mut client, vnc_auth.security_key)
+            .await
+            .then_some(AccessLevel::FullControl),
+        Option::Some(RFBAuthentication::Plain(plain_auth)) => plain_authenticate(&mut client, plain_auth).await,
+    };
+
+    /* VeNCrypt is not Type None, the SecurityResult is always sent */
+    match access_level {
+        Option::Some(access_level) => {
+            write_security_result(&mut client, version, Option::None).await;
+            Option::Some((client, access_level))
+        },
+        Option::None => {
+            write_security_result(&mut client, version, Option::Some("Authentication Failed")).await;
+            Option::None
+        }
+    }
+}
@@ -23,8 +23,9 @@ use crate::server::win32;
 use crate::x11;
 
 use crate::{debug, server::{parser, ipc_client}, authenticate};
-use std::{error::Error, time::Duration, sync::Arc, pin::Pin, process, env};
-use super::{parser::{websocket::OPCODE, GetBits}, FrameBufferUpdate, WindowManager, RFBEncodingType};
+use std::{error::Error, time::Duration, sync::{Arc, RwLock}, pin::Pin, process, collections::HashSet, net::SocketAddr};
+use once_cell::sync::Lazy;
+use super::{parser::{websocket::OPCODE, GetBits}, incremental, FrameBufferUpdate, WindowManager, RFBEncodingType, RFBEncodings};
 use rustls::ServerConfig;
 use tokio::{
     io::{self, AsyncReadExt, AsyncWriteExt, AsyncRead, AsyncWrite},
@@ -40,6 +41,24 @@ pub struct WSCreateOptions {
     pub(crate) spify_daemon: bool
 }
 
+/* Local Addresses of the Proxy's Connections to the RFB Server */
+static PROXIED_CONNECTIONS: Lazy<RwLock<HashSet<SocketAddr>>>
+    = Lazy::new(|| { RwLock::new(HashSet::new()) });
+
+/* Registered while the Proxy is connected, removed on Drop */
+struct ProxiedConnection(SocketAddr);
+impl Drop for ProxiedConnection {
+    fn drop(&mut self) {
+        let mut proxied_lock = PROXIED_CONNECTIONS.write().unwrap();
+        proxied_lock.remove(&self.0);
+    }
+}
+
+pub fn is_proxied(peer_address: SocketAddr) -> bool {
+    let proxied_lock = PROXIED_CONNECTIONS.read().unwrap();
+    proxied_lock.contains(&peer_address)
+}
+
 enum WebsocketStream {
     WS(TcpStream),
     WSS(TlsStream<TcpStream>)
@@ -112,6 +131,12 @@ async fn proxy_websocket(ws_stream: WebsocketStream, proxy_address: String) {
     }
 
     let mut remote = remote_connection.unwrap();
+    let _proxied_connection = remote.local_addr().ok().map(|local_address| {
+        let mut proxied_lock = PROXIED_CONNECTIONS.write().unwrap();
+        proxied_lock.insert(local_address);
+        ProxiedConnection(local_address)
+    });
+
     loop {
         /* Read Websocket Opcode */
         let mut buf: [u8; 2] = [0; 2];
@@ -441,6 +466,10 @@ fn get_api_response(req: Vec<u8>) -> (String, Vec<u8>) {
 
             /* Verify Client Auth in Future */
             let mut framebufferupdate: FrameBufferUpdate = Default::default();
+            let screenshot_encodings = RFBEncodings {
+                encodings: vec![RFBEncodingType::RAW],
+                ..Default::default()
+            };
 
             #[cfg(target_os = "windows")]
             {
@@ -460,7 +489,8 @@ fn get_api_response(req: Vec<u8>) -> (String, Vec<u8>) {
                             framebufferupdate = win32::rectangle_framebuffer_update(
                                 win32_server, 
                                 primary_display.clone(), 
-                                RFBEncodingType::RAW, 
+                                &screenshot_encodings, 
+                                false,
                                 0, 
                                 0, 
                                 primary_display.monitor_devmode.dmPelsWidth as u16, 
@@ -491,7 +521,8 @@ fn get_api_response(req: Vec<u8>) -> (String, Vec<u8>) {
                             framebufferupdate = x11::rectangle_framebuffer_update(
                                 x11_server, 
                                 primary_display.clone(), 
-                                RFBEncodingType::RAW, 
+                                &screenshot_encodings, 
+                                false,
                                 0, 
                                 0, 
                                 primary_display.clone().width_in_pixels, 
@@ -504,6 +535,8 @@ fn get_api_response(req: Vec<u8>) -> (String, Vec<u8>) {
                 }
             }
 
+            /* Screenshots are not Incremental, Drop the cached Frame */
+            incremental::flush_framebuffer(String::from("webapi"));
             let framebuffer_rect = &framebufferupdate.frame_buffer[0];            
             let mut png_data: Vec<u8> = Vec::new();
 
@@ -560,35 +593,7 @@ pub async fn create(options: WSCreateOptions) -> Result<(), Box<dyn Error>> {
             let mut tls_acceptor: Option<TlsAcceptor> = Option::None;
 
             if options.secure == true {
-                let mut spify_installpath = env::current_exe().unwrap();
-                spify_installpath.pop();
-                spify_installpath.push("ssl");
-                spify_installpath.push("cert");
-
-                /* 
-                    We added an extra directory 'cert' as
-                    Rust pops the last element in the path
-                    when set_file_name() is invoked.
-                */
-
-                spify_installpath.set_file_name("cert.pem");
-                let certificate_path = spify_installpath.clone();
-                let certificate_path = certificate_path.to_str().unwrap();
-
-                spify_installpath.set_file_name("key.pem");
-                let key_path = spify_installpath.clone();
-                let key_path = key_path.to_str().unwrap();
-
-                tls_serverconfig = Option::Some(
-                    ServerConfig::builder()
-                    .with_safe_defaults()
-                    .with_no_client_auth()
-                    .with_single_cert(
-                        parser::tls::load_certificates(certificate_path),
-                        parser::tls::load_privatekey(key_path)
-                    )
-                    .unwrap()
-                );
+                tls_serverconfig = Option::Some(parser::tls::load_serverconfig());
 
                 tls_acceptor = Option::Some(
                     TlsAcceptor::from(Arc::new(tls_serverconfig.clone().unwrap()))
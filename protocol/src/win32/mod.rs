@@ -41,10 +41,9 @@ use crate::server::RFBEncodingType;
 use crate::server::RFBServerInit;
 use crate::server::ServerToClientMessage;
 use crate::server::WindowManager;
-use crate::server::encoding_hextile;
-use crate::server::encoding_raw;
-use crate::server::encoding_zlib;
-use crate::server::encoding_zrle;
+use crate::server::incremental;
+use crate::server::encoding_copyrect;
+use crate::server::RFBEncodings;
 
 trait ToU16Vec {
     fn to_u16_vec(input: String) -> Vec<u16>;
@@ -255,7 +254,8 @@ pub fn fire_pointer_event(
 pub fn rectangle_framebuffer_update(
     win32_server: &Win32Server,
     _win32_monitor: Win32Monitor, 
-    encoding_type: i32,
+    client_encodings: &RFBEncodings,
+    incremental: bool,
     x_position: i16,
     y_position: i16,
     width: u16,
@@ -327,33 +327,7 @@ pub fn rectangle_framebuffer_update(
         Win32_Gdi::DeleteDC(compatible_dc);
         Win32_Gdi::ReleaseDC(Win32_Foundation::HWND(0), desktop_dc);
 
-        /* Define Shifts */
-        let red = (pixelformat.red_shift / 8) as usize;
-        let green = (pixelformat.green_shift / 8) as usize;
-        let blue = (pixelformat.blue_shift / 8) as usize;
-
-        /* Encode pixels according to PixelFormat */
-        let mut pixformat_data: Vec<u8> = Vec::with_capacity(pixel_data.len());
-        let pixel_chunks: Vec<&mut [u8]> = pixel_data.chunks_mut((WIN32_BITS_PER_PIXEL / 8) as usize).collect();
-
-        for pixel in pixel_chunks {            
-            let pixel_copy = pixel.to_owned();
-            pixel[red] = pixel_copy[2];
-            pixel[green] = pixel_copy[1];
-            pixel[blue] = pixel_copy[0];
-            
-            if encoding_type == RFBEncodingType::ZRLE {
-                /* Extend Encoded Data for ZRLE */
-                pixformat_data.extend_from_slice(&[
-                    pixel[0],
-                    pixel[1],
-                    pixel[2]
-                ]);
-            }
-        }
-
-        let mut framebuffer_rectangles: Vec<FrameBufferRectangle> = vec![];
-        let mut framebuffer_struct = FrameBuffer {
+        let framebuffer_struct = FrameBuffer {
             x_position: x_position as u16,
             y_position: y_position as u16,
             width,
@@ -364,31 +338,32 @@ pub fn rectangle_framebuffer_update(
             encoded_pixels: vec![],
         };
 
-        match encoding_type {
-            RFBEncodingType::RAW => {
-                framebuffer_struct.encoding = RFBEncodingType::RAW;
-                framebuffer_rectangles.push(encoding_raw::get_pixel_data(framebuffer_struct));
-            },
-            RFBEncodingType::ZRLE => {
-                framebuffer_struct.encoding = RFBEncodingType::ZRLE;
-                framebuffer_struct.encoded_pixels = pixformat_data;
-                framebuffer_rectangles.push(encoding_zrle::get_pixel_data(framebuffer_struct, zstream_id));
-            },
-            RFBEncodingType::ZLIB => {
-                framebuffer_struct.encoding = RFBEncodingType::ZLIB;
-                framebuffer_rectangles.push(encoding_zlib::get_pixel_data(framebuffer_struct, zstream_id));
-            },
-            RFBEncodingType::HEX_TILE => {
-                framebuffer_struct.encoding = RFBEncodingType::HEX_TILE;
-                framebuffer_rectangles.push(encoding_hextile::get_pixel_data(framebuffer_struct));
+        /* Only send what changed since the last Update, if Incremental */
+        let mut framebuffer_rectangles: Vec<FrameBufferRectangle> = vec![];
+        let copy_rect = client_encodings.encodings.contains(&RFBEncodingType::COPY_RECT);
+        /* No Damage Reports on Win32, every Poll Captures and Compares */
+        let damaged_rectangles = incremental::get_damage(&zstream_id, &framebuffer_struct, incremental, copy_rect, Option::None, Option::None);
+
+        for damaged_rectangle in damaged_rectangles {
+            if damaged_rectangle.copy_source.is_some() {
+                /* Moved Content, the Client already has these Pixels */
+                framebuffer_rectangles.push(encoding_copyrect::get_pixel_data(damaged_rectangle));
+                continue;
             }
-            _ => {}
+
+            framebuffer_rectangles.extend(server::encode_rectangle(
+                client_encodings,
+                incremental::crop(&framebuffer_struct, damaged_rectangle),
+                get_pixelformat(),
+                pixelformat,
+                zstream_id.clone()
+            ));
         }
 
         FrameBufferUpdate {
             message_type: ServerToClientMessage::FRAME_BUFFER_UPDATE,
             padding: 0,
-            number_of_rectangles: 1,
+            number_of_rectangles: framebuffer_rectangles.len() as u16,
             frame_buffer: framebuffer_rectangles,
         }
     }
@@ -398,7 +373,7 @@ pub fn get_pixelformat() -> server::PixelFormat {
     PixelFormat {
         bits_per_pixel: WIN32_BITS_PER_PIXEL,
         depth: 24, /* WINDOWS EMULATES FOR TRUE-COLOR */
-        big_endian_flag: 1,
+        big_endian_flag: 0, /* CAPTURED AS B, G, R, A */
         true_color_flag: 1,
         red_max: 2_u16.pow(8) - 1,
         green_max: 2_u16.pow(8) - 1,
@@ -0,0 +1,481 @@
+/*
+    SpifyRFB - Modern RFB Server implementation using Rust
+    Copyright (C) 2023  Atheesh Thirumalairajan
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::{
+    collections::HashMap,
+    sync::{mpsc::{self, Receiver, Sender}, Mutex},
+    thread,
+    time::Duration,
+};
+
+use once_cell::sync::Lazy;
+use x11rb::{
+    connection::{Connection, RequestConnection},
+    protocol::{
+        Event,
+        xfixes,
+        xproto::{
+            self, Atom, AtomEnum, ChangeWindowAttributesAux, CreateWindowAux, EventMask, PropMode,
+            Property, SelectionNotifyEvent, SelectionRequestEvent, Window, WindowClass,
+        },
+    },
+    rust_connection::RustConnection,
+    NONE,
+};
+
+use crate::server::{clipboard::{self, ClipboardData}, events::{self, DesktopEvent}};
+
+/* Selection Requests and Client Cut Text are checked this often */
+const CLIPBOARD_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/* Cut Text from Clients, handed to the Selection Owner thread */
+static CLIPBOARD_SENDER: Lazy<Mutex<Option<Sender<ClipboardData>>>>
+    = Lazy::new(|| { Mutex::new(Option::None) });
+
+struct ClipboardAtoms {
+    clipboard: Atom,
+    timestamp: Atom, /* Zero-length Appends to it give us Server Timestamps */
+    targets: Atom,
+    incr: Atom,
+    text: Atom,
+    utf8_string: Atom,
+    rtf: Atom,
+    html: Atom,
+}
+
+/* A Selection Value too large for one Request, moved in chunks (ICCCM 2.7.2) */
+struct IncrTransfer {
+    target: Atom,
+    value_type: Atom,
+    format: u8,
+    value: Vec<u8>,
+}
+
+fn intern_atom(x11_connection: &RustConnection, atom_name: &[u8]) -> Option<Atom> {
+    xproto::intern_atom(x11_connection, false, atom_name)
+        .ok()
+        .and_then(|atom_cookie| atom_cookie.reply().ok())
+        .map(|atom_reply| atom_reply.atom)
+}
+
+fn intern_atoms(x11_connection: &RustConnection) -> Option<ClipboardAtoms> {
+    Option::Some(ClipboardAtoms {
+        clipboard: intern_atom(x11_connection, b"CLIPBOARD")?,
+        timestamp: intern_atom(x11_connection, b"_SPIFYRFB_TIMESTAMP")?,
+        targets: intern_atom(x11_connection, b"TARGETS")?,
+        incr: intern_atom(x11_connection, b"INCR")?,
+        text: intern_atom(x11_connection, b"TEXT")?,
+        utf8_string: intern_atom(x11_connection, b"UTF8_STRING")?,
+        rtf: intern_atom(x11_connection, b"text/rtf")?,
+        html: intern_atom(x11_connection, b"text/html")?,
+    })
+}
+
+fn create_owner_window(x11_connection: &RustConnection, x11_root: Window) -> Option<Window> {
+    /* Property Changes on it give us Server Timestamps */
+    let x11_window = x11_connection.generate_id().ok()?;
+    xproto::create_window(
+        x11_connection,
+        0,
+        x11_window,
+        x11_root,
+        0,
+        0,
+        1,
+        1,
+        0,
+        WindowClass::INPUT_ONLY,
+        0,
+        &CreateWindowAux::new().event_mask(EventMask::PROPERTY_CHANGE)
+    )
+    .ok()
+    .and_then(|create_cookie| create_cookie.check().ok())?;
+
+    Option::Some(x11_window)
+}
+
+pub fn set_clipboard(clipboard_data: ClipboardData) {
+    if let Some(clipboard_sender) = CLIPBOARD_SENDER.lock().unwrap().as_ref() {
+        clipboard_sender.send(clipboard_data).unwrap_or(());
+    }
+}
+
+fn owned_targets(clipboard_atoms: &ClipboardAtoms, owned_data: &ClipboardData) -> Vec<Atom> {
+    let mut targets: Vec<Atom> = vec![clipboard_atoms.targets];
+    if owned_data.text.is_some() {
+        targets.extend([clipboard_atoms.utf8_string, clipboard_atoms.text, AtomEnum::STRING.into()]);
+    }
+
+    if owned_data.rtf.is_some() {
+        targets.push(clipboard_atoms.rtf);
+    }
+
+    if owned_data.html.is_some() {
+        targets.push(clipboard_atoms.html);
+    }
+
+    targets
+}
+
+fn incr_chunk_size(x11_connection: &RustConnection) -> usize {
+    /* ChangeProperty's 24 byte Header shares the Request with the chunk */
+    (x11_connection.maximum_request_bytes() - 24) & !3
+}
+
+fn answer_selection_request(
+    x11_connection: &RustConnection,
+    clipboard_atoms: &ClipboardAtoms,
+    request_event: SelectionRequestEvent,
+    owned_data: &Option<ClipboardData>
+) -> Option<IncrTransfer> {
+    /* Obsolete Clients leave out the Property, the Target is used instead */
+    let property = if request_event.property == NONE { request_event.target } else { request_event.property };
+    let target = request_event.target;
+
+    /* (TYPE, FORMAT, ELEMENTS, DATA) written to the Requestor's Property */
+    let answer: Option<(Atom, u8, u32, Vec<u8>)> = owned_data.as_ref().and_then(|owned_data| {
+        if target == clipboard_atoms.targets {
+            let targets = owned_targets(clipboard_atoms, owned_data);
+            Option::Some((AtomEnum::ATOM.into(), 32, targets.len() as u32, targets.iter().flat_map(|atom| atom.to_ne_bytes()).collect()))
+        } else if target == clipboard_atoms.utf8_string || target == clipboard_atoms.text {
+            owned_data.text.as_ref().map(|text| (clipboard_atoms.utf8_string, 8, text.len() as u32, text.as_bytes().to_vec()))
+        } else if target == u32::from(AtomEnum::STRING) {
+            owned_data.text.as_ref().map(|text| {
+                let latin1_text = clipboard::string_to_latin1(text);
+                (AtomEnum::STRING.into(), 8, latin1_text.len() as u32, latin1_text)
+            })
+        } else if target == clipboard_atoms.rtf {
+            owned_data.rtf.as_ref().map(|rtf| (clipboard_atoms.rtf, 8, rtf.len() as u32, rtf.as_bytes().to_vec()))
+        } else if target == clipboard_atoms.html {
+            owned_data.html.as_ref().map(|html| (clipboard_atoms.html, 8, html.len() as u32, html.as_bytes().to_vec()))
+        } else {
+            Option::None
+        }
+    });
+
+    let mut incr_transfer: Option<IncrTransfer> = Option::None;
+    let answered = match answer {
+        Some((property_type, format, _elements, data)) if data.len() > incr_chunk_size(x11_connection) => {
+            /* The Requestor deleting the INCR Property, and then each chunk, asks for the next one */
+            let incr_started = xproto::change_window_attributes(
+                x11_connection,
+                request_event.requestor,
+                &ChangeWindowAttributesAux::new().event_mask(EventMask::PROPERTY_CHANGE)
+            ).is_ok() && xproto::change_property(
+                x11_connection,
+                PropMode::REPLACE,
+                request_event.requestor,
+                property,
+                clipboard_atoms.incr,
+                32,
+                1,
+                &(data.len() as u32).to_ne_bytes()
+            ).is_ok();
+
+            if incr_started {
+                incr_transfer = Option::Some(IncrTransfer { target, value_type: property_type, format, value: data });
+            }
+
+            incr_started
+        },
+        Some((property_type, format, elements, data)) => {
+            xproto::change_property(x11_connection, PropMode::REPLACE, request_event.requestor, property, property_type, format, elements, &data).is_ok()
+        },
+        None => false
+    };
+
+    /* Refusals are a SelectionNotify with no Property */
+    let notify_event = SelectionNotifyEvent {
+        response_type: xproto::SELECTION_NOTIFY_EVENT,
+        sequence: 0,
+        time: request_event.time,
+        requestor: request_event.requestor,
+        selection: request_event.selection,
+        target,
+        property: if answered { property } else { NONE },
+    };
+
+    xproto::send_event(x11_connection, false, request_event.requestor, EventMask::NO_EVENT, notify_event).ok();
+    incr_transfer
+}
+
+fn send_incr_chunk(x11_connection: &RustConnection, requestor: Window, property: Atom, incr_transfer: &mut IncrTransfer) -> bool {
+    /* An empty chunk ends the Transfer */
+    let chunk_length = incr_transfer.value.len().min(incr_chunk_size(x11_connection));
+    let chunk: Vec<u8> = incr_transfer.value.drain(..chunk_length).collect();
+    let elements = (chunk.len() / (incr_transfer.format as usize / 8)) as u32;
+    xproto::change_property(
+        x11_connection,
+        PropMode::REPLACE,
+        requestor,
+        property,
+        incr_transfer.value_type,
+        incr_transfer.format,
+        elements,
+        &chunk
+    ).ok();
+
+    chunk.is_empty()
+}
+
+fn read_selection(x11_connection: &RustConnection, x11_window: Window, property: Atom) -> Option<(Atom, Vec<u8>)> {
+    let property_reply = xproto::get_property(x11_connection, true, x11_window, property, AtomEnum::ANY, 0, u32::MAX / 4)
+        .ok()
+        .and_then(|property_cookie| property_cookie.reply().ok())?;
+
+    Option::Some((property_reply.type_, property_reply.value))
+}
+
+fn store_selection(clipboard_atoms: &ClipboardAtoms, incoming_data: &mut ClipboardData, target: Atom, value_type: Atom, value: &[u8]) {
+    if value_type == u32::from(AtomEnum::STRING) {
+        incoming_data.text = Option::Some(clipboard::latin1_to_string(value));
+        return;
+    }
+
+    let value = String::from_utf8_lossy(value).into_owned();
+    if target == clipboard_atoms.rtf {
+        incoming_data.rtf = Option::Some(value);
+    } else if target == clipboard_atoms.html {
+        incoming_data.html = Option::Some(value);
+    } else {
+        incoming_data.text = Option::Some(value);
+    }
+}
+
+fn broadcast_selection(incoming_data: &ClipboardData, clipboard_data: &mut Option<ClipboardData>) {
+    let selection_data = Option::Some(incoming_data.clone());
+    if incoming_data.formats() != 0 && selection_data != *clipboard_data {
+        *clipboard_data = selection_data;
+        events::broadcast(DesktopEvent::ClipboardChanged(incoming_data.clone(), Option::None));
+    }
+}
+
+pub fn watch_clipboard() {
+    let (clipboard_sender, clipboard_receiver): (Sender<ClipboardData>, Receiver<ClipboardData>) = mpsc::channel();
+    *CLIPBOARD_SENDER.lock().unwrap() = Option::Some(clipboard_sender);
+
+    thread::spawn(move || {
+        let x11_connection = match x11rb::connect(None) {
+            Ok((x11_connection, _x11_screen_id)) => x11_connection,
+            Err(_) => return
+        };
+
+        let x11_root = x11_connection.setup().roots[0].root;
+        let clipboard_atoms = match intern_atoms(&x11_connection) {
+            Some(clipboard_atoms) => clipboard_atoms,
+            None => {
+                println!("Clipboard: Interning Atoms failed, Clipboard is not shared");
+                return;
+            }
+        };
+
+        /* Invisible Window owning the Selections on behalf of Clients */
+        let x11_window = match create_owner_window(&x11_connection, x11_root) {
+            Some(x11_window) => x11_window,
+            None => {
+                println!("Clipboard: Creating the Selection Window failed, Clipboard is not shared");
+                return;
+            }
+        };
+
+        /* Only CLIPBOARD is sent to Clients, PRIMARY changes with every highlight */
+        let xfixes_version = xfixes::query_version(&x11_connection, 4, 0)
+            .ok()
+            .and_then(|version_cookie| version_cookie.reply().ok());
+
+        if xfixes_version.is_some() {
+            xfixes::select_selection_input(
+                &x11_connection,
+                x11_window,
+                clipboard_atoms.clipboard,
+                xfixes::SelectionEventMask::SET_SELECTION_OWNER
+            ).ok();
+        }
+
+        x11_connection.flush().unwrap_or_default();
+
+        /* Data we own the Selections with, and the last Data sent to Clients */
+        let mut owned_data: Option<ClipboardData> = Option::None;
+        let mut clipboard_data: Option<ClipboardData> = Option::None;
+
+        /* Client Data waiting for a Timestamp to take the Selections with (ICCCM 2.1) */
+        let mut pending_ownership: Option<ClipboardData> = Option::None;
+
+        /* Targets converted from another Owner, each into a Property named after it */
+        let mut incoming_data: ClipboardData = Default::default();
+        let mut pending_targets: Vec<Atom> = vec![];
+
+        /* Chunked Transfers, into our Properties and into (Requestor, Property) */
+        let mut incoming_transfers: HashMap<Atom, IncrTransfer> = HashMap::new();
+        let mut outgoing_transfers: HashMap<(Window, Atom), IncrTransfer> = HashMap::new();
+        loop {
+            while let Ok(client_data) = clipboard_receiver.try_recv() {
+                /* A zero-length Append changes nothing, but its PropertyNotify carries the Time */
+                let timestamp_requested = xproto::change_property(
+                    &x11_connection,
+                    PropMode::APPEND,
+                    x11_window,
+                    clipboard_atoms.timestamp,
+                    AtomEnum::STRING,
+                    8,
+                    0,
+                    &[]
+                ).is_ok();
+
+                if timestamp_requested {
+                    pending_ownership = Option::Some(client_data);
+                }
+
+                x11_connection.flush().unwrap_or_default();
+            }
+
+            while let Ok(Some(x11_event)) = x11_connection.poll_for_event() {
+                match x11_event {
+                    Event::PropertyNotify(property_event) if property_event.window == x11_window && property_event.atom == clipboard_atoms.timestamp => {
+                        if let Some(client_data) = pending_ownership.take() {
+                            for selection in [clipboard_atoms.clipboard, AtomEnum::PRIMARY.into()] {
+                                xproto::set_selection_owner(&x11_connection, x11_window, selection, property_event.time).ok();
+                            }
+
+                            clipboard_data = Option::Some(client_data.clone());
+                            owned_data = Option::Some(client_data);
+                        }
+                    },
+                    Event::XfixesSelectionNotify(selection_event) if selection_event.owner != x11_window && selection_event.owner != NONE => {
+                        /* Another Application copied, ask for every Format we know */
+                        owned_data = Option::None;
+                        incoming_data = Default::default();
+                        incoming_transfers.clear();
+                        pending_targets = vec![clipboard_atoms.utf8_string, clipboard_atoms.rtf, clipboard_atoms.html];
+                        pending_targets.retain(|target| {
+                            xproto::convert_selection(
+                                &x11_connection,
+                                x11_window,
+                                selection_event.selection,
+                                *target,
+                                *target,
+                                selection_event.timestamp
+                            ).is_ok()
+                        });
+                    },
+                    Event::SelectionNotify(notify_event) if pending_targets.contains(&notify_event.target) => {
+                        let selection_value = if notify_event.property == NONE {
+                            Option::None
+                        } else {
+                            read_selection(&x11_connection, x11_window, notify_event.property)
+                        };
+
+                        match selection_value {
+                            Some((value_type, _value)) if value_type == clipboard_atoms.incr => {
+                                /* Reading deleted the INCR Property, chunks follow as PropertyNotify */
+                                incoming_transfers.insert(notify_event.property, IncrTransfer {
+                                    target: notify_event.target,
+                                    value_type: NONE,
+                                    format: 8,
+                                    value: vec![],
+                                });
+
+                                continue;
+                            },
+                            Some((value_type, value)) => {
+                                store_selection(&clipboard_atoms, &mut incoming_data, notify_event.target, value_type, &value);
+                                pending_targets.retain(|target| *target != notify_event.target);
+                            },
+                            None if notify_event.target == clipboard_atoms.utf8_string => {
+                                /* No UTF-8 from the Owner, Latin-1 is always available */
+                                let string_requested = xproto::convert_selection(
+                                    &x11_connection,
+                                    x11_window,
+                                    notify_event.selection,
+                                    AtomEnum::STRING.into(),
+                                    AtomEnum::STRING,
+                                    notify_event.time
+                                ).is_ok();
+
+                                pending_targets.retain(|target| *target != notify_event.target);
+                                if string_requested {
+                                    pending_targets.push(AtomEnum::STRING.into());
+                                }
+                            },
+                            None => {
+                                pending_targets.retain(|target| *target != notify_event.target);
+                            }
+                        }
+
+                        if pending_targets.is_empty() {
+                            broadcast_selection(&incoming_data, &mut clipboard_data);
+                        }
+                    },
+                    Event::PropertyNotify(property_event) if property_event.window == x11_window && property_event.state == Property::NEW_VALUE && incoming_transfers.contains_key(&property_event.atom) => {
+                        /* Reading deletes the chunk, which asks the Owner for the next one */
+                        let chunk = read_selection(&x11_connection, x11_window, property_event.atom);
+                        if let Some(incr_transfer) = incoming_transfers.get_mut(&property_event.atom) {
+                            match chunk {
+                                Some((value_type, value)) if !value.is_empty() => {
+                                    incr_transfer.value_type = value_type;
+                                    incr_transfer.value.extend(value);
+                                    continue;
+                                },
+                                _ => {}
+                            }
+                        }
+
+                        if let Some(incr_transfer) = incoming_transfers.remove(&property_event.atom) {
+                            store_selection(&clipboard_atoms, &mut incoming_data, incr_transfer.target, incr_transfer.value_type, &incr_transfer.value);
+                            pending_targets.retain(|target| *target != incr_transfer.target);
+                        }
+
+                        if pending_targets.is_empty() {
+                            broadcast_selection(&incoming_data, &mut clipboard_data);
+                        }
+                    },
+                    Event::PropertyNotify(property_event) if property_event.state == Property::DELETE && outgoing_transfers.contains_key(&(property_event.window, property_event.atom)) => {
+                        let transfer_key = (property_event.window, property_event.atom);
+                        let transfer_sent = match outgoing_transfers.get_mut(&transfer_key) {
+                            Some(incr_transfer) => send_incr_chunk(&x11_connection, property_event.window, property_event.atom, incr_transfer),
+                            None => false
+                        };
+
+                        if transfer_sent {
+                            outgoing_transfers.remove(&transfer_key);
+                            if !outgoing_transfers.keys().any(|(requestor, _property)| *requestor == property_event.window) {
+                                xproto::change_window_attributes(
+                                    &x11_connection,
+                                    property_event.window,
+                                    &ChangeWindowAttributesAux::new().event_mask(EventMask::NO_EVENT)
+                                ).ok();
+                            }
+                        }
+                    },
+                    Event::SelectionRequest(request_event) => {
+                        let requestor = request_event.requestor;
+                        let property = if request_event.property == NONE { request_event.target } else { request_event.property };
+                        if let Some(incr_transfer) = answer_selection_request(&x11_connection, &clipboard_atoms, request_event, &owned_data) {
+                            outgoing_transfers.insert((requestor, property), incr_transfer);
+                        }
+                    },
+                    _ => {}
+                }
+
+                x11_connection.flush().unwrap_or_default();
+            }
+
+            thread::sleep(CLIPBOARD_POLL_INTERVAL);
+        }
+    });
+}
@@ -0,0 +1,219 @@
+/*
+    SpifyRFB - Modern RFB Server implementation using Rust
+    Copyright (C) 2023  Atheesh Thirumalairajan
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::{thread, time::{Duration, Instant}};
+use x11rb::{
+    connection::Connection,
+    protocol::{
+        Event,
+        damage,
+        randr,
+        xfixes,
+        xkb,
+        xproto::{self, ChangeWindowAttributesAux, EventMask},
+    },
+};
+
+use crate::server::{events::{self, CursorImage, DesktopEvent}, incremental};
+use super::{get_cursor_image, get_desktop_name, get_screen_size};
+
+/* Pointer motion has no Root Window event, it is polled while a Client has PointerPos */
+const POINTER_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+fn watch_pointer() {
+    thread::spawn(|| {
+        let x11_connection = match x11rb::connect(None) {
+            Ok((x11_connection, _x11_screen_id)) => x11_connection,
+            Err(_) => return
+        };
+
+        let x11_root = x11_connection.setup().roots[0].root;
+        let mut pointer_position: Option<(u16, u16)> = Option::None;
+        loop {
+            thread::sleep(POINTER_POLL_INTERVAL);
+            if !events::pointer_watched() {
+                /* New Watchers get the Position right away */
+                pointer_position = Option::None;
+                continue;
+            }
+
+            let pointer_reply = xproto::query_pointer(&x11_connection, x11_root)
+                .ok()
+                .and_then(|pointer_cookie| pointer_cookie.reply().ok());
+
+            if let Some(pointer_reply) = pointer_reply {
+                let position = (pointer_reply.root_x.max(0) as u16, pointer_reply.root_y.max(0) as u16);
+                if pointer_position != Option::Some(position) {
+                    pointer_position = Option::Some(position);
+                    events::broadcast(DesktopEvent::PointerMoved(position.0, position.1));
+                }
+            }
+        }
+    });
+}
+
+fn get_cursor_shape<C: Connection>(x11_connection: &C) -> Option<(u32, CursorImage)> {
+    let cursor_reply = get_cursor_image(x11_connection)?;
+    Option::Some((
+        cursor_reply.cursor_serial,
+        CursorImage {
+            width: cursor_reply.width,
+            height: cursor_reply.height,
+            x_hotspot: cursor_reply.xhot,
+            y_hotspot: cursor_reply.yhot,
+            pixels: cursor_reply.cursor_image,
+        }
+    ))
+}
+
+pub fn watch_desktop(bell_interval: Option<Duration>) {
+    watch_pointer();
+    thread::spawn(move || {
+        /* Own Connection, Events would otherwise queue up behind get_image */
+        let x11_connection = match x11rb::connect(None) {
+            Ok((x11_connection, _x11_screen_id)) => x11_connection,
+            Err(_) => return
+        };
+
+        let x11_screen = x11_connection.setup().roots[0].clone();
+        let mut desktop_size = get_screen_size(&x11_connection, &x11_screen);
+        let mut desktop_name = get_desktop_name(&x11_connection, &x11_screen);
+
+        /* Root Window: Resizes (even without RandR) and WM_NAME changes */
+        xproto::change_window_attributes(
+            &x11_connection,
+            x11_screen.root,
+            &ChangeWindowAttributesAux::new()
+                .event_mask(EventMask::STRUCTURE_NOTIFY | EventMask::PROPERTY_CHANGE)
+        ).unwrap();
+
+        let randr_version = randr::query_version(&x11_connection, 1, 2)
+            .ok()
+            .and_then(|version_cookie| version_cookie.reply().ok());
+
+        if randr_version.is_some() {
+            randr::select_input(
+                &x11_connection,
+                x11_screen.root,
+                randr::NotifyMask::SCREEN_CHANGE
+            ).unwrap();
+        }
+
+        /* XKB: Bells from any Application */
+        let xkb_extension = xkb::use_extension(&x11_connection, 1, 0)
+            .ok()
+            .and_then(|extension_cookie| extension_cookie.reply().ok());
+
+        if xkb_extension.is_some_and(|extension| extension.supported) {
+            xkb::select_events(
+                &x11_connection,
+                xkb::ID::USE_CORE_KBD.into(),
+                xkb::EventType::from(0_u16),
+                xkb::EventType::BELL_NOTIFY,
+                xkb::MapPart::from(0_u16),
+                xkb::MapPart::from(0_u16),
+                &xkb::SelectEventsAux::new()
+            ).unwrap();
+        }
+
+        /* Bells closer together than bell_interval are dropped */
+        let mut last_bell: Option<Instant> = Option::None;
+
+        /* XFixes 2.0+: Cursor Shape changes */
+        let mut cursor_serial: Option<u32> = Option::None;
+        let xfixes_version = xfixes::query_version(&x11_connection, 4, 0)
+            .ok()
+            .and_then(|version_cookie| version_cookie.reply().ok());
+
+        if xfixes_version.is_some_and(|version| version.major_version >= 2) {
+            xfixes::select_cursor_input(
+                &x11_connection,
+                x11_screen.root,
+                xfixes::CursorNotifyMask::DISPLAY_CURSOR
+            ).unwrap();
+
+            if let Some((serial, cursor)) = get_cursor_shape(&x11_connection) {
+                cursor_serial = Option::Some(serial);
+                events::broadcast(DesktopEvent::CursorChanged(cursor));
+            }
+        }
+
+        /* XDamage: Incremental Polls only Capture after the Screen changed */
+        let damage_version = damage::query_version(&x11_connection, 1, 1)
+            .ok()
+            .and_then(|version_cookie| version_cookie.reply().ok());
+
+        if damage_version.is_some() {
+            let root_damage = x11_connection.generate_id().ok();
+            let damage_created = root_damage.is_some_and(|root_damage| {
+                damage::create(&x11_connection, root_damage, x11_screen.root, damage::ReportLevel::NON_EMPTY)
+                    .ok()
+                    .and_then(|create_cookie| create_cookie.check().ok())
+                    .is_some()
+            });
+
+            if damage_created {
+                incremental::start_damage_reports();
+            }
+        }
+
+        x11_connection.flush().unwrap_or_default();
+        while let Ok(x11_event) = x11_connection.wait_for_event() {
+            match x11_event {
+                Event::RandrScreenChangeNotify(_) | Event::ConfigureNotify(_) => {
+                    let screen_size = get_screen_size(&x11_connection, &x11_screen);
+                    if screen_size != desktop_size {
+                        desktop_size = screen_size;
+                        events::broadcast(DesktopEvent::Resized(screen_size.0, screen_size.1));
+                    }
+                },
+                Event::DamageNotify(damage_event) => {
+                    /* NON_EMPTY reports again once the Damage is cleared */
+                    incremental::report_damage();
+                    if damage::subtract(&x11_connection, damage_event.damage, x11rb::NONE, x11rb::NONE).is_ok() {
+                        x11_connection.flush().unwrap_or_default();
+                    }
+                },
+                Event::XkbBellNotify(_) => {
+                    let rate_limited = bell_interval
+                        .zip(last_bell)
+                        .is_some_and(|(bell_interval, last_bell)| last_bell.elapsed() < bell_interval);
+
+                    if !rate_limited {
+                        last_bell = Option::Some(Instant::now());
+                        events::broadcast(DesktopEvent::Bell);
+                    }
+                },
+                Event::PropertyNotify(property_event) if property_event.atom == u32::from(xproto::AtomEnum::WM_NAME) => {
+                    let screen_name = get_desktop_name(&x11_connection, &x11_screen);
+                    if screen_name != desktop_name {
+                        desktop_name = screen_name;
+                        events::broadcast(DesktopEvent::Renamed(desktop_name.clone()));
+                    }
+                },
+                Event::XfixesCursorNotify(cursor_event) if Option::Some(cursor_event.cursor_serial) != cursor_serial => {
+                    if let Some((serial, cursor)) = get_cursor_shape(&x11_connection) {
+                        cursor_serial = Option::Some(serial);
+                        events::broadcast(DesktopEvent::CursorChanged(cursor));
+                    }
+                },
+                _ => {}
+            }
+        }
+    });
+}
@@ -50,3 +50,45 @@ pub fn create_keysym_map(x11_connection: &RustConnection) -> HashMap<u32, u8> {
     /* RETURN KEYSYM <-> KEYCODE MAP */
     keysym_keycode_map
 }
+
+pub fn xt_to_evdev(xt_scancode: u32) -> Option<u8> {
+    /* QEMU XT Scancodes: E0-prefixed keys have the high bit of the low byte set */
+    match xt_scancode {
+        /* Main Block, Function Keys and Keypad share their numbers with evdev */
+        0x01..=0x53 => Some(xt_scancode as u8),
+        0x54 => Some(99),  /* SYSRQ */
+        0x56 => Some(86),  /* 102ND */
+        0x57 => Some(87),  /* F11 */
+        0x58 => Some(88),  /* F12 */
+        0x70 => Some(93),  /* KATAKANAHIRAGANA */
+        0x73 => Some(89),  /* RO */
+        0x79 => Some(92),  /* HENKAN */
+        0x7b => Some(94),  /* MUHENKAN */
+        0x7d => Some(124), /* YEN */
+        0x7e => Some(121), /* KPCOMMA */
+        0x9c => Some(96),  /* KPENTER */
+        0x9d => Some(97),  /* RIGHTCTRL */
+        0xa0 => Some(113), /* MUTE */
+        0xae => Some(114), /* VOLUMEDOWN */
+        0xb0 => Some(115), /* VOLUMEUP */
+        0xb5 => Some(98),  /* KPSLASH */
+        0xb7 => Some(99),  /* SYSRQ */
+        0xb8 => Some(100), /* RIGHTALT */
+        0xc6 => Some(119), /* PAUSE */
+        0xc7 => Some(102), /* HOME */
+        0xc8 => Some(103), /* UP */
+        0xc9 => Some(104), /* PAGEUP */
+        0xcb => Some(105), /* LEFT */
+        0xcd => Some(106), /* RIGHT */
+        0xcf => Some(107), /* END */
+        0xd0 => Some(108), /* DOWN */
+        0xd1 => Some(109), /* PAGEDOWN */
+        0xd2 => Some(110), /* INSERT */
+        0xd3 => Some(111), /* DELETE */
+        0xdb => Some(125), /* LEFTMETA */
+        0xdc => Some(126), /* RIGHTMETA */
+        0xdd => Some(127), /* COMPOSE */
+        0xde => Some(116), /* POWER */
+        _ => None
+    }
+}
@@ -17,16 +17,21 @@
 */
 
 mod keycodes;
+mod events;
+mod clipboard;
+pub use events::watch_desktop;
+pub use clipboard::{set_clipboard, watch_clipboard};
 use std::{collections::HashMap, sync::Arc};
 use crate::server::{
     self, FrameBufferRectangle, FrameBufferUpdate, PixelFormat, RFBEncodingType, RFBServerInit,
-    ServerToClientMessage, WindowManager, encoding_raw, encoding_zrle, encoding_zlib, encoding_hextile, FrameBuffer,
+    ServerToClientMessage, WindowManager, FrameBuffer, incremental, encoding_copyrect, encoding_cursor, RFBEncodings,
 };
 
 use x11rb::{
     connection::Connection,
     protocol::{
         xproto::{self, ImageFormat, KeyButMask, Screen},
+        xfixes,
         xtest,
     },
     rust_connection::{ConnectError, RustConnection},
@@ -47,8 +52,12 @@ pub struct X11PointerEvent {
 pub struct X11KeyEvent {
     pub(crate) key_down: u8,
     pub(crate) key_sym: u32,
+    pub(crate) key_code: Option<u32>, /* XT SCANCODE, QEMU EXTENDED KEY EVENT */
 }
 
+/* X11 Keycodes are evdev Keycodes shifted by 8 */
+const EVDEV_KEYCODE_OFFSET: u8 = 8;
+
 fn parse_keybutmask(mask: KeyButMask) -> u8 {
     match mask {
         KeyButMask::BUTTON1 => 1,
@@ -61,7 +70,12 @@ fn parse_keybutmask(mask: KeyButMask) -> u8 {
 }
 
 pub fn fire_key_event(x11_server: &X11Server, x11_screen: Screen, x11_keyevent: X11KeyEvent) {
-    keycodes::create_keysym_map(&x11_server.connection);
+    /* Scancodes don't depend on the Layout, Keysyms (mapped once on connect) are the fallback */
+    let x11_keycode = x11_keyevent.key_code
+        .and_then(keycodes::xt_to_evdev)
+        .map(|evdev_keycode| evdev_keycode + EVDEV_KEYCODE_OFFSET)
+        .unwrap_or(*x11_server.keysym_map.get(&x11_keyevent.key_sym).unwrap_or(&0));
+
     xtest::fake_input(
         &x11_server.connection,
         if x11_keyevent.key_down == 0 {
@@ -69,7 +83,7 @@ pub fn fire_key_event(x11_server: &X11Server, x11_screen: Screen, x11_keyevent:
         } else {
             xproto::KEY_PRESS_EVENT
         },
-        *x11_server.keysym_map.get(&x11_keyevent.key_sym).unwrap_or(&0),
+        x11_keycode,
         x11rb::CURRENT_TIME,
         x11_screen.root,
         0,
@@ -128,139 +142,255 @@ pub fn fire_pointer_event(
     .unwrap();
 }
 
+pub fn get_bits_per_pixel(x11_screen: &Screen) -> u8 {
+    /* ZPixmap pads 24 bit depth to 32, 15 bit depth to 16 */
+    match x11_screen.root_depth {
+        24 => 32,
+        15 => 16,
+        root_depth => root_depth
+    }
+}
+
+fn channel_format(channel_mask: u32) -> (u16, u8) {
+    /* (MAX, SHIFT) of a Visual's contiguous channel mask */
+    if channel_mask == 0 {
+        return (0, 0);
+    }
+
+    let shift = channel_mask.trailing_zeros();
+    ((channel_mask >> shift) as u16, shift as u8)
+}
+
 pub fn get_pixelformat(x11_screen: Screen) -> server::PixelFormat {
+    /* Channels come from the Root Visual, Colour-mapped Visuals have none */
+    let root_visual = x11_screen.allowed_depths
+        .iter()
+        .flat_map(|allowed_depth| allowed_depth.visuals.iter())
+        .find(|visual| visual.visual_id == x11_screen.root_visual)
+        .filter(|visual| visual.class == xproto::VisualClass::TRUE_COLOR || visual.class == xproto::VisualClass::DIRECT_COLOR);
+
+    let (red, green, blue) = root_visual
+        .map(|visual| (channel_format(visual.red_mask), channel_format(visual.green_mask), channel_format(visual.blue_mask)))
+        .unwrap_or(((0, 0), (0, 0), (0, 0)));
+
     PixelFormat {
-        bits_per_pixel: if x11_screen.root_depth == 24 {
-            32
-        } else {
-            x11_screen.root_depth
-        }, /* ADD ALPHA-CHANNEL IF TRUE-COLOR */
+        bits_per_pixel: get_bits_per_pixel(&x11_screen),
         depth: x11_screen.root_depth,
-        big_endian_flag: 1,
-        true_color_flag: (x11_screen.root_depth == 24).into(),
-        red_max: if x11_screen.root_depth == 24 {
-            2_u16.pow(8) - 1
-        } else {
-            0
-        },
-        green_max: if x11_screen.root_depth == 24 {
-            2_u16.pow(8) - 1
-        } else {
-            0
-        },
-        blue_max: if x11_screen.root_depth == 24 {
-            2_u16.pow(8) - 1
-        } else {
-            0
-        },
-        red_shift: 0, /* COMMENT */
-        green_shift: 0,
-        blue_shift: 0,
+        big_endian_flag: 0, /* CAPTURED AS B, G, R, X */
+        true_color_flag: root_visual.is_some().into(),
+        red_max: red.0,
+        green_max: green.0,
+        blue_max: blue.0,
+        red_shift: red.1,
+        green_shift: green.1,
+        blue_shift: blue.1,
         padding: [0, 0, 0],
     }
 }
 
+pub fn get_screen_size(connection: &RustConnection, x11_screen: &Screen) -> (u16, u16) {
+    /* The Setup is from connect(), RandR may have resized the Screen since */
+    xproto::get_geometry(connection, x11_screen.root)
+        .ok()
+        .and_then(|geometry_cookie| geometry_cookie.reply().ok())
+        .map(|geometry| (geometry.width, geometry.height))
+        .unwrap_or((x11_screen.width_in_pixels, x11_screen.height_in_pixels))
+}
+
+pub fn get_desktop_name(connection: &RustConnection, x11_screen: &Screen) -> String {
+    /* `xsetroot -name` names the Desktop, otherwise use the X Server Vendor */
+    let wm_name = xproto::get_property(
+        connection,
+        false,
+        x11_screen.root,
+        xproto::AtomEnum::WM_NAME,
+        xproto::AtomEnum::ANY,
+        0,
+        1024
+    )
+    .ok()
+    .and_then(|property_cookie| property_cookie.reply().ok())
+    .filter(|property| !property.value.is_empty());
+
+    match wm_name {
+        Some(property) if property.type_ == u32::from(xproto::AtomEnum::STRING) => {
+            /* STRING is Latin-1 */
+            property.value.iter().map(|character| *character as char).collect()
+        },
+        Some(property) => String::from_utf8_lossy(&property.value).to_string(),
+        None => String::from_utf8_lossy(&connection.setup().vendor).to_string()
+    }
+}
+
+pub fn get_cursor_image<C: Connection>(connection: &C) -> Option<xfixes::GetCursorImageReply> {
+    /* Needs XFixes 2.0+, Servers without it have no Cursor Image */
+    xfixes::get_cursor_image(connection)
+        .ok()
+        .and_then(|cursor_cookie| cursor_cookie.reply().ok())
+}
+
 pub fn get_display_struct(x11_server: &X11Server, x11_screen: Screen) -> server::RFBServerInit {
+    let (framebuffer_width, framebuffer_height) = get_screen_size(&x11_server.connection, &x11_screen);
+    let name_string = get_desktop_name(&x11_server.connection, &x11_screen);
+
     RFBServerInit {
-        framebuffer_width: x11_screen.width_in_pixels,
-        framebuffer_height: x11_screen.height_in_pixels,
+        framebuffer_width,
+        framebuffer_height,
         server_pixelformat: get_pixelformat(x11_screen),
-        name_length: (x11_server).connection.setup().vendor_len().into(),
-        name_string: String::from_utf8(x11_server.connection.setup().clone().vendor).unwrap(),
+        name_length: name_string.len() as u32,
+        name_string,
     }
 }
 
-pub fn rectangle_framebuffer_update(
-    x11_server: &X11Server,
-    x11_screen: Screen,
-    encoding_type: i32,
-    x_position: i16,
-    y_position: i16,
-    width: u16,
-    height: u16,
-    pixelformat: PixelFormat,
-    zstream_id: String
-) -> FrameBufferUpdate {
+fn capture_rectangle(x11_server: &X11Server, x11_screen: &Screen, region: incremental::DamagedRectangle) -> FrameBuffer {
     let x11_cookie = xproto::get_image(
         &x11_server.connection,
         ImageFormat::Z_PIXMAP,
         x11_screen.root,
-        x_position,
-        y_position,
-        width,
-        height,
+        region.x_position as i16,
+        region.y_position as i16,
+        region.width,
+        region.height,
         !0,
     )
     .unwrap()
     .reply();
 
-    let mut pixel_data = x11_cookie.unwrap().data;
-    let bits_per_pixel = if x11_screen.root_depth == 24 { 32 } else { x11_screen.root_depth };
-    
-    /* Define Shifts */
-    let red = (pixelformat.red_shift / 8) as usize;
-    let green = (pixelformat.green_shift / 8) as usize;
-    let blue = (pixelformat.blue_shift / 8) as usize;
-
-    let mut pixformat_data: Vec<u8> = Vec::with_capacity(pixel_data.len());
-    let pixel_chunks: Vec<&mut [u8]> = pixel_data.chunks_mut((bits_per_pixel / 8) as usize).collect();
-
-    for pixel in pixel_chunks {
-        let pixel_copy = pixel.to_owned();
-        pixel[red] = pixel_copy[2];
-        pixel[green] = pixel_copy[1];
-        pixel[blue] = pixel_copy[0];
-        
-        /* X11 Sets RGBA (Alpha) to Padding */
-        pixel[3] = 255;
-        
-        if encoding_type == RFBEncodingType::ZRLE {
-            /* Extend Encoded Data for ZRLE */
-            pixformat_data.extend_from_slice(&[
-                pixel[0],
-                pixel[1],
-                pixel[2]
-            ]);
-        }
+    FrameBuffer {
+        x_position: region.x_position,
+        y_position: region.y_position,
+        width: region.width,
+        height: region.height,
+        bits_per_pixel: get_bits_per_pixel(x11_screen),
+        raw_pixels: x11_cookie.unwrap().data,
+        encoding: RFBEncodingType::RAW,
+        encoded_pixels: vec![],
     }
+}
 
-    let mut framebuffer_rectangles: Vec<FrameBufferRectangle> = vec![];
-    let mut framebuffer_struct = FrameBuffer {
+fn composite_cursor_image(framebuffer: &mut FrameBuffer, x11_screen: &Screen, cursor_reply: &xfixes::GetCursorImageReply) {
+    encoding_cursor::composite(
+        framebuffer,
+        &get_pixelformat(x11_screen.clone()),
+        &cursor_reply.cursor_image,
+        cursor_reply.width,
+        cursor_reply.height,
+        cursor_reply.x as i32 - cursor_reply.xhot as i32,
+        cursor_reply.y as i32 - cursor_reply.yhot as i32
+    );
+}
+
+pub fn rectangle_framebuffer_update(
+    x11_server: &X11Server,
+    x11_screen: Screen,
+    client_encodings: &RFBEncodings,
+    incremental: bool,
+    x_position: i16,
+    y_position: i16,
+    width: u16,
+    height: u16,
+    pixelformat: PixelFormat,
+    zstream_id: String
+) -> FrameBufferUpdate {
+    /* Read before the Capture, Damage during get_image is caught by the next Poll */
+    let damage_serial = incremental::damage_serial();
+    let requested_region = incremental::DamagedRectangle {
         x_position: x_position as u16,
         y_position: y_position as u16,
         width,
         height,
-        bits_per_pixel,
-        raw_pixels: pixel_data.clone(),
-        encoding: RFBEncodingType::RAW,
-        encoded_pixels: vec![],
+        copy_source: Option::None,
     };
 
-    match encoding_type {
-        RFBEncodingType::RAW => {
-            framebuffer_struct.encoding = RFBEncodingType::RAW;
-            framebuffer_rectangles.push(encoding_raw::get_pixel_data(framebuffer_struct));
-        },
-        RFBEncodingType::ZRLE => {
-            framebuffer_struct.encoding = RFBEncodingType::ZRLE;
-            framebuffer_struct.encoded_pixels = pixformat_data;
-            framebuffer_rectangles.push(encoding_zrle::get_pixel_data(framebuffer_struct, zstream_id));
-        },
-        RFBEncodingType::ZLIB => {
-            framebuffer_struct.encoding = RFBEncodingType::ZLIB;
-            framebuffer_rectangles.push(encoding_zlib::get_pixel_data(framebuffer_struct, zstream_id));
-        },
-        RFBEncodingType::HEX_TILE => {
-            framebuffer_struct.encoding = RFBEncodingType::HEX_TILE;
-            framebuffer_rectangles.push(encoding_hextile::get_pixel_data(framebuffer_struct));
+    /* get_image leaves out the Pointer, draw it for Clients that can't */
+    let cursor_reply = if client_encodings.cursor_encoding().is_none() {
+        get_cursor_image(&x11_server.connection)
+    } else {
+        Option::None
+    };
+
+    let cursor = cursor_reply.as_ref().map(|cursor_reply| incremental::CompositedCursor {
+        area: incremental::clip(
+            cursor_reply.x as i32 - cursor_reply.xhot as i32,
+            cursor_reply.y as i32 - cursor_reply.yhot as i32,
+            cursor_reply.width,
+            cursor_reply.height,
+            requested_region
+        ),
+        cursor_serial: cursor_reply.cursor_serial,
+    });
+
+    let mut framebuffer_rectangles: Vec<FrameBufferRectangle> = vec![];
+    if incremental && incremental::is_undamaged(&zstream_id, requested_region, damage_serial) {
+        /* XDamage saw nothing, skip the Capture. Pointer moves are not Damage, only the Cursor Areas are re-read */
+        let previous_cursor = incremental::composited_cursor(&zstream_id);
+        let cursor_framebuffers = match (previous_cursor, cursor, cursor_reply.as_ref()) {
+            (previous_cursor, cursor, _) if previous_cursor == cursor => Option::Some(vec![]),
+            (Some(previous_cursor), Some(cursor), Some(cursor_reply)) => {
+                let cursor_patches: Vec<FrameBuffer> = [previous_cursor.area, cursor.area]
+                    .into_iter()
+                    .flatten()
+                    .map(|cursor_area| {
+                        let mut cursor_patch = capture_rectangle(x11_server, &x11_screen, cursor_area);
+                        composite_cursor_image(&mut cursor_patch, &x11_screen, cursor_reply);
+                        cursor_patch
+                    })
+                    .collect();
+
+                incremental::get_cursor_damage(&zstream_id, &cursor_patches, cursor)
+            },
+            _ => Option::None
+        };
+
+        if let Some(cursor_framebuffers) = cursor_framebuffers {
+            for cursor_framebuffer in cursor_framebuffers {
+                framebuffer_rectangles.extend(server::encode_rectangle(
+                    client_encodings,
+                    cursor_framebuffer,
+                    get_pixelformat(x11_screen.clone()),
+                    pixelformat,
+                    zstream_id.clone()
+                ));
+            }
+
+            return FrameBufferUpdate {
+                message_type: ServerToClientMessage::FRAME_BUFFER_UPDATE,
+                padding: 0,
+                number_of_rectangles: framebuffer_rectangles.len() as u16,
+                frame_buffer: framebuffer_rectangles,
+            };
+        }
+    }
+
+    let mut framebuffer_struct = capture_rectangle(x11_server, &x11_screen, requested_region);
+    if let Some(cursor_reply) = cursor_reply.as_ref() {
+        composite_cursor_image(&mut framebuffer_struct, &x11_screen, cursor_reply);
+    }
+
+    /* Only send what changed since the last Update, if Incremental */
+    let copy_rect = client_encodings.encodings.contains(&RFBEncodingType::COPY_RECT);
+    let damaged_rectangles = incremental::get_damage(&zstream_id, &framebuffer_struct, incremental, copy_rect, damage_serial, cursor);
+
+    for damaged_rectangle in damaged_rectangles {
+        if damaged_rectangle.copy_source.is_some() {
+            /* Moved Content, the Client already has these Pixels */
+            framebuffer_rectangles.push(encoding_copyrect::get_pixel_data(damaged_rectangle));
+            continue;
         }
-        _ => {}
+
+        framebuffer_rectangles.extend(server::encode_rectangle(
+            client_encodings,
+            incremental::crop(&framebuffer_struct, damaged_rectangle),
+            get_pixelformat(x11_screen.clone()),
+            pixelformat,
+            zstream_id.clone()
+        ));
     }
 
     FrameBufferUpdate {
         message_type: ServerToClientMessage::FRAME_BUFFER_UPDATE,
         padding: 0,
-        number_of_rectangles: 1,
+        number_of_rectangles: framebuffer_rectangles.len() as u16,
         frame_buffer: framebuffer_rectangles,
     }
 }
@@ -268,6 +398,11 @@ pub fn rectangle_framebuffer_update(
 pub fn connect() -> Result<Arc<WindowManager>, ConnectError> {
     match x11rb::connect(None) {
         Ok((x11_connection, _x11_screen_id)) => {
+            /* XFixes must be negotiated before Cursor Images can be read */
+            xfixes::query_version(&x11_connection, 4, 0)
+                .ok()
+                .and_then(|version_cookie| version_cookie.reply().ok());
+
             return Ok(Arc::new(WindowManager::X11(X11Server {
                 displays: x11_connection.setup().clone().roots,
                 keysym_map: keycodes::create_keysym_map(&x11_connection),